@@ -0,0 +1,161 @@
+use std::{path::PathBuf, process};
+use cacherebbok::{config::Config, export::{Encoding, Record}, inspect::{self, Stats}, snapshot::{self, Codec}, utils::create_cache_entry};
+
+const USAGE: &str = "\
+Usage: cacherebbok-inspect [--data-dir DIR] [--file PATH] COMMAND
+
+Reads a snapshot without starting the cache. The data directory and encryption
+keys come from the same CACHE_* variables as the server.
+
+Commands:
+  keys                      List keys with their namespace, creation and expiry times
+  stats                     Record count, expired count and key/value size histogram
+  verify                    Check the header, checksum and every record; exits 1 on problems
+  salvage [--output PATH]   Write the readable records to PATH (default: FILE.salvaged)
+
+Options:
+  --data-dir <DIR>          Data directory, overriding CACHE_DATA_DIR
+  --file <PATH>             Snapshot to read, e.g. a generation (default: DIR/cache.json)
+  -h, --help                Print this help and exit";
+
+enum Command {
+    Keys,
+    Stats,
+    Verify,
+    Salvage { output: Option<PathBuf> },
+}
+
+struct Args {
+    data_dir: Option<PathBuf>,
+    file: Option<PathBuf>,
+    command: Command,
+}
+
+fn parse_args() -> Result<Option<Args>, String> {
+    let mut data_dir = None;
+    let mut file = None;
+    let mut command = None;
+    let mut output = None;
+    let mut args = std::env::args().skip(1);
+
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--data-dir" => data_dir = Some(PathBuf::from(args.next().ok_or("--data-dir needs a directory")?)),
+            "--file" => file = Some(PathBuf::from(args.next().ok_or("--file needs a path")?)),
+            "--output" => output = Some(PathBuf::from(args.next().ok_or("--output needs a path")?)),
+            "-h" | "--help" => return Ok(None),
+            "keys" | "stats" | "verify" | "salvage" if command.is_none() => command = Some(arg),
+            other => return Err(format!("unknown argument '{}'", other)),
+        }
+    }
+
+    let command = match command.as_deref() {
+        Some("keys") => Command::Keys,
+        Some("stats") => Command::Stats,
+        Some("verify") => Command::Verify,
+        Some("salvage") => Command::Salvage { output: output.take() },
+        _ => return Err("no command given".to_string()),
+    };
+    if output.is_some() {
+        return Err("--output is only used by salvage".to_string());
+    }
+
+    Ok(Some(Args { data_dir, file, command }))
+}
+
+fn main() {
+    let args = match parse_args() {
+        Ok(Some(args)) => args,
+        Ok(None) => {
+            println!("{}", USAGE);
+            return;
+        }
+        Err(e) => {
+            eprintln!("{}\n\n{}", e, USAGE);
+            process::exit(2);
+        }
+    };
+
+    let config = match Config::from_env() {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("Invalid configuration: {}", e);
+            process::exit(1);
+        }
+    };
+
+    let data_dir = args.data_dir.unwrap_or(config.data_dir);
+    let path = args.file.unwrap_or_else(|| data_dir.join(snapshot::SNAPSHOT_FILE));
+    let codec = Codec { compression: config.snapshot_compression, keyring: config.encryption };
+
+    let inspection = match inspect::inspect(&path, &codec) {
+        Ok(inspection) => inspection,
+        Err(e) => {
+            eprintln!("Cannot read {}: {}", path.display(), e);
+            process::exit(1);
+        }
+    };
+
+    match args.command {
+        Command::Keys => {
+            for (namespace, key, value) in &inspection.records {
+                let record = Record::new(key, &create_cache_entry(value), Encoding::Utf8);
+                let expires_at = record.expires_at.map_or("-".to_string(), |expires_at| expires_at.to_rfc3339());
+                println!("{}\t{}\t{}\t{}\t{}", namespace, record.key, record.encoding, record.created_at.to_rfc3339(), expires_at);
+            }
+        }
+        Command::Stats => {
+            let stats = Stats::collect(&inspection.records);
+            println!("file:     {} ({} bytes)", path.display(), inspection.file_bytes);
+            match &inspection.header {
+                Some(header) => println!(
+                    "format:   version {}, {} compression{}",
+                    header.version, header.compression, if header.encrypted { ", encrypted" } else { "" }
+                ),
+                None => println!("format:   legacy, no header"),
+            }
+            println!("records:  {}", stats.records);
+            println!("with ttl: {}", stats.with_ttl);
+            println!("expired:  {}", stats.expired);
+            println!("invalid:  {}", inspection.invalid_records);
+            println!();
+            println!("{:<8} {:>10} {:>10}", "bytes", "keys", "values");
+            for (label, keys, values) in stats.histogram() {
+                println!("{:<8} {:>10} {:>10}", label, keys, values);
+            }
+        }
+        Command::Verify => {
+            if inspection.is_healthy() {
+                println!("OK: {} records", inspection.records.len());
+                return;
+            }
+            for problem in &inspection.problems {
+                println!("PROBLEM: {}", problem);
+            }
+            println!("{} readable records; run salvage to keep them", inspection.records.len());
+            process::exit(1);
+        }
+        Command::Salvage { output } => {
+            let output = output.unwrap_or_else(|| {
+                let mut name = path.file_name().map(|name| name.to_os_string()).unwrap_or_default();
+                name.push(".salvaged");
+                path.with_file_name(name)
+            });
+
+            for problem in &inspection.problems {
+                eprintln!("PROBLEM: {}", problem);
+            }
+
+            match inspect::salvage(&inspection, &output, &codec) {
+                Ok(bytes) => println!(
+                    "Salvaged {} records ({} dropped) into {} ({} bytes)",
+                    inspection.records.len(), inspection.invalid_records, output.display(), bytes
+                ),
+                Err(e) => {
+                    eprintln!("Cannot write {}: {}", output.display(), e);
+                    process::exit(1);
+                }
+            }
+        }
+    }
+}
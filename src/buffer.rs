@@ -1,17 +1,20 @@
-use std::{io::{self, Read, Write}, sync::Arc};
+use std::{io::{self, Read}, time::Instant};
 use chrono::Utc;
-use crate::Cache;
+use crate::{conditional::Condition, counter, latency::Operation, logger, multi, scan, storage};
+use crate::{Cache, LogLevel};
+
+pub type BatchResults = Vec<Result<(), Box<dyn std::error::Error>>>;
 
 pub trait BufferAccess<'a> {
     fn _read(&mut self) -> Result<[u8; 128], Box<dyn std::error::Error>>;
     fn handle_in(&'a mut self, input: [u8;128]) -> Result<(), Box<dyn std::error::Error>>;
-    fn handle_batch(&'a mut self, inputs: &[[u8;128]]) -> Result<Vec<Result<(), Box<dyn std::error::Error>>>, Box<dyn std::error::Error>>;
+    fn handle_batch(&'a mut self, inputs: &[[u8;128]]) -> Result<BatchResults, Box<dyn std::error::Error>>;
 }
 
 impl<'a> BufferAccess<'a> for Cache {
     fn _read(&mut self) -> Result<[u8; 128], Box<dyn std::error::Error>> {
         let mut input_buf = self.cur_buf.lock().map_err(|_| "Mutex lock failed")?;
-        io::stdin().read(&mut *input_buf)?;
+        io::stdin().read_exact(&mut *input_buf)?;
         Ok(*input_buf)
     }
 
@@ -22,139 +25,359 @@ impl<'a> BufferAccess<'a> for Cache {
             self.ops_since_invalidation.store(0, std::sync::atomic::Ordering::SeqCst);
         }
 
-        // Get command and references to key/value bytes without unnecessary copying
+        let start = Instant::now();
+        // Taken on every frame so a lock wait is never charged to a later command
+        let wait = std::mem::take(&mut self.lock_wait);
+        
+        // Frames after an M or S header carry its keys whatever their command byte
+        if let Some(request) = self.pending.as_mut() {
+            request.wait += wait;
+            if !request.push(&input) {
+                return Ok(());
+            }
+            let request = self.pending.take().unwrap();
+            let (command, key, started, wait) = (request.kind.command(), request.keys[0], request.started, request.wait);
+            let result = self.handle_multi(request);
+            self.slowlog.observe(command, &key, started.elapsed(), wait, self.batch_size);
+            return result;
+        }
+        
         let command = input[0];
+        self.stats().command(command);
+        let result = self.handle_command(command, &input);
+        
+        match self.pending.as_mut() {
+            // The header started a multi-key request, timed once its reply is sent
+            Some(request) => (request.started, request.wait) = (start, wait),
+            None => {
+                let elapsed = start.elapsed();
+                if let Some(operation) = Operation::from_command(command) {
+                    self.stats().latency().record(operation, elapsed);
+                }
+                self.slowlog.observe(command, &input[1..64], elapsed, wait, self.batch_size);
+            }
+        }
+        result
+    }
+
+    // New batch processing method for improved throughput
+    fn handle_batch(&'a mut self, inputs: &[[u8;128]]) -> Result<BatchResults, Box<dyn std::error::Error>> {
+        let start = Instant::now();
+        let mut results = Vec::with_capacity(inputs.len());
+        
+        // Process all commands in batch
+        for input in inputs {
+            results.push(self.handle_in(*input));
+        }
         
+        // Always force a save after batch operations
+        self.save_flag.store(true, std::sync::atomic::Ordering::SeqCst);
+        
+        self.stats().latency().record(Operation::Batch, start.elapsed());
+        Ok(results)
+    }
+}
+
+impl Cache {
+    // Run one command frame and send its reply
+    fn handle_command(&mut self, command: u8, input: &[u8; 128]) -> Result<(), Box<dyn std::error::Error>> {
         // We do need to copy for HashMap keys, but we'll minimize unnecessary copies
         let key_slice = &input[1..64];
         
         // Check if key is empty or all zeros (treat as invalid)
         let is_empty_key = key_slice.iter().all(|&b| b == 0);
-        if is_empty_key && (command == b'I' || command == b'G') {
-            let stdout = io::stdout();
-            let mut handle = stdout.lock();
-            handle.write_all(b"E\n").unwrap(); // Return error code for empty key
-            handle.flush().unwrap();
+        if is_empty_key && matches!(command, b'I' | b'G' | b'V' | b'N' | b'X' | b'C' | b'D' | b'+' | b'-') {
+            self.responses.send(b"E\n")?; // Return error code for empty key
             return Ok(());
         }
         
         let value_slice = &input[64..128];
         
-        let vals = Arc::clone(&self.vals);
-
         match command {
             b'G' => {
-                // Optimize by using RwLock's read access for get operations
-                let kv = vals.read().expect("Unable to get read lock on KV in thread");
                 let mut key = [0u8; 63];
                 key.copy_from_slice(key_slice);
                 
-                if let Some(out) = kv.get(&key) {
-                    let stdout = io::stdout();
-                    let mut handle = stdout.lock();
-                    handle.write_all(out).unwrap();
-                    handle.write(b"\n").unwrap();
-                    handle.flush().unwrap();
+                let found = self.storage.get(&key)?;
+                self.stats().lookup(self.namespace, found.is_some());
+                if let Some(out) = found {
+                    self.responses.send(&[&storage::wire(&out)[..], b"\n"].concat())?;
                 } else {
-                    let stdout = io::stdout();
-                    let mut handle = stdout.lock();
-                    handle.write_all(b"G").unwrap();
-                    handle.write(b"\n").unwrap();
-                    handle.flush().unwrap();
+                    self.responses.send(b"G\n")?;
                 };
             },
 
-            b'R' => {
-                let mut kv = vals.write().expect("Unable to lock KV in thread");
+            b'V' => {
                 let mut key = [0u8; 63];
                 key.copy_from_slice(key_slice);
                 
-                // Also remove from entries map
-                self.entries.remove(&key);
-                
-                let _ = kv.remove(&key);
-                let stdout = io::stdout();
-                let mut handle = stdout.lock();
-                handle.write_all(b"R\n").unwrap();
-                handle.flush().unwrap();
+                // The value and its version read together, for a later compare-and-swap
+                let found = self.storage.get(&key)?;
+                self.stats().lookup(self.namespace, found.is_some());
+                let response = match found {
+                    Some(out) => [&storage::wire(&out)[..], format!("{}\n", storage::version(&out)).as_bytes()].concat(),
+                    None => b"V\n".to_vec(),
+                };
+                self.responses.send(&response)?;
+            }
+
+            b'R' => {
+                let mut key = [0u8; 63];
+                key.copy_from_slice(key_slice);
                 
-                // Force save on remove to ensure persistence
-                self.save_flag.store(true, std::sync::atomic::Ordering::SeqCst);
+                if self.storage.delete(&key)?.is_some() {
+                    self.mark_dirty(1);
+                }
+                self.responses.send(b"R\n")?;
+            }
+            
+            b'D' => {
+                // Key bytes hold a glob pattern; removed from storage in one write and counted
+                let pattern = scan::trim(key_slice);
+                let removed = self.storage.delete_where(&mut |key, _| scan::glob_match(pattern, scan::trim(key)))?;
+                if removed > 0 {
+                    self.mark_dirty(removed);
+                }
+                self.responses.send(format!("{}\n", removed).as_bytes())?;
             }
             
             b'I' => {
-                let mut kv = vals.write().expect("Unable to lock KV in thread");
-                if self.level == crate::LogLevel::DEBUG {
-                    self.log_debug(format!("ADDING KV"));
+                if self.logger().enabled(LogLevel::Trace) {
+                    self.log_with(LogLevel::Trace, "command", "insert", &[("key_hash", serde_json::json!(logger::key_hash(scan::trim(key_slice))))]);
                 }
                 
                 let mut key = [0u8; 63];
                 let mut value = [0u8; 64];
                 key.copy_from_slice(key_slice);
                 value.copy_from_slice(value_slice);
+                // The TTL counts from the server's insert time, not the timestamp the client sent
+                value[56..62].copy_from_slice(&Utc::now().timestamp().to_be_bytes()[2..8]);
                 
-                // Extract expiration time and timestamp for the cache entry
-                let expire_time_seconds = u16::from_be_bytes([value[62], value[63]]);
+                self.storage.update(&key, &mut |current| Some(storage::stored(&value, storage::next_version(current))))?;
+                self.mark_dirty(1);
                 
-                // Set timestamp to now
-                let timestamp = Utc::now();
+                self.responses.send(b"I\n")?;
+            }
+
+            b'N' | b'X' | b'C' => {
+                let mut key = [0u8; 63];
+                let mut frame = [0u8; 64];
+                key.copy_from_slice(key_slice);
+                frame.copy_from_slice(value_slice);
+                
+                // Check and write in one storage update so no other write can slip in between
+                let condition = Condition::from_frame(command, &frame).expect("conditional insert command");
+                let now = Utc::now();
+                let written = self.storage.update(&key, &mut |current| {
+                    condition
+                        .holds(current, now)
+                        .then(|| storage::stored(&condition.value(&frame, now), storage::next_version(current)))
+                })?;
                 
-                // Calculate expiration time
-                let expires_at = if expire_time_seconds > 0 {
-                    Some(timestamp + chrono::TimeDelta::try_seconds(expire_time_seconds as i64).unwrap_or_default())
+                let response: &[u8] = if written {
+                    self.mark_dirty(1);
+                    b"I\n"
                 } else {
-                    None
+                    b"F\n"
                 };
+                self.responses.send(response)?;
+            }
+
+            b'M' | b'S' => {
+                let mut frame = [0u8; 64];
+                frame.copy_from_slice(value_slice);
+                let kind = if command == b'M' { multi::Kind::Get } else { multi::Kind::Set };
                 
-                // Create and store cache entry
-                let mut value_only = [0u8; 56];
-                value_only.copy_from_slice(&value[0..56]);
+                match multi::Request::start(kind, &frame) {
+                    Some(request) => self.pending = Some(request),
+                    None => {
+                        self.responses.send(b"E\n")?;
+                    }
+                }
+            }
+
+            b'P' => {
+                let mut cursor = [0u8; 63];
+                let mut frame = [0u8; 64];
+                cursor.copy_from_slice(key_slice);
+                frame.copy_from_slice(value_slice);
                 
-                let entry = crate::CacheEntry {
-                    value: value_only,
-                    created_at: timestamp,
-                    expires_at,
+                let (count, pattern) = scan::scan_args(&frame);
+                let cursor = (!is_empty_key).then_some(&cursor);
+                let page = scan::scan(self.storage.as_ref(), cursor, pattern, count, Utc::now())?;
+                self.responses.send(&page.response())?;
+            }
+
+            b'K' => {
+                let pattern = scan::trim(key_slice);
+                let response = match scan::keys(self.storage.as_ref(), pattern, scan::MAX_KEYS_REPLY, Utc::now())? {
+                    Some(page) => page.response(),
+                    // Too many to list at once; SCAN pages through them
+                    None => b"E\n".to_vec(),
                 };
+                self.responses.send(&response)?;
+            }
+
+            b'+' | b'-' => {
+                let mut key = [0u8; 63];
+                let mut frame = [0u8; 64];
+                key.copy_from_slice(key_slice);
+                frame.copy_from_slice(value_slice);
                 
-                // Store in both collections
-                self.entries.insert(key, entry);
-                let _ = kv.insert(key, value);
+                // Read, add and write back in one storage update so concurrent writers cannot interleave
+                let now = Utc::now();
+                let mut outcome = Err(counter::CounterError::NotAnInteger);
+                self.storage.update(&key, &mut |current| {
+                    outcome = counter::apply(current, &frame, command == b'-', now);
+                    outcome.as_ref().ok().map(|(_, value)| *value)
+                })?;
                 
-                let stdout = io::stdout();
-                let mut handle = stdout.lock();
-                handle.write_all(b"I\n").unwrap();
-                handle.flush().unwrap();
+                let response = match outcome {
+                    Ok((counter, _)) => {
+                        self.mark_dirty(1);
+                        format!("{}\n", counter)
+                    }
+                    Err(counter::CounterError::Overflow) => "O\n".to_string(),
+                    Err(counter::CounterError::NotAnInteger) => "E\n".to_string(),
+                };
+                self.responses.send(response.as_bytes())?;
+            }
+
+            b'U' => {
+                // Value bytes hold the namespace number as ASCII decimal
+                let mut frame = [0u8; 64];
+                frame.copy_from_slice(value_slice);
+                let selected = counter::parse(&frame)
+                    .and_then(|namespace| usize::try_from(namespace).ok())
+                    .filter(|&namespace| namespace < self.namespaces.count())
+                    .map(|namespace| self.select(namespace))
+                    .transpose()?;
                 
-                // Flag for save on insert to ensure persistence
-                self.save_flag.store(true, std::sync::atomic::Ordering::SeqCst);
+                let response: &[u8] = if selected.is_some() { b"U\n" } else { b"E\n" };
+                self.responses.send(response)?;
+            }
+
+            b'Z' => {
+                // Keys in the selected namespace
+                let response = format!("{}\n", self.storage.len()?);
+                self.responses.send(response.as_bytes())?;
+            }
+
+            b'W' => {
+                // Value bytes: optional words "all" to flush every namespace and "async" to save in the background
+                let words = String::from_utf8_lossy(scan::trim(&value_slice[..56])).to_ascii_lowercase();
+                let (mut all, mut background, mut valid) = (false, false, true);
+                for word in words.split_whitespace() {
+                    match word {
+                        "all" => all = true,
+                        "async" => background = true,
+                        _ => valid = false,
+                    }
+                }
+                
+                let response: &[u8] = match valid.then(|| self.flush(all, background)) {
+                    Some(Ok(())) => b"W\n",
+                    Some(Err(e)) => {
+                        self.log(LogLevel::Error, "flush", format!("saving the flushed state failed: {}", e));
+                        b"E\n"
+                    }
+                    None => b"E\n",
+                };
+                self.responses.send(response)?;
             }
 
             b'H' => {
                 if let Err(e) = self.clean_up() {
-                    if self.level == crate::LogLevel::DEBUG {
-                        println!("An error occurred in clean_up: {}", e);
-                    }
+                    self.log(LogLevel::Error, "snapshot", format!("save failed: {}", e));
                 }
             }
+
+            b'B' => {
+                // Snapshot in the background; E if a background save is already running
+                let response: &[u8] = if self.bgsave() { b"B\n" } else { b"E\n" };
+                self.responses.send(response)?;
+            }
+
+            b'T' => {
+                let response = format!("{}\n", self.info()?);
+                self.responses.send(response.as_bytes())?;
+            }
+
+            b'O' => {
+                // Value bytes: "reset", or how many of the newest entries to return, all when empty
+                let argument = scan::trim(&value_slice[..56]);
+                let response = if argument.eq_ignore_ascii_case(b"reset") {
+                    self.slowlog.reset();
+                    b"O\n".to_vec()
+                } else if argument.is_empty() {
+                    self.slowlog.response(usize::MAX)
+                } else {
+                    match std::str::from_utf8(argument).ok().and_then(|count| count.trim().parse().ok()) {
+                        Some(count) => self.slowlog.response(count),
+                        None => b"E\n".to_vec(),
+                    }
+                };
+                self.responses.send(&response)?;
+            }
+
+            b'L' => {
+                // Last successful save as epoch seconds, followed by its outcome and whether a save is running
+                let status = self.save_status();
+                let response = format!(
+                    "{} {} {}\n",
+                    status.last_save(),
+                    if status.last_ok() { "ok" } else { "err" },
+                    if status.is_in_progress() { "running" } else { "idle" },
+                );
+                self.responses.send(response.as_bytes())?;
+            }
+
+            b'Q' => {
+                self.responses.send(b"Q\n")?;
+                
+                // The main loop finishes queued commands, saves and exits
+                self.shutdown.request();
+            }
             _ => {}, // Early return for unrecognized command
         };
-
+        
         Ok(())
     }
 
-    // New batch processing method for improved throughput
-    fn handle_batch(&'a mut self, inputs: &[[u8;128]]) -> Result<Vec<Result<(), Box<dyn std::error::Error>>>, Box<dyn std::error::Error>> {
-        let mut results = Vec::with_capacity(inputs.len());
-        
-        // Process all commands in batch
-        for input in inputs {
-            results.push(self.handle_in(*input));
-        }
-        
-        // Always force a save after batch operations
-        self.save_flag.store(true, std::sync::atomic::Ordering::SeqCst);
+    // Answer a multi-key request once all of its frames have arrived
+    fn handle_multi(&mut self, request: multi::Request) -> Result<(), Box<dyn std::error::Error>> {
+        let response = if request.invalid {
+            b"E\n".to_vec()
+        } else {
+            match request.kind {
+                multi::Kind::Get => {
+                    let values = request
+                        .keys
+                        .iter()
+                        .map(|key| Ok(self.storage.get(key)?.map(|value| *storage::wire(&value))))
+                        .collect::<std::io::Result<Vec<_>>>()?;
+                    for value in &values {
+                        self.stats().lookup(self.namespace, value.is_some());
+                    }
+                    multi::get_response(&values)
+                }
+                multi::Kind::Set => {
+                    // One storage update, so readers and snapshots see all of the keys or none. As with I,
+                    // the TTLs count from the server's clock.
+                    let now = Utc::now().timestamp().to_be_bytes();
+                    let written = self.storage.update_many(&request.keys, &mut |index, current| {
+                        let mut value = request.values[index];
+                        value[56..62].copy_from_slice(&now[2..8]);
+                        Some(storage::stored(&value, storage::next_version(current)))
+                    })?;
+                    self.mark_dirty(written);
+                    b"I\n".to_vec()
+                }
+            }
+        };
         
-        Ok(results)
+        self.responses.send(&response)?;
+        Ok(())
     }
 }
 
@@ -165,13 +388,22 @@ mod tests {
 
     // Helper to create a Cache with a pre-filled buffer
     fn setup_cache_with_buffer(buffer: [u8; 128]) -> Cache {
-        let cache = Cache::new("/tmp/cache_test.log", crate::LogLevel::DEBUG);
+        let cache = Cache::new("/tmp/cache_test.log", crate::LogLevel::Debug);
         let buf = Arc::clone(&cache.cur_buf);
         let mut buf = buf.lock().unwrap();
         *buf = buffer;
         cache
     }
 
+    // Helper to create a Cache persisting into a temporary data directory
+    fn setup_cache_in(dir: &tempfile::TempDir) -> Cache {
+        let config = crate::config::Config {
+            data_dir: dir.path().to_path_buf(),
+            ..crate::config::Config::default()
+        };
+        Cache::with_config("/tmp/cache_test.log", crate::LogLevel::Debug, config)
+    }
+
     // Helper to create a test buffer (command + 63-byte key + 60-byte value + 4-byte expiration)
     fn create_test_buffer(command: u8, key: &[u8; 63], value: &[u8; 60], expiration: &[u8; 4]) -> [u8; 128] {
         let mut buf = [0; 128];
@@ -191,14 +423,12 @@ mod tests {
         full_value[0..60].copy_from_slice(&value);
         full_value[60..64].copy_from_slice(expiration);
 
-        let cache = Cache::new("/tmp/cache_test.log", crate::LogLevel::DEBUG);
-        cache.vals.write().unwrap().insert(key, full_value);
-
         let buf = create_test_buffer(b'G', &key, &value, expiration);
-        let cache = setup_cache_with_buffer(buf);
+        let mut cache = setup_cache_with_buffer(buf);
+        cache.storage.put(key, crate::storage::stored(&full_value, 1)).unwrap();
+        cache.handle_in(buf).unwrap();
 
-        let kv = cache.vals.read().unwrap();
-        assert_eq!(kv.get(&key), Some(&full_value));
+        assert_eq!(cache.storage.get(&key).unwrap().map(|value| *crate::storage::wire(&value)), Some(full_value));
     }
 
     #[test]
@@ -210,14 +440,12 @@ mod tests {
         full_value[0..60].copy_from_slice(&value);
         full_value[60..64].copy_from_slice(expiration);
 
-        let cache = Cache::new("/tmp/cache_test.log", crate::LogLevel::DEBUG);
-        cache.vals.write().unwrap().insert(key, full_value);
-
         let buf = create_test_buffer(b'R', &key, &value, expiration);
-        let cache = setup_cache_with_buffer(buf);
+        let mut cache = setup_cache_with_buffer(buf);
+        cache.storage.put(key, crate::storage::stored(&full_value, 1)).unwrap();
+        cache.handle_in(buf).unwrap();
 
-        let kv = cache.vals.read().unwrap();
-        assert_eq!(kv.get(&key), None);
+        assert_eq!(cache.storage.get(&key).unwrap(), None);
     }
 
     #[test]
@@ -230,10 +458,34 @@ mod tests {
         full_value[60..64].copy_from_slice(expiration);
 
         let buf = create_test_buffer(b'I', &key, &value, expiration);
-        let cache = setup_cache_with_buffer(buf);
+        let mut cache = setup_cache_with_buffer(buf);
+        cache.handle_in(buf).unwrap();
+
+        // Everything but the timestamp is stored as sent
+        let stored = *crate::storage::wire(&cache.storage.get(&key).unwrap().unwrap());
+        assert_eq!(stored[..56], full_value[..56]);
+        assert_eq!(stored[62..], full_value[62..]);
+    }
+
+    #[test]
+    fn test_insert_ttl_counts_from_server_time() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let mut cache = setup_cache_in(&dir);
+        let (writer, captured) = crate::response::ResponseWriter::capture();
+        cache.set_response_writer(writer);
+
+        // Timestamp 0 and a 60 second TTL: expired long ago if the client's timestamp counted
+        let mut value = [0u8; 60];
+        value[..5].copy_from_slice(b"fresh");
+        cache.handle_in(create_test_buffer(b'I', &[1; 63], &value, &[0, 0, 0, 60])).unwrap();
+        captured.take();
+        cache.handle_in(create_test_buffer(b'G', &[1; 63], &[0; 60], &[0; 4])).unwrap();
 
-        let kv = cache.vals.read().unwrap();
-        assert_eq!(kv.get(&key), Some(&full_value));
+        let reply = captured.take();
+        assert_eq!(reply.len(), 65);
+        assert_eq!(&reply[..5], b"fresh");
+        let (created_at, _) = crate::utils::parse_cache_metadata(reply[..64].try_into().unwrap());
+        assert!((Utc::now() - created_at).num_seconds() < 5);
     }
 
     #[test]
@@ -253,17 +505,16 @@ mod tests {
             buffers.push(create_test_buffer(b'I', &key, &value, expiration));
         }
         
-        let mut cache = Cache::new("/tmp/cache_test.log", crate::LogLevel::DEBUG);
+        let mut cache = Cache::new("/tmp/cache_test.log", crate::LogLevel::Debug);
         let result = cache.handle_batch(&buffers);
         
         assert!(result.is_ok());
         
         // Verify all 3 keys were inserted
-        let kv = cache.vals.read().unwrap();
         for i in 0..3 {
             let mut key = [0u8; 63];
             key[0] = i + 1;
-            assert!(kv.contains_key(&key));
+            assert!(cache.storage.get(&key).unwrap().is_some());
         }
     }
     
@@ -280,8 +531,634 @@ mod tests {
         assert!(result.is_ok());
         
         // Empty key should not be inserted
-        let kv = cache.vals.read().unwrap();
-        assert!(!kv.contains_key(&key));
+        assert!(cache.storage.get(&key).unwrap().is_none());
+    }
+    
+    #[test]
+    fn test_shutdown_command() {
+        let key = [1; 63];
+        let value = [0; 60];
+        let expiration = [0; 4];
+        
+        let buf = create_test_buffer(b'Q', &key, &value, &expiration);
+        let mut cache = setup_cache_with_buffer(buf);
+        let shutdown = cache.shutdown_signal();
+        assert!(!shutdown.is_requested());
+        
+        cache.handle_in(buf).unwrap();
+        
+        // Shutdown is only requested; the key space is untouched
+        assert!(shutdown.is_requested());
+        assert!(cache.storage.is_empty().unwrap());
+    }
+    
+    #[test]
+    fn test_background_save() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let mut cache = setup_cache_in(&dir);
+        
+        let key = [1; 63];
+        let value = [2; 60];
+        let expiration = [0; 4];
+        cache.handle_in(create_test_buffer(b'I', &key, &value, &expiration)).unwrap();
+        assert_eq!(cache.save_status().last_save(), 0);
+        
+        cache.handle_in(create_test_buffer(b'B', &[0; 63], &[0; 60], &[0; 4])).unwrap();
+        cache.thread_pool.join();
+        
+        assert!(cache.save_status().last_save() > 0);
+        assert!(!cache.save_status().is_in_progress());
+        let saved = crate::snapshot::read(&dir.path().join(crate::snapshot::SNAPSHOT_FILE), &cache.codec()).unwrap();
+        assert_eq!(saved.len(), crate::snapshot::RECORD_SIZE);
+    }
+    
+    #[test]
+    fn test_background_save_captures_point_in_time() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let mut cache = setup_cache_in(&dir);
+        
+        let key = [1; 63];
+        cache.handle_in(create_test_buffer(b'I', &key, &[2; 60], &[0; 4])).unwrap();
+        
+        // Keep the pool busy so the save is still queued when the key is removed
+        let gate = Arc::new(std::sync::Mutex::new(()));
+        let guard = gate.lock().unwrap();
+        for _ in 0..crate::THREAD_POOL_SIZE {
+            let gate = Arc::clone(&gate);
+            cache.thread_pool.execute(move || {
+                drop(gate.lock());
+            });
+        }
+        
+        assert!(cache.bgsave());
+        cache.handle_in(create_test_buffer(b'R', &key, &[0; 60], &[0; 4])).unwrap();
+        assert!(cache.storage.is_empty().unwrap());
+        
+        drop(guard);
+        cache.thread_pool.join();
+        
+        let saved = crate::snapshot::read(&dir.path().join(crate::snapshot::SNAPSHOT_FILE), &cache.codec()).unwrap();
+        assert_eq!(saved[0], 0);
+        assert_eq!(&saved[1..64], &key);
+    }
+    
+    #[test]
+    fn test_dirty_counter_drives_save_policy() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let config = crate::config::Config {
+            data_dir: dir.path().to_path_buf(),
+            save_policies: vec![crate::config::SavePolicy { after: std::time::Duration::ZERO, min_changes: 2 }],
+            ..crate::config::Config::default()
+        };
+        let mut cache = Cache::with_config("/tmp/cache_test.log", crate::LogLevel::Debug, config);
+        
+        let mut key = [0u8; 63];
+        key[0] = 1;
+        cache.handle_in(create_test_buffer(b'I', &key, &[2; 60], &[0; 4])).unwrap();
+        assert_eq!(cache.dirty(), 1);
+        assert!(!cache.save_due());
+        
+        // Removing a missing key is not a change
+        let mut missing = [0u8; 63];
+        missing[0] = 9;
+        cache.handle_in(create_test_buffer(b'R', &missing, &[0; 60], &[0; 4])).unwrap();
+        assert_eq!(cache.dirty(), 1);
+        
+        cache.handle_in(create_test_buffer(b'R', &key, &[0; 60], &[0; 4])).unwrap();
+        assert_eq!(cache.dirty(), 2);
+        assert!(cache.save_due());
+        
+        cache.clean_up().unwrap();
+        assert_eq!(cache.dirty(), 0);
+        assert!(!cache.save_due());
+    }
+    
+    #[test]
+    fn test_encrypted_snapshot_round_trip() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let config = crate::config::Config {
+            data_dir: dir.path().to_path_buf(),
+            encryption: Some(crate::crypto::Keyring::parse(&"5a".repeat(32)).unwrap()),
+            ..crate::config::Config::default()
+        };
+        
+        let mut key = [0u8; 63];
+        key[..12].copy_from_slice(b"session:1234");
+        let mut value = [0u8; 60];
+        value[..6].copy_from_slice(b"secret");
+        
+        let mut cache = Cache::with_config("/tmp/cache_test.log", crate::LogLevel::Debug, config.clone());
+        cache.handle_in(create_test_buffer(b'I', &key, &value, &[0; 4])).unwrap();
+        cache.clean_up().unwrap();
+        
+        let on_disk = std::fs::read(dir.path().join(crate::snapshot::SNAPSHOT_FILE)).unwrap();
+        assert!(!on_disk.windows(6).any(|w| w == b"secret"));
+        assert!(!on_disk.windows(8).any(|w| w == b"session:"));
+        
+        let mut restored = Cache::with_config("/tmp/cache_test.log", crate::LogLevel::Debug, config);
+        restored.load().unwrap();
+        assert!(restored.storage.get(&key).unwrap().is_some());
+        
+        // Without the key the snapshot cannot be loaded
+        let mut keyless = setup_cache_in(&dir);
+        assert!(keyless.load().is_err());
+    }
+    
+    #[test]
+    fn test_restore_generation() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let config = crate::config::Config {
+            data_dir: dir.path().to_path_buf(),
+            snapshot_retention: crate::generations::Retention { keep: 5, max_age: None },
+            ..crate::config::Config::default()
+        };
+        let mut cache = Cache::with_config("/tmp/cache_test.log", crate::LogLevel::Debug, config.clone());
+        
+        let mut good = [0u8; 63];
+        good[0] = 1;
+        let mut bad = [0u8; 63];
+        bad[0] = 2;
+        
+        cache.handle_in(create_test_buffer(b'I', &good, &[1; 60], &[0; 4])).unwrap();
+        cache.clean_up().unwrap();
+        let before_bad_write = chrono::Utc::now();
+        std::thread::sleep(std::time::Duration::from_millis(5));
+        
+        cache.handle_in(create_test_buffer(b'I', &bad, &[2; 60], &[0; 4])).unwrap();
+        cache.clean_up().unwrap();
+        
+        let mut restored = Cache::with_config("/tmp/cache_test.log", crate::LogLevel::Debug, config.clone());
+        restored.restore(&crate::generations::RestoreTarget::Before(before_bad_write)).unwrap();
+        assert!(restored.storage.get(&good).unwrap().is_some());
+        assert!(restored.storage.get(&bad).unwrap().is_none());
+        
+        // The restored state is now the current snapshot
+        let mut reloaded = Cache::with_config("/tmp/cache_test.log", crate::LogLevel::Debug, config);
+        reloaded.load().unwrap();
+        assert!(reloaded.storage.get(&bad).unwrap().is_none());
+    }
+    
+    #[test]
+    fn test_disk_storage_survives_restart_without_snapshots() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let config = crate::config::Config {
+            data_dir: dir.path().to_path_buf(),
+            save_policies: Vec::new(),
+            storage: crate::storage::StorageKind::Btree,
+            ..crate::config::Config::default()
+        };
+        
+        let key = [1; 63];
+        {
+            let mut cache = Cache::with_config("/tmp/cache_test.log", crate::LogLevel::Debug, config.clone());
+            cache.handle_in(create_test_buffer(b'I', &key, &[2; 60], &[0; 4])).unwrap();
+            cache.final_save().unwrap();
+        }
+        assert!(!dir.path().join(crate::snapshot::SNAPSHOT_FILE).exists());
+        
+        let mut restarted = Cache::with_config("/tmp/cache_test.log", crate::LogLevel::Debug, config);
+        restarted.load().unwrap();
+        assert_eq!(&restarted.storage.get(&key).unwrap().unwrap()[..56], &[2; 56]);
+    }
+    
+    #[test]
+    fn test_counter_commands() {
+        let mut key = [0u8; 63];
+        key[..8].copy_from_slice(b"requests");
+        let mut cache = Cache::new("/tmp/cache_test.log", crate::LogLevel::Debug);
+        
+        let mut step = [0u8; 60];
+        cache.handle_in(create_test_buffer(b'+', &key, &step, &[0; 4])).unwrap();
+        step[..2].copy_from_slice(b"10");
+        cache.handle_in(create_test_buffer(b'+', &key, &step, &[0; 4])).unwrap();
+        step[..2].copy_from_slice(b"3\0");
+        cache.handle_in(create_test_buffer(b'-', &key, &step, &[0; 4])).unwrap();
+        
+        let stored = cache.storage.get(&key).unwrap().unwrap();
+        assert_eq!(crate::counter::parse(crate::storage::wire(&stored)), Some(8));
+        assert_eq!(cache.dirty(), 3);
+        
+        // A text value is left alone
+        let mut text = [0u8; 63];
+        text[0] = 1;
+        cache.handle_in(create_test_buffer(b'I', &text, &[b'a'; 60], &[0; 4])).unwrap();
+        cache.handle_in(create_test_buffer(b'+', &text, &[0; 60], &[0; 4])).unwrap();
+        assert_eq!(cache.storage.get(&text).unwrap().unwrap()[0], b'a');
+        assert_eq!(cache.dirty(), 4);
+    }
+    
+    #[test]
+    fn test_conditional_inserts() {
+        let mut key = [0u8; 63];
+        key[..4].copy_from_slice(b"lock");
+        let mut cache = Cache::new("/tmp/cache_test.log", crate::LogLevel::Debug);
+        let version = |cache: &Cache| crate::storage::version(&cache.storage.get(&key).unwrap().unwrap());
+        
+        // X fails on a missing key, N creates it and then fails
+        cache.handle_in(create_test_buffer(b'X', &key, &[b'x'; 60], &[0; 4])).unwrap();
+        assert_eq!(cache.storage.get(&key).unwrap(), None);
+        cache.handle_in(create_test_buffer(b'N', &key, &[b'a'; 60], &[0; 4])).unwrap();
+        cache.handle_in(create_test_buffer(b'N', &key, &[b'b'; 60], &[0; 4])).unwrap();
+        assert_eq!(cache.storage.get(&key).unwrap().unwrap()[0], b'a');
+        assert_eq!(cache.dirty(), 1);
+        
+        cache.handle_in(create_test_buffer(b'X', &key, &[b'c'; 60], &[0; 4])).unwrap();
+        assert_eq!(cache.storage.get(&key).unwrap().unwrap()[0], b'c');
+        let current = version(&cache);
+        
+        // C with a stale version is rejected, with the current one it writes and bumps the version
+        let compare_and_swap = |expected: u64| {
+            let mut buf = create_test_buffer(b'C', &key, &[b'd'; 60], &[0; 4]);
+            buf[120..126].copy_from_slice(&expected.to_be_bytes()[2..8]);
+            buf
+        };
+        cache.handle_in(compare_and_swap(current - 1)).unwrap();
+        assert_eq!(cache.storage.get(&key).unwrap().unwrap()[0], b'c');
+        cache.handle_in(compare_and_swap(current)).unwrap();
+        assert_eq!(cache.storage.get(&key).unwrap().unwrap()[0], b'd');
+        assert_eq!(version(&cache), current + 1);
+        assert_eq!(cache.dirty(), 3);
+        
+        // V is a read
+        cache.handle_in(create_test_buffer(b'V', &key, &[0; 60], &[0; 4])).unwrap();
+        assert_eq!(version(&cache), current + 1);
+    }
+    
+    #[test]
+    fn test_multi_key_commands() {
+        let mut cache = Cache::new("/tmp/cache_test.log", crate::LogLevel::Debug);
+        let mut count = [0u8; 60];
+        count[0] = b'3';
+        let keys: Vec<[u8; 63]> = (1..=3u8).map(|i| [i; 63]).collect();
+        
+        // The header alone writes nothing; the keys land with the last frame
+        cache.handle_in(create_test_buffer(b'S', &[0; 63], &count, &[0; 4])).unwrap();
+        for (i, key) in keys.iter().enumerate() {
+            assert_eq!(cache.storage.len().unwrap(), 0);
+            cache.handle_in(create_test_buffer(b'I', key, &[b'a' + i as u8; 60], &[0; 4])).unwrap();
+        }
+        assert_eq!(cache.storage.len().unwrap(), 3);
+        assert_eq!(cache.storage.get(&keys[2]).unwrap().unwrap()[0], b'c');
+        assert_eq!(cache.dirty(), 3);
+        
+        // Frames after an MGET header are keys, not commands
+        count[0] = b'2';
+        cache.handle_in(create_test_buffer(b'M', &[0; 63], &count, &[0; 4])).unwrap();
+        cache.handle_in(create_test_buffer(b'R', &keys[0], &[0; 60], &[0; 4])).unwrap();
+        cache.handle_in(create_test_buffer(b'R', &[9; 63], &[0; 60], &[0; 4])).unwrap();
+        assert!(cache.pending.is_none());
+        assert_eq!(cache.storage.len().unwrap(), 3);
+        
+        // An empty key rejects the whole MSET
+        cache.handle_in(create_test_buffer(b'S', &[0; 63], &count, &[0; 4])).unwrap();
+        cache.handle_in(create_test_buffer(b'I', &[7; 63], &[b'z'; 60], &[0; 4])).unwrap();
+        cache.handle_in(create_test_buffer(b'I', &[0; 63], &[b'z'; 60], &[0; 4])).unwrap();
+        assert_eq!(cache.storage.get(&[7; 63]).unwrap(), None);
+        
+        // A bad count leaves the next frame to be a command again
+        cache.handle_in(create_test_buffer(b'S', &[0; 63], &[0; 60], &[0; 4])).unwrap();
+        assert!(cache.pending.is_none());
+    }
+    
+    #[test]
+    fn test_mset_ttl_counts_from_server_time() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let mut cache = setup_cache_in(&dir);
+        let mut count = [0u8; 60];
+        count[0] = b'1';
+        
+        // Timestamp 0 and a 60 second TTL: expired long ago if the client's timestamp counted
+        cache.handle_in(create_test_buffer(b'S', &[0; 63], &count, &[0; 4])).unwrap();
+        cache.handle_in(create_test_buffer(b'I', &[1; 63], &[b'a'; 60], &[0, 0, 0, 60])).unwrap();
+        
+        let (writer, captured) = crate::response::ResponseWriter::capture();
+        cache.set_response_writer(writer);
+        cache.handle_in(create_test_buffer(b'G', &[1; 63], &[0; 60], &[0; 4])).unwrap();
+        let reply = captured.take();
+        assert_eq!(reply.len(), 65);
+        let (created_at, _) = crate::utils::parse_cache_metadata(reply[..64].try_into().unwrap());
+        assert!((Utc::now() - created_at).num_seconds() < 5);
+    }
+    
+    #[test]
+    fn test_delete_matching() {
+        let mut cache = Cache::new("/tmp/cache_test.log", crate::LogLevel::Debug);
+        for name in ["session:1:a", "session:1:b", "session:10:a", "user:1"] {
+            let mut key = [0u8; 63];
+            key[..name.len()].copy_from_slice(name.as_bytes());
+            cache.handle_in(create_test_buffer(b'I', &key, &[1; 60], &[0; 4])).unwrap();
+        }
+        
+        let mut pattern = [0u8; 63];
+        pattern[..10].copy_from_slice(b"session:1:");
+        pattern[10] = b'*';
+        cache.handle_in(create_test_buffer(b'D', &pattern, &[0; 60], &[0; 4])).unwrap();
+        assert_eq!(cache.storage.len().unwrap(), 2);
+        assert_eq!(cache.dirty(), 6);
+        
+        // An empty pattern is rejected rather than clearing the cache
+        cache.handle_in(create_test_buffer(b'D', &[0; 63], &[0; 60], &[0; 4])).unwrap();
+        assert_eq!(cache.storage.len().unwrap(), 2);
+    }
+    
+    #[test]
+    fn test_namespaces_are_separate_and_persisted() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let key = [1u8; 63];
+        let select = |namespace: &str| {
+            let mut value = [0u8; 60];
+            value[..namespace.len()].copy_from_slice(namespace.as_bytes());
+            create_test_buffer(b'U', &[0; 63], &value, &[0; 4])
+        };
+        
+        {
+            let mut cache = setup_cache_in(&dir);
+            cache.handle_in(create_test_buffer(b'I', &key, &[b'a'; 60], &[0; 4])).unwrap();
+            cache.handle_in(select("3")).unwrap();
+            assert_eq!(cache.namespace(), 3);
+            assert_eq!(cache.storage.get(&key).unwrap(), None);
+            cache.handle_in(create_test_buffer(b'I', &key, &[b'b'; 60], &[0; 4])).unwrap();
+            
+            // Out of range selections keep the current namespace
+            cache.handle_in(select("99")).unwrap();
+            assert_eq!(cache.namespace(), 3);
+            cache.clean_up().unwrap();
+        }
+        
+        let mut cache = setup_cache_in(&dir);
+        cache.load().unwrap();
+        assert_eq!(cache.storage.get(&key).unwrap().unwrap()[0], b'a');
+        cache.handle_in(select("3")).unwrap();
+        assert_eq!(cache.storage.get(&key).unwrap().unwrap()[0], b'b');
+        assert_eq!(cache.key_count().unwrap(), 2);
+        
+        // With fewer namespaces configured, the snapshot is refused and nothing is loaded
+        let config = crate::config::Config { data_dir: dir.path().to_path_buf(), namespaces: 2, ..crate::config::Config::default() };
+        let mut cache = Cache::with_config("/tmp/cache_test.log", crate::LogLevel::Debug, config);
+        assert!(cache.load().is_err());
+        assert_eq!(cache.key_count().unwrap(), 0);
+    }
+    
+    #[test]
+    fn test_flush_commands() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let mut cache = setup_cache_in(&dir);
+        let words = |text: &str| {
+            let mut value = [0u8; 60];
+            value[..text.len()].copy_from_slice(text.as_bytes());
+            value
+        };
+        let mut namespace = [0u8; 60];
+        namespace[0] = b'1';
+        
+        cache.handle_in(create_test_buffer(b'I', &[1; 63], &[1; 60], &[0; 4])).unwrap();
+        cache.handle_in(create_test_buffer(b'U', &[0; 63], &namespace, &[0; 4])).unwrap();
+        cache.handle_in(create_test_buffer(b'I', &[1; 63], &[1; 60], &[0; 4])).unwrap();
+        cache.handle_in(create_test_buffer(b'I', &[2; 63], &[1; 60], &[0; 4])).unwrap();
+        
+        // Only the selected namespace, saved before the reply
+        cache.handle_in(create_test_buffer(b'W', &[0; 63], &[0; 60], &[0; 4])).unwrap();
+        assert_eq!(cache.key_count().unwrap(), 1);
+        assert_eq!(cache.dirty(), 0);
+        let saved = crate::snapshot::read(&dir.path().join(crate::snapshot::SNAPSHOT_FILE), &cache.codec()).unwrap();
+        assert_eq!(saved.len(), crate::snapshot::RECORD_SIZE);
+        
+        // Unknown words are rejected
+        cache.handle_in(create_test_buffer(b'W', &[0; 63], &words("everything"), &[0; 4])).unwrap();
+        assert_eq!(cache.key_count().unwrap(), 1);
+        
+        cache.handle_in(create_test_buffer(b'W', &[0; 63], &words("all async"), &[0; 4])).unwrap();
+        assert_eq!(cache.key_count().unwrap(), 0);
+        cache.thread_pool.join();
+        let saved = crate::snapshot::read(&dir.path().join(crate::snapshot::SNAPSHOT_FILE), &cache.codec()).unwrap();
+        assert!(saved.is_empty());
+    }
+    
+    #[test]
+    fn test_stats_counters() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let mut cache = setup_cache_in(&dir);
+        
+        cache.handle_in(create_test_buffer(b'I', &[1; 63], &[1; 60], &[0; 4])).unwrap();
+        cache.handle_in(create_test_buffer(b'G', &[1; 63], &[0; 60], &[0; 4])).unwrap();
+        cache.handle_in(create_test_buffer(b'V', &[2; 63], &[0; 60], &[0; 4])).unwrap();
+        cache.handle_in(create_test_buffer(b'T', &[0; 63], &[0; 60], &[0; 4])).unwrap();
+        
+        let info = cache.info().unwrap();
+        assert_eq!(info.keys, 1);
+        assert_eq!((info.hits, info.misses), (1, 1));
+        assert_eq!(info.memory_bytes, crate::stats::ENTRY_BYTES);
+        assert_eq!(info.ops, vec![(b'G', 1), (b'I', 1), (b'T', 1), (b'V', 1)]);
+        assert_eq!(info.last_save, 0);
+        
+        // Keys, hits and misses are also kept per namespace
+        cache.select(1).unwrap();
+        cache.handle_in(create_test_buffer(b'G', &[1; 63], &[0; 60], &[0; 4])).unwrap();
+        let info = cache.info().unwrap();
+        assert_eq!((info.hits, info.misses), (1, 2));
+        assert_eq!(info.namespaces, vec![
+            crate::stats::NamespaceInfo { namespace: 0, keys: 1, hits: 1, misses: 1 },
+            crate::stats::NamespaceInfo { namespace: 1, keys: 0, hits: 0, misses: 1 },
+        ]);
+        
+        cache.clean_up().unwrap();
+        let info = cache.info().unwrap();
+        assert!(info.last_save > 0 && info.last_save_ok && !info.saving);
+    }
+    
+    #[test]
+    fn test_replies_are_the_only_output() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let config = crate::config::Config {
+            data_dir: dir.path().to_path_buf(),
+            log_sinks: "file,stderr".parse().unwrap(),
+            ..crate::config::Config::default()
+        };
+        // Every log record is produced, none of them may reach the replies
+        let log_path = dir.path().join("log.log");
+        let mut cache = Cache::with_config(log_path.to_str().unwrap(), crate::LogLevel::Trace, config);
+        let (writer, captured) = crate::response::ResponseWriter::capture();
+        cache.set_response_writer(writer);
+        
+        cache.handle_in(create_test_buffer(b'I', &[1; 63], &[7; 60], &[0; 4])).unwrap();
+        let stored = cache.storage.get(&[1; 63]).unwrap().unwrap();
+        let (value, version) = (*crate::storage::wire(&stored), crate::storage::version(&stored));
+        cache.handle_in(create_test_buffer(b'G', &[1; 63], &[0; 60], &[0; 4])).unwrap();
+        cache.handle_in(create_test_buffer(b'G', &[2; 63], &[0; 60], &[0; 4])).unwrap();
+        cache.handle_in(create_test_buffer(b'V', &[1; 63], &[0; 60], &[0; 4])).unwrap();
+        cache.handle_in(create_test_buffer(b'R', &[1; 63], &[0; 60], &[0; 4])).unwrap();
+        cache.handle_in(create_test_buffer(b'G', &[0; 63], &[0; 60], &[0; 4])).unwrap();
+        cache.handle_batch(&[
+            create_test_buffer(b'I', &[3; 63], &[7; 60], &[0; 4]),
+            create_test_buffer(b'R', &[3; 63], &[0; 60], &[0; 4]),
+        ]).unwrap();
+        cache.clean_up().unwrap();
+        
+        let expected = [
+            &b"I\n"[..],
+            &value,
+            b"\n",
+            b"G\n",
+            &value,
+            format!("{}\n", version).as_bytes(),
+            b"R\n",
+            b"E\n",
+            b"I\nR\n",
+        ].concat();
+        assert_eq!(captured.take(), expected);
+        
+        cache.flush_log().unwrap();
+        assert!(std::fs::read_to_string(&log_path).unwrap().contains("\"msg\":\"insert\""));
+    }
+    
+    #[test]
+    fn test_slowlog_commands() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let config = crate::config::Config {
+            data_dir: dir.path().to_path_buf(),
+            slowlog_threshold: std::time::Duration::from_millis(5),
+            slowlog_max_len: 4,
+            ..crate::config::Config::default()
+        };
+        let mut cache = Cache::with_config("/tmp/cache_test.log", crate::LogLevel::Debug, config);
+        let argument = |text: &str| {
+            let mut value = [0u8; 60];
+            value[..text.len()].copy_from_slice(text.as_bytes());
+            value
+        };
+        
+        cache.handle_in(create_test_buffer(b'I', &[1; 63], &[1; 60], &[0; 4])).unwrap();
+        assert_eq!(cache.slowlog().entries().count(), 0);
+        
+        // A batch that waited on the lock is charged to its first command only
+        cache.begin_batch(3, std::time::Duration::from_millis(8));
+        cache.handle_in(create_test_buffer(b'G', &[1; 63], &[0; 60], &[0; 4])).unwrap();
+        cache.handle_in(create_test_buffer(b'G', &[1; 63], &[0; 60], &[0; 4])).unwrap();
+        let entries: Vec<_> = cache.slowlog().entries().cloned().collect();
+        assert_eq!(entries.len(), 1);
+        assert_eq!((entries[0].command, entries[0].batch), (b'G', 3));
+        assert_eq!(entries[0].key, vec![1; crate::slowlog::KEY_BYTES]);
+        assert!(entries[0].wait >= std::time::Duration::from_millis(8));
+        
+        cache.handle_in(create_test_buffer(b'O', &[0; 63], &argument("1"), &[0; 4])).unwrap();
+        cache.handle_in(create_test_buffer(b'O', &[0; 63], &argument("reset"), &[0; 4])).unwrap();
+        assert_eq!(cache.slowlog().entries().count(), 0);
+    }
+    
+    #[test]
+    fn test_slowlog_multi_and_error_replies() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let config = crate::config::Config {
+            data_dir: dir.path().to_path_buf(),
+            slowlog_threshold: std::time::Duration::from_millis(5),
+            slowlog_max_len: 8,
+            ..crate::config::Config::default()
+        };
+        let mut cache = Cache::with_config("/tmp/cache_test.log", crate::LogLevel::Debug, config);
+        let mut count = [0u8; 60];
+        count[0] = b'2';
+        let wait = std::time::Duration::from_millis(8);
+        
+        // A multi-key request is one entry, with the lock waits of every batch it spans
+        cache.begin_batch(2, wait);
+        cache.handle_in(create_test_buffer(b'M', &[0; 63], &count, &[0; 4])).unwrap();
+        cache.handle_in(create_test_buffer(b'G', &[3; 63], &[0; 60], &[0; 4])).unwrap();
+        cache.begin_batch(1, wait);
+        cache.handle_in(create_test_buffer(b'G', &[4; 63], &[0; 60], &[0; 4])).unwrap();
+        let entries: Vec<_> = cache.slowlog().entries().cloned().collect();
+        assert_eq!(entries.len(), 1);
+        assert_eq!((entries[0].command, entries[0].batch), (b'M', 1));
+        assert_eq!(entries[0].key, vec![3; crate::slowlog::KEY_BYTES]);
+        assert!(entries[0].wait >= wait * 2);
+        
+        // The wait was used up by the request, so a fast command after it is not logged
+        cache.handle_in(create_test_buffer(b'G', &[3; 63], &[0; 60], &[0; 4])).unwrap();
+        assert_eq!(cache.slowlog().entries().count(), 1);
+        
+        // An empty key is rejected with E and still timed
+        cache.begin_batch(1, wait);
+        cache.handle_in(create_test_buffer(b'G', &[0; 63], &[0; 60], &[0; 4])).unwrap();
+        let newest = cache.slowlog().entries().next().unwrap().clone();
+        assert_eq!((newest.command, newest.key.is_empty()), (b'G', true));
+    }
+    
+    fn write_large_snapshot(dir: &tempfile::TempDir, count: usize, codec: &crate::snapshot::Codec) {
+        let now = Utc::now().timestamp();
+        let mut view = crate::snapshot::SnapshotView::with_capacity(count);
+        for i in 0..count {
+            let mut key = [0u8; 63];
+            let name = format!("session:{}", i);
+            key[..name.len()].copy_from_slice(name.as_bytes());
+            let mut value = [0u8; 64];
+            value[..8].copy_from_slice(&(i as u64).to_be_bytes());
+            // Every tenth entry expired an hour ago
+            let (created_at, expiry) = if i % 10 == 0 { (now - 7200, 60u16) } else { (now, 3600u16) };
+            value[56..62].copy_from_slice(&created_at.to_be_bytes()[2..8]);
+            value[62..64].copy_from_slice(&expiry.to_be_bytes());
+            view.insert(key, crate::storage::stored(&value, 1));
+        }
+        let status = crate::snapshot::SaveStatus::new();
+        let logger = crate::logger::Logger::new("/tmp/cache_test.log", LogLevel::Info, Default::default());
+        crate::snapshot::save(dir.path(), status.next_seq(), &[view], &status, codec, &crate::generations::Retention::default(), &logger).unwrap();
+    }
+    
+    #[test]
+    fn test_streaming_load_across_batches() {
+        let count = crate::snapshot::LOAD_BATCH_RECORDS * 2 + 25;
+        for compression in [crate::snapshot::Compression::None, crate::snapshot::Compression::Lz4] {
+            let dir = tempfile::TempDir::new().unwrap();
+            let codec = crate::snapshot::Codec { compression, keyring: None };
+            write_large_snapshot(&dir, count, &codec);
+            
+            let mut cache = setup_cache_in(&dir);
+            cache.load().unwrap();
+            
+            let expected = count - count.div_ceil(10);
+            assert_eq!(cache.storage.len().unwrap(), expected, "{}", compression);
+            
+            let mut key = [0u8; 63];
+            key[..12].copy_from_slice(b"session:1234");
+            let value = cache.storage.get(&key).unwrap().unwrap();
+            assert_eq!(&value[..8], &1234u64.to_be_bytes());
+            assert!(crate::utils::create_cache_entry(&value).expires_at.is_some());
+        }
+    }
+    
+    #[test]
+    fn test_corrupt_snapshot_does_not_load() {
+        let dir = tempfile::TempDir::new().unwrap();
+        write_large_snapshot(&dir, 100, &crate::snapshot::Codec::default());
+        
+        let path = dir.path().join(crate::snapshot::SNAPSHOT_FILE);
+        let mut buffer = std::fs::read(&path).unwrap();
+        let last = buffer.len() - 1;
+        buffer[last] ^= 0xff;
+        std::fs::write(&path, buffer).unwrap();
+        
+        let mut cache = setup_cache_in(&dir);
+        assert!(cache.load().is_err());
+        assert!(cache.storage.is_empty().unwrap());
+    }
+    
+    // Startup time for a large snapshot: cargo test --release bench_snapshot_load -- --ignored --nocapture
+    #[test]
+    #[ignore]
+    fn bench_snapshot_load() {
+        let count = 2_000_000;
+        for compression in [crate::snapshot::Compression::None, crate::snapshot::Compression::Lz4] {
+            let dir = tempfile::TempDir::new().unwrap();
+            let codec = crate::snapshot::Codec { compression, keyring: None };
+            write_large_snapshot(&dir, count, &codec);
+            
+            let config = crate::config::Config {
+                data_dir: dir.path().to_path_buf(),
+                snapshot_compression: compression,
+                ..crate::config::Config::default()
+            };
+            let mut cache = Cache::with_config("/tmp/cache_test.log", crate::LogLevel::Info, config);
+            let start = std::time::Instant::now();
+            cache.load().unwrap();
+            println!("{:<6} {} records loaded in {} ms", compression, cache.storage.len().unwrap(), start.elapsed().as_millis());
+        }
     }
 }
-
@@ -0,0 +1,167 @@
+use std::path::PathBuf;
+use chrono::{DateTime, Utc};
+use crate::{export::{Encoding, Format}, generations::RestoreTarget};
+
+pub const USAGE: &str = "\
+Usage: cacherebbok [OPTIONS]
+       cacherebbok export [--format FORMAT] [--encoding ENCODING] [FILE]
+       cacherebbok import [--format FORMAT] [FILE]
+
+Options:
+  --list-generations        Print the snapshot generations in the data directory and exit
+  --restore <NAME|latest>   Boot from a snapshot generation instead of the current snapshot
+  --restore-before <TIME>   Boot from the newest generation taken at or before TIME
+                            (RFC 3339, e.g. 2024-06-01T12:00:00Z, or epoch seconds)
+  -h, --help                Print this help and exit
+
+Commands:
+  export                    Write the saved snapshot to FILE, or stdout when omitted
+  import                    Merge records from FILE, or stdin when omitted, into the saved snapshot
+
+Export and import options:
+  --format <FORMAT>         json, ndjson or csv; defaults to the FILE extension, then json
+  --encoding <ENCODING>     utf8, hex or base64 for keys and values (export only, default utf8).
+                            utf8 falls back to hex for records that are not valid text";
+
+// Offline operations on the data directory, run instead of the cache
+#[derive(Debug, PartialEq)]
+pub enum Command {
+    Export { format: Format, encoding: Encoding, path: Option<PathBuf> },
+    Import { format: Format, path: Option<PathBuf> },
+}
+
+#[derive(Debug, Default, PartialEq)]
+pub struct Args {
+    pub list_generations: bool,
+    pub restore: Option<RestoreTarget>,
+    pub help: bool,
+    pub command: Option<Command>,
+}
+
+// Parse command line arguments, excluding the program name
+pub fn parse<I>(args: I) -> Result<Args, String>
+where
+    I: IntoIterator<Item = String>,
+{
+    let mut parsed = Args::default();
+    let mut args = args.into_iter().peekable();
+
+    if let Some(name) = args.next_if(|arg| arg == "export" || arg == "import") {
+        parsed.command = Some(parse_command(&name, args)?);
+        return Ok(parsed);
+    }
+
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--list-generations" => parsed.list_generations = true,
+            "--restore" => {
+                let name = args.next().ok_or("--restore needs a generation name or 'latest'")?;
+                parsed.restore = Some(if name == "latest" {
+                    RestoreTarget::Latest
+                } else {
+                    RestoreTarget::Named(name)
+                });
+            }
+            "--restore-before" => {
+                let time = args.next().ok_or("--restore-before needs a timestamp")?;
+                parsed.restore = Some(RestoreTarget::Before(parse_time(&time)?));
+            }
+            "-h" | "--help" => parsed.help = true,
+            other => return Err(format!("unknown argument '{}'", other)),
+        }
+    }
+
+    Ok(parsed)
+}
+
+fn parse_command<I>(name: &str, mut args: I) -> Result<Command, String>
+where
+    I: Iterator<Item = String>,
+{
+    let mut format = None;
+    let mut encoding = None;
+    let mut path = None;
+
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--format" => format = Some(args.next().ok_or("--format needs json, ndjson or csv")?.parse::<Format>()?),
+            "--encoding" if name == "export" => {
+                encoding = Some(args.next().ok_or("--encoding needs utf8, hex or base64")?.parse::<Encoding>()?);
+            }
+            "-" if path.is_none() => path = Some(None),
+            other if !other.starts_with('-') && path.is_none() => path = Some(Some(PathBuf::from(other))),
+            other => return Err(format!("unknown {} argument '{}'", name, other)),
+        }
+    }
+
+    let path = path.flatten();
+    let format = format.unwrap_or_else(|| path.as_deref().map(Format::from_path).unwrap_or_default());
+
+    Ok(match name {
+        "export" => Command::Export { format, encoding: encoding.unwrap_or_default(), path },
+        _ => Command::Import { format, path },
+    })
+}
+
+fn parse_time(input: &str) -> Result<DateTime<Utc>, String> {
+    if let Ok(seconds) = input.parse::<i64>() {
+        return DateTime::<Utc>::from_timestamp(seconds, 0).ok_or_else(|| format!("timestamp out of range: {}", input));
+    }
+
+    DateTime::parse_from_rfc3339(input)
+        .map(|time| time.with_timezone(&Utc))
+        .map_err(|_| format!("invalid timestamp '{}', expected RFC 3339 or epoch seconds", input))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse_args(args: &[&str]) -> Result<Args, String> {
+        parse(args.iter().map(|arg| arg.to_string()))
+    }
+
+    #[test]
+    fn test_no_arguments() {
+        assert_eq!(parse_args(&[]).unwrap(), Args::default());
+    }
+
+    #[test]
+    fn test_restore_targets() {
+        assert_eq!(parse_args(&["--restore", "latest"]).unwrap().restore, Some(RestoreTarget::Latest));
+        assert_eq!(
+            parse_args(&["--restore", "cache-20240601T120000.000Z.snap"]).unwrap().restore,
+            Some(RestoreTarget::Named("cache-20240601T120000.000Z.snap".to_string()))
+        );
+
+        let expected = DateTime::<Utc>::from_timestamp(1_717_243_200, 0).unwrap();
+        assert_eq!(parse_args(&["--restore-before", "2024-06-01T12:00:00Z"]).unwrap().restore, Some(RestoreTarget::Before(expected)));
+        assert_eq!(parse_args(&["--restore-before", "1717243200"]).unwrap().restore, Some(RestoreTarget::Before(expected)));
+    }
+
+    #[test]
+    fn test_invalid_arguments() {
+        assert!(parse_args(&["--restore"]).is_err());
+        assert!(parse_args(&["--restore-before", "yesterday"]).is_err());
+        assert!(parse_args(&["--bogus"]).is_err());
+        assert!(parse_args(&["import", "--encoding", "hex"]).is_err());
+        assert!(parse_args(&["export", "--format", "xml"]).is_err());
+        assert!(parse_args(&["export", "a.json", "b.json"]).is_err());
+    }
+
+    #[test]
+    fn test_export_and_import_commands() {
+        assert_eq!(
+            parse_args(&["export"]).unwrap().command,
+            Some(Command::Export { format: Format::Json, encoding: Encoding::Utf8, path: None })
+        );
+        assert_eq!(
+            parse_args(&["export", "--encoding", "base64", "dump.csv"]).unwrap().command,
+            Some(Command::Export { format: Format::Csv, encoding: Encoding::Base64, path: Some(PathBuf::from("dump.csv")) })
+        );
+        assert_eq!(
+            parse_args(&["import", "--format", "ndjson", "-"]).unwrap().command,
+            Some(Command::Import { format: Format::Ndjson, path: None })
+        );
+    }
+}
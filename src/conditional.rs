@@ -0,0 +1,118 @@
+use chrono::{DateTime, Utc};
+use crate::{storage::{self, Value}, utils};
+
+/*
+    Conditional inserts, answered with I when written and F when the condition failed:
+    - N: only if the key is absent
+    - X: only if the key is present
+    - C: only if the key's version matches. The timestamp bytes of the frame carry the expected version
+      (48 bits, big endian) instead of a timestamp, and 0 expects the key to be absent.
+    Written values take the server's clock as their timestamp, so the TTL counts from the write.
+    Expired keys count as absent. A successful write bumps the version by one; V reads it.
+ */
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Condition {
+    Absent,
+    Present,
+    Version(u64),
+}
+
+impl Condition {
+    // The condition of a conditional insert frame, None for other commands
+    pub fn from_frame(command: u8, frame: &[u8; 64]) -> Option<Condition> {
+        match command {
+            b'N' => Some(Condition::Absent),
+            b'X' => Some(Condition::Present),
+            b'C' => {
+                let mut version = [0u8; 8];
+                version[2..8].copy_from_slice(&frame[56..62]);
+                Some(Condition::Version(u64::from_be_bytes(version)))
+            }
+            _ => None,
+        }
+    }
+
+    pub fn holds(&self, current: Option<&Value>, now: DateTime<Utc>) -> bool {
+        let live = live(current, now);
+        match self {
+            Condition::Absent => live.is_none(),
+            Condition::Present => live.is_some(),
+            Condition::Version(expected) => live.map_or(0, storage::version) == *expected,
+        }
+    }
+
+    // The wire value to store for a frame meeting this condition
+    pub fn value(&self, frame: &[u8; 64], now: DateTime<Utc>) -> [u8; 64] {
+        let mut value = *frame;
+        value[56..62].copy_from_slice(&now.timestamp().to_be_bytes()[2..8]);
+        value
+    }
+}
+
+// The current value unless it has expired
+pub fn live(current: Option<&Value>, now: DateTime<Utc>) -> Option<&Value> {
+    current.filter(|value| utils::create_cache_entry(value).expires_at.is_none_or(|expires| expires > now))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn value(timestamp: i64, ttl: u16, version: u64) -> Value {
+        let mut wire = [0u8; 64];
+        wire[56..62].copy_from_slice(&timestamp.to_be_bytes()[2..8]);
+        wire[62..64].copy_from_slice(&ttl.to_be_bytes());
+        storage::stored(&wire, version)
+    }
+
+    #[test]
+    fn test_conditions() {
+        let now = Utc::now();
+        let current = value(now.timestamp(), 0, 5);
+        let expired = value(now.timestamp() - 120, 60, 9);
+
+        assert!(Condition::Absent.holds(None, now));
+        assert!(Condition::Absent.holds(Some(&expired), now));
+        assert!(!Condition::Absent.holds(Some(&current), now));
+        assert!(Condition::Present.holds(Some(&current), now));
+        assert!(!Condition::Present.holds(Some(&expired), now));
+
+        assert!(Condition::Version(5).holds(Some(&current), now));
+        assert!(!Condition::Version(4).holds(Some(&current), now));
+        assert!(Condition::Version(0).holds(Some(&expired), now));
+    }
+
+    #[test]
+    fn test_version_frame() {
+        let mut frame = [0u8; 64];
+        frame[..2].copy_from_slice(b"ok");
+        frame[56..62].copy_from_slice(&42u64.to_be_bytes()[2..8]);
+        frame[62..64].copy_from_slice(&30u16.to_be_bytes());
+
+        let condition = Condition::from_frame(b'C', &frame).unwrap();
+        assert_eq!(condition, Condition::Version(42));
+        assert_eq!(Condition::from_frame(b'I', &frame), None);
+
+        // The expected version is replaced by the server's clock
+        let now = Utc::now();
+        let stored = condition.value(&frame, now);
+        assert_eq!(&stored[..2], b"ok");
+        assert_eq!(utils::parse_cache_metadata(&stored).0.timestamp(), now.timestamp());
+        assert_eq!(&stored[62..64], &30u16.to_be_bytes());
+    }
+
+    #[test]
+    fn test_absent_and_present_use_server_time() {
+        // A client clock an hour behind would have the 60 second TTL run out before the write
+        let now = Utc::now();
+        let mut frame = [0u8; 64];
+        frame[56..62].copy_from_slice(&(now.timestamp() - 3600).to_be_bytes()[2..8]);
+        frame[62..64].copy_from_slice(&60u16.to_be_bytes());
+
+        for command in [b'N', b'X'] {
+            let stored = storage::stored(&Condition::from_frame(command, &frame).unwrap().value(&frame, now), 1);
+            assert_eq!(utils::parse_cache_metadata(storage::wire(&stored)).0.timestamp(), now.timestamp());
+            assert!(live(Some(&stored), now).is_some());
+        }
+    }
+}
@@ -0,0 +1,357 @@
+use std::{env, net::SocketAddr, path::{Path, PathBuf}, str::FromStr, time::Duration};
+use crate::{crypto::Keyring, generations::Retention, logger::{LogLevel, Rotation, Sinks}, snapshot::Compression, storage::StorageKind};
+
+// How long a graceful shutdown may take before the process is forced down
+const DEFAULT_SHUTDOWN_TIMEOUT_SECS: u64 = 10;
+// Save within a minute of any change, matching the old fixed persistence interval
+const DEFAULT_SAVE_POLICY: &str = "60:1";
+// Entries kept in memory by the tiered backend before spilling to disk
+const DEFAULT_HOT_ENTRIES: usize = 1_000_000;
+// Numbered key spaces selectable with U; snapshots store the namespace in one byte
+const DEFAULT_NAMESPACES: usize = 16;
+pub const MAX_NAMESPACES: usize = 256;
+// Commands taking at least this long, lock wait included, go to the slow log
+const DEFAULT_SLOWLOG_THRESHOLD_US: u64 = 10_000;
+const DEFAULT_SLOWLOG_MAX_LEN: usize = 128;
+
+// Save once `after` has elapsed since the last save and at least `min_changes` keys changed
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct SavePolicy {
+    pub after: Duration,
+    pub min_changes: usize,
+}
+
+impl SavePolicy {
+    pub fn is_due(&self, since_last_save: Duration, changes: usize) -> bool {
+        since_last_save >= self.after && changes >= self.min_changes
+    }
+
+    // Parse "seconds:changes" rules separated by commas, e.g. "900:1,300:10,60:10000". "off" disables saving.
+    pub fn parse_list(input: &str) -> Option<Vec<SavePolicy>> {
+        let input = input.trim();
+        if input.is_empty() || input.eq_ignore_ascii_case("off") {
+            return Some(Vec::new());
+        }
+
+        input
+            .split(',')
+            .map(|rule| {
+                let (secs, changes) = rule.trim().split_once(':')?;
+                Some(SavePolicy {
+                    after: Duration::from_secs(secs.trim().parse().ok()?),
+                    min_changes: changes.trim().parse().ok()?,
+                })
+            })
+            .collect()
+    }
+}
+
+// Runtime configuration, read from CACHE_* environment variables
+#[derive(Clone, Debug)]
+pub struct Config {
+    // Directory holding persisted cache data
+    pub data_dir: PathBuf,
+    // Deadline for draining input, saving and joining threads on shutdown
+    pub shutdown_timeout: Duration,
+    // Shut down when stdin is closed instead of waiting for a signal or a Q command
+    pub shutdown_on_eof: bool,
+    // Snapshot rules; a save runs when any rule is satisfied
+    pub save_policies: Vec<SavePolicy>,
+    // Codec used when writing snapshots; snapshots record their own codec for loading
+    pub snapshot_compression: Compression,
+    // Encrypts persisted data when set; the first key encrypts, older keys stay readable for rotation
+    pub encryption: Option<Keyring>,
+    // Timestamped snapshot copies kept for restores
+    pub snapshot_retention: Retention,
+    // Where the key space lives: in memory, in a memory-mapped file or in an on-disk B-tree
+    pub storage: StorageKind,
+    // Tiered backend: entry limit of the memory tier and the backend holding the overflow
+    pub hot_entries: usize,
+    pub cold_storage: StorageKind,
+    // Number of namespaces, each an independent key space
+    pub namespaces: usize,
+    // Address serving Prometheus metrics at /metrics, off when unset
+    pub metrics_addr: Option<SocketAddr>,
+    // Slow log threshold and the number of entries it keeps; 0 entries turns it off
+    pub slowlog_threshold: Duration,
+    pub slowlog_max_len: usize,
+    // Lowest level that gets logged and where log records go
+    pub log_level: LogLevel,
+    pub log_sinks: Sinks,
+    // Size and age limits of the log file and how many rotated files are kept
+    pub log_rotation: Rotation,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            data_dir: std::env::current_dir().unwrap_or_else(|_| PathBuf::from(".")).join("data"),
+            shutdown_timeout: Duration::from_secs(DEFAULT_SHUTDOWN_TIMEOUT_SECS),
+            shutdown_on_eof: false,
+            save_policies: SavePolicy::parse_list(DEFAULT_SAVE_POLICY).unwrap_or_default(),
+            snapshot_compression: Compression::None,
+            encryption: None,
+            snapshot_retention: Retention::default(),
+            storage: StorageKind::default(),
+            hot_entries: DEFAULT_HOT_ENTRIES,
+            cold_storage: StorageKind::Mmap,
+            namespaces: DEFAULT_NAMESPACES,
+            metrics_addr: None,
+            slowlog_threshold: Duration::from_micros(DEFAULT_SLOWLOG_THRESHOLD_US),
+            slowlog_max_len: DEFAULT_SLOWLOG_MAX_LEN,
+            log_level: LogLevel::default(),
+            log_sinks: Sinks::default(),
+            log_rotation: Rotation::default(),
+        }
+    }
+}
+
+impl Config {
+    pub fn from_env() -> Result<Self, String> {
+        Self::from_vars(|name| env::var(name).ok())
+    }
+
+    // Build a config from any variable lookup, falling back to defaults for missing or invalid values.
+    // Encryption settings are the exception: a bad key is an error rather than silently storing plaintext.
+    pub fn from_vars<F>(lookup: F) -> Result<Self, String>
+    where
+        F: Fn(&str) -> Option<String>,
+    {
+        let mut config = Config::default();
+
+        if let Some(dir) = lookup("CACHE_DATA_DIR") {
+            config.data_dir = PathBuf::from(dir);
+        }
+
+        if let Some(secs) = parse_var::<u64, _>(&lookup, "CACHE_SHUTDOWN_TIMEOUT_SECS") {
+            config.shutdown_timeout = Duration::from_secs(secs);
+        }
+
+        if let Some(on_eof) = parse_var::<bool, _>(&lookup, "CACHE_SHUTDOWN_ON_EOF") {
+            config.shutdown_on_eof = on_eof;
+        }
+
+        if let Some(policies) = lookup("CACHE_SAVE_POLICY").and_then(|value| SavePolicy::parse_list(&value)) {
+            config.save_policies = policies;
+        }
+
+        if let Some(compression) = parse_var(&lookup, "CACHE_SNAPSHOT_COMPRESSION") {
+            config.snapshot_compression = compression;
+        }
+
+        if let Some(keep) = parse_var(&lookup, "CACHE_SNAPSHOT_GENERATIONS") {
+            config.snapshot_retention.keep = keep;
+        }
+
+        if let Some(secs) = parse_var::<u64, _>(&lookup, "CACHE_SNAPSHOT_MAX_AGE_SECS") {
+            config.snapshot_retention.max_age = Some(Duration::from_secs(secs));
+        }
+
+        if let Some(storage) = parse_var(&lookup, "CACHE_STORAGE") {
+            config.storage = storage;
+        }
+
+        if let Some(hot_entries) = parse_var(&lookup, "CACHE_HOT_ENTRIES") {
+            config.hot_entries = hot_entries;
+        }
+
+        if let Some(cold_storage) = parse_var(&lookup, "CACHE_COLD_STORAGE") {
+            config.cold_storage = cold_storage;
+        }
+
+        if let Some(namespaces) = parse_var(&lookup, "CACHE_NAMESPACES").filter(|count| (1..=MAX_NAMESPACES).contains(count)) {
+            config.namespaces = namespaces;
+        }
+
+        if let Some(addr) = parse_var(&lookup, "CACHE_METRICS_ADDR") {
+            config.metrics_addr = Some(addr);
+        }
+
+        if let Some(micros) = parse_var::<u64, _>(&lookup, "CACHE_SLOWLOG_THRESHOLD_US") {
+            config.slowlog_threshold = Duration::from_micros(micros);
+        }
+
+        if let Some(max_len) = parse_var(&lookup, "CACHE_SLOWLOG_MAX_LEN") {
+            config.slowlog_max_len = max_len;
+        }
+
+        if let Some(level) = parse_var(&lookup, "CACHE_LOG_LEVEL") {
+            config.log_level = level;
+        }
+
+        if let Some(sinks) = parse_var(&lookup, "CACHE_LOG_SINKS") {
+            config.log_sinks = sinks;
+        }
+
+        if let Some(max_bytes) = parse_var(&lookup, "CACHE_LOG_MAX_BYTES") {
+            config.log_rotation.max_bytes = max_bytes;
+        }
+
+        if let Some(secs) = parse_var::<u64, _>(&lookup, "CACHE_LOG_MAX_AGE_SECS") {
+            config.log_rotation.max_age = Some(Duration::from_secs(secs)).filter(|age| !age.is_zero());
+        }
+
+        if let Some(keep) = parse_var(&lookup, "CACHE_LOG_KEEP") {
+            config.log_rotation.keep = keep;
+        }
+
+        if let Some(compression) = parse_var(&lookup, "CACHE_LOG_COMPRESSION") {
+            config.log_rotation.compression = compression;
+        }
+
+        config.encryption = match (lookup("CACHE_ENCRYPTION_KEY"), lookup("CACHE_ENCRYPTION_KEY_FILE")) {
+            (Some(keys), _) => Some(Keyring::parse(&keys)?),
+            (None, Some(path)) => Some(Keyring::from_file(Path::new(&path))?),
+            (None, None) => None,
+        };
+
+        // Disk backends store keys and values as they are, which would undo encryption at rest
+        if config.encryption.is_some() && matches!(config.storage, StorageKind::Mmap | StorageKind::Btree | StorageKind::Tiered) {
+            return Err(format!("CACHE_STORAGE={} stores entries unencrypted and cannot be used with an encryption key", config.storage));
+        }
+
+        Ok(config)
+    }
+}
+
+fn parse_var<T, F>(lookup: &F, name: &str) -> Option<T>
+where
+    T: FromStr,
+    F: Fn(&str) -> Option<String>,
+{
+    lookup(name).and_then(|value| value.trim().parse().ok())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    fn config_from(vars: &[(&str, &str)]) -> Config {
+        let vars: HashMap<String, String> = vars.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect();
+        Config::from_vars(|name| vars.get(name).cloned()).unwrap()
+    }
+
+    #[test]
+    fn test_defaults() {
+        let config = config_from(&[]);
+        assert_eq!(config.shutdown_timeout, Duration::from_secs(DEFAULT_SHUTDOWN_TIMEOUT_SECS));
+        assert!(!config.shutdown_on_eof);
+        assert!(config.data_dir.ends_with("data"));
+        assert_eq!(config.save_policies, vec![SavePolicy { after: Duration::from_secs(60), min_changes: 1 }]);
+        assert!(!config.snapshot_retention.is_enabled());
+    }
+
+    #[test]
+    fn test_overrides() {
+        let config = config_from(&[
+            ("CACHE_DATA_DIR", "/tmp/cache-data"),
+            ("CACHE_SHUTDOWN_TIMEOUT_SECS", "3"),
+            ("CACHE_SHUTDOWN_ON_EOF", "true"),
+            ("CACHE_SAVE_POLICY", "900:1, 60:10000"),
+            ("CACHE_SNAPSHOT_COMPRESSION", "zstd"),
+            ("CACHE_SNAPSHOT_GENERATIONS", "24"),
+            ("CACHE_SNAPSHOT_MAX_AGE_SECS", "86400"),
+            ("CACHE_STORAGE", "mmap"),
+            ("CACHE_HOT_ENTRIES", "5000"),
+            ("CACHE_COLD_STORAGE", "btree"),
+            ("CACHE_NAMESPACES", "4"),
+            ("CACHE_METRICS_ADDR", "127.0.0.1:9464"),
+            ("CACHE_SLOWLOG_THRESHOLD_US", "500"),
+            ("CACHE_SLOWLOG_MAX_LEN", "0"),
+            ("CACHE_LOG_LEVEL", "trace"),
+            ("CACHE_LOG_SINKS", "stderr"),
+            ("CACHE_LOG_MAX_BYTES", "1048576"),
+            ("CACHE_LOG_MAX_AGE_SECS", "86400"),
+            ("CACHE_LOG_KEEP", "7"),
+            ("CACHE_LOG_COMPRESSION", "zstd"),
+        ]);
+        assert_eq!(config.data_dir, PathBuf::from("/tmp/cache-data"));
+        assert_eq!(config.shutdown_timeout, Duration::from_secs(3));
+        assert!(config.shutdown_on_eof);
+        assert_eq!(config.save_policies, vec![
+            SavePolicy { after: Duration::from_secs(900), min_changes: 1 },
+            SavePolicy { after: Duration::from_secs(60), min_changes: 10000 },
+        ]);
+        assert_eq!(config.snapshot_compression, Compression::Zstd);
+        assert_eq!(config.snapshot_retention, Retention { keep: 24, max_age: Some(Duration::from_secs(86400)) });
+        assert_eq!(config.storage, StorageKind::Mmap);
+        assert_eq!((config.hot_entries, config.cold_storage), (5000, StorageKind::Btree));
+        assert_eq!(config.namespaces, 4);
+        assert_eq!(config.metrics_addr, Some("127.0.0.1:9464".parse().unwrap()));
+        assert_eq!((config.slowlog_threshold, config.slowlog_max_len), (Duration::from_micros(500), 0));
+        assert_eq!((config.log_level, config.log_sinks), (LogLevel::Trace, Sinks { file: false, stderr: true }));
+        assert_eq!(config.log_rotation, Rotation {
+            max_bytes: 1_048_576,
+            max_age: Some(Duration::from_secs(86400)),
+            keep: 7,
+            compression: Compression::Zstd,
+        });
+    }
+
+    #[test]
+    fn test_invalid_value_falls_back() {
+        let config = config_from(&[
+            ("CACHE_SHUTDOWN_TIMEOUT_SECS", "soon"),
+            ("CACHE_SAVE_POLICY", "60"),
+            ("CACHE_STORAGE", "rocksdb"),
+            ("CACHE_NAMESPACES", "1000"),
+            ("CACHE_METRICS_ADDR", "9464"),
+            ("CACHE_LOG_LEVEL", "loud"),
+        ]);
+        assert_eq!(config.shutdown_timeout, Duration::from_secs(DEFAULT_SHUTDOWN_TIMEOUT_SECS));
+        assert_eq!(config.save_policies.len(), 1);
+        assert_eq!(config.storage, StorageKind::Memory);
+        assert_eq!(config.namespaces, DEFAULT_NAMESPACES);
+        assert_eq!(config.metrics_addr, None);
+        assert_eq!(config.log_level, LogLevel::Info);
+    }
+
+    #[test]
+    fn test_save_policy_off() {
+        assert_eq!(SavePolicy::parse_list("off"), Some(Vec::new()));
+    }
+
+    #[test]
+    fn test_save_policy_is_due() {
+        let policy = SavePolicy { after: Duration::from_secs(60), min_changes: 1000 };
+        assert!(!policy.is_due(Duration::from_secs(59), 5000));
+        assert!(!policy.is_due(Duration::from_secs(120), 999));
+        assert!(policy.is_due(Duration::from_secs(60), 1000));
+    }
+
+    #[test]
+    fn test_encryption_key_from_env() {
+        let config = config_from(&[("CACHE_ENCRYPTION_KEY", &"ab".repeat(32))]);
+        assert!(config.encryption.is_some());
+        assert!(config_from(&[]).encryption.is_none());
+    }
+
+    #[test]
+    fn test_encryption_key_from_file() {
+        let file = tempfile::NamedTempFile::new().unwrap();
+        std::fs::write(file.path(), format!("# current\n{}\n{}\n", "ab".repeat(32), "cd".repeat(32))).unwrap();
+        let config = config_from(&[("CACHE_ENCRYPTION_KEY_FILE", file.path().to_str().unwrap())]);
+        assert!(config.encryption.is_some());
+    }
+
+    #[test]
+    fn test_encryption_rejects_disk_backends() {
+        let key = "ab".repeat(32);
+        for storage in ["mmap", "btree", "tiered"] {
+            let result = Config::from_vars(|name| match name {
+                "CACHE_ENCRYPTION_KEY" => Some(key.clone()),
+                "CACHE_STORAGE" => Some(storage.to_string()),
+                _ => None,
+            });
+            assert!(result.is_err(), "{}", storage);
+        }
+        assert!(config_from(&[("CACHE_ENCRYPTION_KEY", &key), ("CACHE_STORAGE", "memory")]).encryption.is_some());
+    }
+
+    #[test]
+    fn test_invalid_encryption_key_is_an_error() {
+        let result = Config::from_vars(|name| (name == "CACHE_ENCRYPTION_KEY").then(|| "short".to_string()));
+        assert!(result.is_err());
+    }
+}
@@ -0,0 +1,122 @@
+use chrono::{DateTime, Utc};
+use crate::{conditional, storage::{self, Value}};
+
+/*
+    Counter commands (+ = increment, - = decrement):
+    - Value bytes: ASCII decimal step, empty means 1
+    - Expiry bytes: used only when the command creates the key, with the server's clock as the timestamp
+    Counters are stored as ASCII decimal padded with zeros, so G returns them as text.
+ */
+const VALUE_SIZE: usize = 56;
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum CounterError {
+    // The stored value or the step is not a decimal integer
+    NotAnInteger,
+    // The result does not fit in an i64
+    Overflow,
+}
+
+// Read a counter from the value bytes of a stored value or frame
+pub fn parse(value: &[u8; 64]) -> Option<i64> {
+    let end = value[..VALUE_SIZE].iter().position(|&b| b == 0).unwrap_or(VALUE_SIZE);
+    if value[end..VALUE_SIZE].iter().any(|&b| b != 0) {
+        return None;
+    }
+    std::str::from_utf8(&value[..end]).ok()?.trim().parse().ok()
+}
+
+// Canonical encoding, keeping the timestamp and expiry bytes of `metadata`
+pub fn encode(counter: i64, metadata: &[u8; 64]) -> [u8; 64] {
+    let mut value = [0u8; 64];
+    let digits = counter.to_string();
+    value[..digits.len()].copy_from_slice(digits.as_bytes());
+    value[VALUE_SIZE..].copy_from_slice(&metadata[VALUE_SIZE..]);
+    value
+}
+
+// Apply an increment (or decrement) frame to the current value, returning the new counter and the
+// value to store. Missing and expired keys count from zero and take their expiry from the frame,
+// counted from `now`.
+pub fn apply(current: Option<&Value>, frame: &[u8; 64], decrement: bool, now: DateTime<Utc>) -> Result<(i64, Value), CounterError> {
+    let step = if frame[..VALUE_SIZE].iter().all(|&b| b == 0) {
+        1
+    } else {
+        parse(frame).ok_or(CounterError::NotAnInteger)?
+    };
+    let delta = if decrement { step.checked_neg().ok_or(CounterError::Overflow)? } else { step };
+
+    let (base, metadata) = match conditional::live(current, now) {
+        Some(value) => (parse(storage::wire(value)).ok_or(CounterError::NotAnInteger)?, *storage::wire(value)),
+        None => {
+            let mut metadata = *frame;
+            metadata[VALUE_SIZE..62].copy_from_slice(&now.timestamp().to_be_bytes()[2..8]);
+            (0, metadata)
+        }
+    };
+
+    let counter = base.checked_add(delta).ok_or(CounterError::Overflow)?;
+    Ok((counter, storage::stored(&encode(counter, &metadata), storage::next_version(current))))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn frame(step: &str, timestamp: i64, ttl: u16) -> [u8; 64] {
+        let mut value = [0u8; 64];
+        value[..step.len()].copy_from_slice(step.as_bytes());
+        value[56..62].copy_from_slice(&timestamp.to_be_bytes()[2..8]);
+        value[62..64].copy_from_slice(&ttl.to_be_bytes());
+        value
+    }
+
+    #[test]
+    fn test_increment_creates_and_keeps_expiry() {
+        let now = Utc::now();
+        let (counter, first) = apply(None, &frame("", now.timestamp(), 60), false, now).unwrap();
+        let value = first;
+        assert_eq!(counter, 1);
+        assert_eq!(&value[..2], b"1\0");
+        assert_eq!(&value[62..64], &60u16.to_be_bytes());
+
+        // A later increment keeps the TTL set on creation
+        let (counter, value) = apply(Some(&value), &frame("41", now.timestamp(), 0), false, now).unwrap();
+        assert_eq!(counter, 42);
+        assert_eq!(parse(storage::wire(&value)), Some(42));
+        assert_eq!(&value[62..64], &60u16.to_be_bytes());
+        assert_eq!(storage::version(&value), storage::version(&first) + 1);
+    }
+
+    #[test]
+    fn test_created_counter_uses_server_time() {
+        // An hour old client timestamp with a 60 second TTL must not create an expired counter
+        let now = Utc::now();
+        let (_, value) = apply(None, &frame("", now.timestamp() - 3600, 60), false, now).unwrap();
+        assert_eq!(crate::utils::parse_cache_metadata(storage::wire(&value)).0.timestamp(), now.timestamp());
+        assert!(conditional::live(Some(&value), now).is_some());
+    }
+
+    #[test]
+    fn test_decrement_and_expired_restart() {
+        let now = Utc::now();
+        let (counter, _) = apply(None, &frame("5", now.timestamp(), 0), true, now).unwrap();
+        assert_eq!(counter, -5);
+
+        let expired = frame("100", now.timestamp() - 120, 60);
+        let (counter, _) = apply(Some(&storage::stored(&expired, 1)), &frame("", now.timestamp(), 0), false, now).unwrap();
+        assert_eq!(counter, 1);
+    }
+
+    #[test]
+    fn test_errors() {
+        let now = Utc::now();
+        let text = frame("hello", now.timestamp(), 0);
+        assert_eq!(apply(Some(&storage::stored(&text, 1)), &frame("", 0, 0), false, now), Err(CounterError::NotAnInteger));
+        assert_eq!(apply(None, &frame("1.5", 0, 0), false, now), Err(CounterError::NotAnInteger));
+
+        let max = encode(i64::MAX, &frame("", now.timestamp(), 0));
+        assert_eq!(apply(Some(&storage::stored(&max, 1)), &frame("", 0, 0), false, now), Err(CounterError::Overflow));
+        assert_eq!(apply(None, &frame(&i64::MIN.to_string(), 0, 0), true, now), Err(CounterError::Overflow));
+    }
+}
@@ -0,0 +1,182 @@
+use std::{fmt, fs, io, path::Path};
+use chacha20poly1305::{aead::{Aead, AeadCore, KeyInit, OsRng, Payload}, ChaCha20Poly1305, Key, Nonce};
+use sha2::{Digest, Sha256};
+
+pub const KEY_SIZE: usize = 32;
+pub const KEY_ID_SIZE: usize = 4;
+const NONCE_SIZE: usize = 12;
+// Key id, nonce and authentication tag added to every sealed payload
+pub const SEAL_OVERHEAD: usize = KEY_ID_SIZE + NONCE_SIZE + 16;
+
+#[derive(Clone)]
+struct EncryptionKey {
+    id: [u8; KEY_ID_SIZE],
+    key: [u8; KEY_SIZE],
+}
+
+impl EncryptionKey {
+    fn new(key: [u8; KEY_SIZE]) -> Self {
+        // Fingerprint lets a reader pick the right key without trial decryption
+        let digest = Sha256::new().chain_update(b"kvopt-key-id").chain_update(key).finalize();
+        let mut id = [0u8; KEY_ID_SIZE];
+        id.copy_from_slice(&digest[..KEY_ID_SIZE]);
+        EncryptionKey { id, key }
+    }
+
+    fn cipher(&self) -> ChaCha20Poly1305 {
+        ChaCha20Poly1305::new(Key::from_slice(&self.key))
+    }
+}
+
+// Encryption keys for persisted data. The first key encrypts; every key can decrypt, which allows rotation.
+#[derive(Clone)]
+pub struct Keyring {
+    keys: Vec<EncryptionKey>,
+}
+
+impl Keyring {
+    // Parse hex encoded 32 byte keys separated by commas or newlines, current key first
+    pub fn parse(input: &str) -> Result<Self, String> {
+        let keys = input
+            .split([',', '\n'])
+            .map(str::trim)
+            .filter(|key| !key.is_empty() && !key.starts_with('#'))
+            .map(|key| decode_hex_key(key).map(EncryptionKey::new))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        if keys.is_empty() {
+            return Err("no encryption key given".to_string());
+        }
+
+        Ok(Keyring { keys })
+    }
+
+    pub fn from_file(path: &Path) -> Result<Self, String> {
+        let contents = fs::read_to_string(path)
+            .map_err(|e| format!("cannot read key file {}: {}", path.display(), e))?;
+        Self::parse(&contents)
+    }
+
+    pub fn primary_id(&self) -> [u8; KEY_ID_SIZE] {
+        self.keys[0].id
+    }
+
+    // Encrypt with the current key: key id, random nonce, then ciphertext and tag
+    pub fn seal(&self, aad: &[u8], plaintext: &[u8]) -> io::Result<Vec<u8>> {
+        let key = &self.keys[0];
+        let nonce = ChaCha20Poly1305::generate_nonce(&mut OsRng);
+        let ciphertext = key
+            .cipher()
+            .encrypt(&nonce, Payload { msg: plaintext, aad })
+            .map_err(|_| io::Error::other("encryption failed"))?;
+
+        let mut sealed = Vec::with_capacity(KEY_ID_SIZE + NONCE_SIZE + ciphertext.len());
+        sealed.extend_from_slice(&key.id);
+        sealed.extend_from_slice(&nonce);
+        sealed.extend_from_slice(&ciphertext);
+        Ok(sealed)
+    }
+
+    // Decrypt with whichever key produced the payload, failing if it was tampered with
+    pub fn open(&self, aad: &[u8], sealed: &[u8]) -> io::Result<Vec<u8>> {
+        if sealed.len() < SEAL_OVERHEAD {
+            return Err(invalid_data("encrypted payload is truncated".to_string()));
+        }
+
+        let (id, rest) = sealed.split_at(KEY_ID_SIZE);
+        let (nonce, ciphertext) = rest.split_at(NONCE_SIZE);
+
+        let key = self
+            .keys
+            .iter()
+            .find(|key| key.id == id)
+            .ok_or_else(|| invalid_data(format!("payload was encrypted with unknown key {}", hex(id))))?;
+
+        key.cipher()
+            .decrypt(Nonce::from_slice(nonce), Payload { msg: ciphertext, aad })
+            .map_err(|_| invalid_data("decryption failed, data is corrupted or was modified".to_string()))
+    }
+}
+
+// Never print key material
+impl fmt::Debug for Keyring {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let ids: Vec<String> = self.keys.iter().map(|key| hex(&key.id)).collect();
+        f.debug_struct("Keyring").field("key_ids", &ids).finish()
+    }
+}
+
+fn decode_hex_key(input: &str) -> Result<[u8; KEY_SIZE], String> {
+    if input.len() != KEY_SIZE * 2 {
+        return Err(format!("encryption keys must be {} hex characters", KEY_SIZE * 2));
+    }
+
+    let mut key = [0u8; KEY_SIZE];
+    for (i, byte) in key.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(&input[i * 2..i * 2 + 2], 16)
+            .map_err(|_| "encryption keys must be hex encoded".to_string())?;
+    }
+    Ok(key)
+}
+
+pub fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn invalid_data(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const KEY_A: &str = "000102030405060708090a0b0c0d0e0f101112131415161718191a1b1c1d1e1f";
+    const KEY_B: &str = "ffeeddccbbaa99887766554433221100ffeeddccbbaa99887766554433221100";
+
+    #[test]
+    fn test_seal_open_round_trip() {
+        let keyring = Keyring::parse(KEY_A).unwrap();
+        let sealed = keyring.seal(b"header", b"secret session").unwrap();
+
+        assert!(!sealed.windows(6).any(|w| w == b"secret"));
+        assert_eq!(keyring.open(b"header", &sealed).unwrap(), b"secret session");
+    }
+
+    #[test]
+    fn test_tampering_is_detected() {
+        let keyring = Keyring::parse(KEY_A).unwrap();
+        let mut sealed = keyring.seal(b"header", b"secret session").unwrap();
+
+        assert!(keyring.open(b"other", &sealed).is_err());
+        let last = sealed.len() - 1;
+        sealed[last] ^= 1;
+        assert!(keyring.open(b"header", &sealed).is_err());
+    }
+
+    #[test]
+    fn test_rotation_reads_old_and_writes_new() {
+        let old = Keyring::parse(KEY_A).unwrap();
+        let sealed_with_old = old.seal(b"", b"data").unwrap();
+
+        let rotated = Keyring::parse(&format!("{}\n{}", KEY_B, KEY_A)).unwrap();
+        assert_eq!(rotated.open(b"", &sealed_with_old).unwrap(), b"data");
+
+        let sealed_with_new = rotated.seal(b"", b"data").unwrap();
+        assert_eq!(&sealed_with_new[..KEY_ID_SIZE], &rotated.primary_id());
+        assert!(old.open(b"", &sealed_with_new).is_err());
+    }
+
+    #[test]
+    fn test_invalid_keys_are_rejected() {
+        assert!(Keyring::parse("").is_err());
+        assert!(Keyring::parse("abcd").is_err());
+        assert!(Keyring::parse(&"zz".repeat(KEY_SIZE)).is_err());
+    }
+
+    #[test]
+    fn test_debug_hides_key_material() {
+        let keyring = Keyring::parse(KEY_A).unwrap();
+        assert!(!format!("{:?}", keyring).contains(KEY_A));
+    }
+}
@@ -0,0 +1,425 @@
+use std::{fmt, io::{self, BufRead, Read, Write}, ops::ControlFlow, path::Path, str::FromStr};
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use crate::{crypto::hex, storage, utils, Cache, CacheEntry};
+
+const KEY_SIZE: usize = 63;
+const VALUE_SIZE: usize = 56;
+
+// File formats for exported cache contents
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Format {
+    #[default]
+    Json,
+    Ndjson,
+    Csv,
+}
+
+impl Format {
+    // Pick the format from a file extension, JSON when unknown
+    pub fn from_path(path: &Path) -> Format {
+        path.extension()
+            .and_then(|ext| ext.to_str())
+            .and_then(|ext| ext.parse().ok())
+            .unwrap_or_default()
+    }
+}
+
+impl FromStr for Format {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "json" => Ok(Format::Json),
+            "ndjson" | "jsonl" => Ok(Format::Ndjson),
+            "csv" => Ok(Format::Csv),
+            other => Err(format!("unknown format '{}', expected json, ndjson or csv", other)),
+        }
+    }
+}
+
+// How keys and values are written. UTF-8 falls back to hex for records that are not valid text.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Encoding {
+    #[default]
+    Utf8,
+    Hex,
+    Base64,
+}
+
+impl Encoding {
+    fn encode(self, bytes: &[u8]) -> String {
+        match self {
+            Encoding::Utf8 => String::from_utf8_lossy(bytes).into_owned(),
+            Encoding::Hex => hex(bytes),
+            Encoding::Base64 => BASE64.encode(bytes),
+        }
+    }
+
+    fn decode(self, text: &str) -> Result<Vec<u8>, String> {
+        match self {
+            Encoding::Utf8 => Ok(text.as_bytes().to_vec()),
+            Encoding::Hex => decode_hex(text),
+            Encoding::Base64 => BASE64.decode(text).map_err(|e| format!("invalid base64: {}", e)),
+        }
+    }
+}
+
+impl FromStr for Encoding {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "utf8" | "utf-8" | "text" => Ok(Encoding::Utf8),
+            "hex" => Ok(Encoding::Hex),
+            "base64" => Ok(Encoding::Base64),
+            other => Err(format!("unknown encoding '{}', expected utf8, hex or base64", other)),
+        }
+    }
+}
+
+impl fmt::Display for Encoding {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Encoding::Utf8 => write!(f, "utf8"),
+            Encoding::Hex => write!(f, "hex"),
+            Encoding::Base64 => write!(f, "base64"),
+        }
+    }
+}
+
+// One exported cache entry. Keys and values have their zero padding stripped; it is restored on import.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Record {
+    pub key: String,
+    pub value: String,
+    pub encoding: Encoding,
+    pub created_at: DateTime<Utc>,
+    pub expires_at: Option<DateTime<Utc>>,
+    // Dumps from before namespaces belong to namespace 0
+    #[serde(default)]
+    pub namespace: u8,
+}
+
+impl Record {
+    pub fn new(key: &[u8; KEY_SIZE], entry: &CacheEntry, encoding: Encoding) -> Self {
+        let key = trim_padding(key);
+        let value = trim_padding(&entry.value);
+
+        // Keep each record readable as text only when both fields are clean UTF-8
+        let encoding = match encoding {
+            Encoding::Utf8 if !is_text(key) || !is_text(value) => Encoding::Hex,
+            other => other,
+        };
+
+        Record {
+            key: encoding.encode(key),
+            value: encoding.encode(value),
+            encoding,
+            created_at: entry.created_at,
+            expires_at: entry.expires_at,
+            namespace: 0,
+        }
+    }
+
+    // Convert back into the fixed size key and 64 byte value stored by the cache
+    pub fn to_entry(&self) -> Result<([u8; KEY_SIZE], [u8; 64], CacheEntry), String> {
+        let key_bytes = self.encoding.decode(&self.key)?;
+        if key_bytes.is_empty() || key_bytes.len() > KEY_SIZE {
+            return Err(format!("key must be 1 to {} bytes, got {}", KEY_SIZE, key_bytes.len()));
+        }
+
+        let value_bytes = self.encoding.decode(&self.value)?;
+        if value_bytes.len() > VALUE_SIZE {
+            return Err(format!("value must be at most {} bytes, got {}", VALUE_SIZE, value_bytes.len()));
+        }
+
+        let expiry_seconds = match self.expires_at {
+            Some(expires_at) => {
+                let seconds = (expires_at - self.created_at).num_seconds();
+                u16::try_from(seconds)
+                    .ok()
+                    .filter(|&seconds| seconds > 0)
+                    .ok_or_else(|| format!("expires_at must be 1 to {} seconds after created_at", u16::MAX))?
+            }
+            None => 0,
+        };
+
+        let mut key = [0u8; KEY_SIZE];
+        key[..key_bytes.len()].copy_from_slice(&key_bytes);
+
+        let mut value = [0u8; 64];
+        value[..value_bytes.len()].copy_from_slice(&value_bytes);
+        value[56..62].copy_from_slice(&self.created_at.timestamp().to_be_bytes()[2..8]);
+        value[62..64].copy_from_slice(&expiry_seconds.to_be_bytes());
+
+        let mut value_only = [0u8; VALUE_SIZE];
+        value_only.copy_from_slice(&value[0..VALUE_SIZE]);
+
+        // Snapshots keep whole seconds, so drop sub-second precision here too
+        let created_at = DateTime::<Utc>::from_timestamp(self.created_at.timestamp(), 0).unwrap_or(self.created_at);
+        let entry = CacheEntry {
+            value: value_only,
+            created_at,
+            expires_at: self.expires_at.map(|_| created_at + chrono::TimeDelta::seconds(expiry_seconds as i64)),
+            // Assigned when the record is imported
+            version: 0,
+        };
+
+        Ok((key, value, entry))
+    }
+}
+
+// Collect the contents of every namespace as records, sorted by namespace and key for stable output
+pub fn records(cache: &Cache, encoding: Encoding) -> io::Result<Vec<Record>> {
+    let mut entries: Vec<(u8, [u8; KEY_SIZE], CacheEntry)> = Vec::new();
+    for (namespace, storage) in cache.namespaces().opened() {
+        storage.iter(&mut |key, value| {
+            entries.push((namespace as u8, *key, utils::create_cache_entry(value)));
+            ControlFlow::Continue(())
+        })?;
+    }
+    entries.sort_by_key(|(namespace, key, _)| (*namespace, *key));
+
+    Ok(entries
+        .iter()
+        .map(|(namespace, key, entry)| Record { namespace: *namespace, ..Record::new(key, entry, encoding) })
+        .collect())
+}
+
+pub fn write_records<W: Write>(records: &[Record], format: Format, mut writer: W) -> io::Result<()> {
+    match format {
+        Format::Json => {
+            serde_json::to_writer_pretty(&mut writer, records)?;
+            writeln!(writer)?;
+            writer.flush()
+        }
+        Format::Ndjson => {
+            for record in records {
+                serde_json::to_writer(&mut writer, record)?;
+                writeln!(writer)?;
+            }
+            writer.flush()
+        }
+        Format::Csv => {
+            let mut writer = csv::Writer::from_writer(writer);
+            for record in records {
+                writer.serialize(record).map_err(io::Error::other)?;
+            }
+            writer.flush()
+        }
+    }
+}
+
+pub fn read_records<R: Read>(format: Format, reader: R) -> io::Result<Vec<Record>> {
+    match format {
+        Format::Json => serde_json::from_reader(reader).map_err(|e| invalid_data(e.to_string())),
+        Format::Ndjson => {
+            let mut records = Vec::new();
+            for (index, line) in io::BufReader::new(reader).lines().enumerate() {
+                let line = line?;
+                if line.trim().is_empty() {
+                    continue;
+                }
+                let record = serde_json::from_str(&line)
+                    .map_err(|e| invalid_data(format!("line {}: {}", index + 1, e)))?;
+                records.push(record);
+            }
+            Ok(records)
+        }
+        Format::Csv => csv::Reader::from_reader(reader)
+            .deserialize()
+            .collect::<Result<Vec<Record>, _>>()
+            .map_err(|e| invalid_data(e.to_string())),
+    }
+}
+
+// Insert records into the cache, replacing existing keys. Expired records are skipped.
+// Returns the number of records imported.
+pub fn import_records(cache: &mut Cache, records: &[Record]) -> io::Result<usize> {
+    // Validate everything first so a bad file leaves the cache untouched
+    let namespaces = cache.namespaces().count();
+    let entries = records
+        .iter()
+        .enumerate()
+        .map(|(index, record)| {
+            if record.namespace as usize >= namespaces {
+                return Err(invalid_data(format!("record {}: namespace {} is out of range, there are {}", index + 1, record.namespace, namespaces)));
+            }
+            record.to_entry().map_err(|e| invalid_data(format!("record {}: {}", index + 1, e)))
+        })
+        .collect::<io::Result<Vec<_>>>()?;
+
+    let now = Utc::now();
+    let mut imported = 0;
+
+    for ((key, value, entry), record) in entries.into_iter().zip(records) {
+        if entry.expires_at.is_some_and(|expires_at| expires_at <= now) {
+            continue;
+        }
+        cache.namespaces().get(record.namespace as usize)?.update(&key, &mut |current| Some(storage::stored(&value, storage::next_version(current))))?;
+        imported += 1;
+    }
+
+    cache.mark_dirty(imported);
+    Ok(imported)
+}
+
+fn trim_padding(bytes: &[u8]) -> &[u8] {
+    let end = bytes.iter().rposition(|&b| b != 0).map_or(0, |i| i + 1);
+    &bytes[..end]
+}
+
+fn is_text(bytes: &[u8]) -> bool {
+    std::str::from_utf8(bytes).is_ok_and(|text| !text.contains('\0'))
+}
+
+fn decode_hex(text: &str) -> Result<Vec<u8>, String> {
+    if !text.len().is_multiple_of(2) {
+        return Err("invalid hex: odd number of digits".to_string());
+    }
+
+    (0..text.len())
+        .step_by(2)
+        .map(|i| {
+            text.get(i..i + 2)
+                .and_then(|pair| u8::from_str_radix(pair, 16).ok())
+                .ok_or_else(|| format!("invalid hex digits at offset {}", i))
+        })
+        .collect()
+}
+
+fn invalid_data(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    fn entry(value: &[u8], created_at: i64, ttl: Option<i64>) -> CacheEntry {
+        let mut padded = [0u8; VALUE_SIZE];
+        padded[..value.len()].copy_from_slice(value);
+        let created_at = DateTime::<Utc>::from_timestamp(created_at, 0).unwrap();
+        CacheEntry {
+            value: padded,
+            created_at,
+            expires_at: ttl.map(|ttl| created_at + chrono::TimeDelta::seconds(ttl)),
+            version: 0,
+        }
+    }
+
+    fn key(name: &[u8]) -> [u8; KEY_SIZE] {
+        let mut key = [0u8; KEY_SIZE];
+        key[..name.len()].copy_from_slice(name);
+        key
+    }
+
+    fn sample() -> Vec<Record> {
+        let now = Utc::now().timestamp();
+        vec![
+            Record::new(&key(b"user:1"), &entry(b"alice, \"admin\"", now, Some(3600)), Encoding::Utf8),
+            Record::new(&key(&[0xff, 0x00, 0x01]), &entry(&[0x80, 0x81], now, None), Encoding::Utf8),
+            Record::new(&key(b"blob"), &entry(b"\x00\x01binary", now, None), Encoding::Base64),
+        ]
+    }
+
+    #[test]
+    fn test_text_and_binary_encoding() {
+        let records = sample();
+        assert_eq!(records[0].encoding, Encoding::Utf8);
+        assert_eq!(records[0].key, "user:1");
+        assert_eq!(records[1].encoding, Encoding::Hex);
+        assert_eq!(records[1].key, "ff0001");
+        assert_eq!(records[2].encoding, Encoding::Base64);
+    }
+
+    #[test]
+    fn test_formats_round_trip() {
+        let records = sample();
+        for format in [Format::Json, Format::Ndjson, Format::Csv] {
+            let mut buffer = Vec::new();
+            write_records(&records, format, &mut buffer).unwrap();
+            assert_eq!(read_records(format, buffer.as_slice()).unwrap(), records, "{:?}", format);
+        }
+    }
+
+    #[test]
+    fn test_entry_round_trip() {
+        let original = entry(b"hello", 1_700_000_000, Some(90));
+        let record = Record::new(&key(b"greeting"), &original, Encoding::Utf8);
+        let (decoded_key, value, decoded) = record.to_entry().unwrap();
+
+        assert_eq!(decoded_key, key(b"greeting"));
+        assert_eq!(decoded.value, original.value);
+        assert_eq!(decoded.expires_at, original.expires_at);
+        assert_eq!(crate::utils::parse_cache_metadata(&value), (original.created_at, original.expires_at));
+    }
+
+    #[test]
+    fn test_invalid_records_are_rejected() {
+        let mut record = Record::new(&key(b"k"), &entry(b"v", 1_700_000_000, None), Encoding::Hex);
+        record.key = "zz".to_string();
+        assert!(record.to_entry().is_err());
+
+        let mut record = Record::new(&key(b"k"), &entry(b"v", 1_700_000_000, None), Encoding::Utf8);
+        record.value = "x".repeat(VALUE_SIZE + 1);
+        assert!(record.to_entry().is_err());
+
+        record.value = "v".to_string();
+        record.expires_at = Some(record.created_at - chrono::TimeDelta::seconds(1));
+        assert!(record.to_entry().is_err());
+    }
+
+    #[test]
+    fn test_import_then_export() {
+        let dir = TempDir::new().unwrap();
+        let config = crate::config::Config { data_dir: dir.path().to_path_buf(), ..Default::default() };
+        let mut cache = Cache::with_config("/tmp/cache_test.log", crate::LogLevel::Info, config);
+
+        let mut records = sample();
+        records[2].namespace = 3;
+        let expired = Record::new(&key(b"old"), &entry(b"gone", 1_600_000_000, Some(60)), Encoding::Utf8);
+        records.push(expired);
+
+        assert_eq!(import_records(&mut cache, &records).unwrap(), 3);
+        assert_eq!(cache.dirty(), 3);
+
+        let mut expected = sample();
+        expected[2].namespace = 3;
+        expected.sort_by_key(|record| (record.namespace, record.to_entry().unwrap().0));
+        let exported = super::records(&cache, Encoding::Utf8).unwrap();
+        assert_eq!(exported.len(), 3);
+        assert_eq!(exported[2].key, hex(b"blob"));
+        assert_eq!(exported[2].namespace, 3);
+        assert_eq!(cache.namespaces().get(3).unwrap().len().unwrap(), 1);
+        assert_eq!(
+            super::records(&cache, Encoding::Hex).unwrap().iter().map(|r| r.key.clone()).collect::<Vec<_>>(),
+            expected.iter().map(|r| hex(trim_padding(&r.to_entry().unwrap().0))).collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn test_import_rejects_namespace_out_of_range() {
+        let dir = TempDir::new().unwrap();
+        let config = crate::config::Config { data_dir: dir.path().to_path_buf(), ..Default::default() };
+        let mut cache = Cache::with_config("/tmp/cache_test.log", crate::LogLevel::Info, config);
+
+        // The bad record comes after good ones, which must not be written either
+        let mut records = sample();
+        records[1].namespace = cache.namespaces().count() as u8;
+        assert!(import_records(&mut cache, &records).is_err());
+        assert_eq!(cache.info().unwrap().keys, 0);
+        assert_eq!(cache.dirty(), 0);
+    }
+
+    #[test]
+    fn test_format_from_path() {
+        assert_eq!(Format::from_path(Path::new("dump.csv")), Format::Csv);
+        assert_eq!(Format::from_path(Path::new("dump.ndjson")), Format::Ndjson);
+        assert_eq!(Format::from_path(Path::new("dump")), Format::Json);
+    }
+}
@@ -0,0 +1,209 @@
+use std::{fs, io, path::{Path, PathBuf}, time::Duration};
+use chrono::{DateTime, NaiveDateTime, Utc};
+
+// Timestamped copies of past snapshots live next to the current one
+pub const GENERATIONS_DIR: &str = "generations";
+const GENERATION_PREFIX: &str = "cache-";
+const GENERATION_SUFFIX: &str = ".snap";
+const GENERATION_TIME_FORMAT: &str = "%Y%m%dT%H%M%S%.3fZ";
+
+// How many generations to keep and for how long. Generations are disabled when keep is 0.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Retention {
+    pub keep: usize,
+    pub max_age: Option<Duration>,
+}
+
+impl Retention {
+    pub fn is_enabled(&self) -> bool {
+        self.keep > 0
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Generation {
+    pub path: PathBuf,
+    pub created_at: DateTime<Utc>,
+}
+
+impl Generation {
+    pub fn name(&self) -> String {
+        self.path.file_name().map(|name| name.to_string_lossy().into_owned()).unwrap_or_default()
+    }
+}
+
+// Which generation to boot from
+#[derive(Clone, Debug, PartialEq)]
+pub enum RestoreTarget {
+    Latest,
+    // A generation file name as shown by --list-generations
+    Named(String),
+    // The newest generation taken at or before this time
+    Before(DateTime<Utc>),
+}
+
+pub fn generations_dir(data_dir: &Path) -> PathBuf {
+    data_dir.join(GENERATIONS_DIR)
+}
+
+fn generation_name(created_at: DateTime<Utc>) -> String {
+    format!("{}{}{}", GENERATION_PREFIX, created_at.format(GENERATION_TIME_FORMAT), GENERATION_SUFFIX)
+}
+
+fn parse_generation_name(name: &str) -> Option<DateTime<Utc>> {
+    let stamp = name.strip_prefix(GENERATION_PREFIX)?.strip_suffix(GENERATION_SUFFIX)?;
+    NaiveDateTime::parse_from_str(stamp, GENERATION_TIME_FORMAT).ok().map(|time| time.and_utc())
+}
+
+// Generations on disk, oldest first
+pub fn list(data_dir: &Path) -> io::Result<Vec<Generation>> {
+    let dir = generations_dir(data_dir);
+    if !dir.exists() {
+        return Ok(Vec::new());
+    }
+
+    let mut generations: Vec<Generation> = fs::read_dir(&dir)?
+        .filter_map(|entry| entry.ok())
+        .filter_map(|entry| {
+            let created_at = parse_generation_name(&entry.file_name().to_string_lossy())?;
+            Some(Generation { path: entry.path(), created_at })
+        })
+        .collect();
+
+    generations.sort_by_key(|generation| generation.created_at);
+    Ok(generations)
+}
+
+// Keep a copy of a freshly written snapshot as a new generation, then apply retention
+pub fn record(snapshot_path: &Path, data_dir: &Path, created_at: DateTime<Utc>, retention: &Retention) -> io::Result<Generation> {
+    let dir = generations_dir(data_dir);
+    fs::create_dir_all(&dir)?;
+
+    let path = dir.join(generation_name(created_at));
+
+    // Snapshots are replaced by rename, so a hard link keeps this version without copying it
+    if fs::hard_link(snapshot_path, &path).is_err() {
+        fs::copy(snapshot_path, &path)?;
+    }
+
+    prune(data_dir, retention, created_at)?;
+
+    Ok(Generation { path, created_at })
+}
+
+// Remove generations beyond the retained count or older than the max age. The newest is always kept.
+pub fn prune(data_dir: &Path, retention: &Retention, now: DateTime<Utc>) -> io::Result<usize> {
+    let generations = list(data_dir)?;
+    let keep = retention.keep.max(1);
+    let mut removed = 0;
+
+    for (index, generation) in generations.iter().enumerate().rev() {
+        let newer = generations.len() - 1 - index;
+        if newer == 0 {
+            continue;
+        }
+
+        let too_many = newer >= keep;
+        let too_old = retention.max_age.is_some_and(|max_age| {
+            (now - generation.created_at).to_std().is_ok_and(|age| age > max_age)
+        });
+
+        if too_many || too_old {
+            fs::remove_file(&generation.path)?;
+            removed += 1;
+        }
+    }
+
+    Ok(removed)
+}
+
+pub fn find(data_dir: &Path, target: &RestoreTarget) -> io::Result<Generation> {
+    let generations = list(data_dir)?;
+
+    let found = match target {
+        RestoreTarget::Latest => generations.last(),
+        RestoreTarget::Named(name) => generations.iter().find(|generation| generation.name() == *name),
+        RestoreTarget::Before(time) => generations.iter().rev().find(|generation| generation.created_at <= *time),
+    };
+
+    found.cloned().ok_or_else(|| {
+        io::Error::new(io::ErrorKind::NotFound, format!("no snapshot generation matches {:?}", target))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeDelta;
+    use tempfile::TempDir;
+
+    fn write_generations(dir: &TempDir, times: &[DateTime<Utc>]) {
+        let snapshot = dir.path().join("cache.json");
+        let tmp = dir.path().join("cache.json.tmp");
+        for time in times {
+            // Replaced by rename, like real snapshots
+            fs::write(&tmp, time.to_rfc3339()).unwrap();
+            fs::rename(&tmp, &snapshot).unwrap();
+            record(&snapshot, dir.path(), *time, &Retention { keep: 100, max_age: None }).unwrap();
+        }
+    }
+
+    fn at(seconds: i64) -> DateTime<Utc> {
+        DateTime::<Utc>::from_timestamp(1_700_000_000 + seconds, 0).unwrap()
+    }
+
+    #[test]
+    fn test_name_round_trip() {
+        let time = DateTime::<Utc>::from_timestamp(1_700_000_000, 123_000_000).unwrap();
+        assert_eq!(parse_generation_name(&generation_name(time)), Some(time));
+        assert_eq!(parse_generation_name("cache.json"), None);
+    }
+
+    #[test]
+    fn test_generations_keep_their_contents() {
+        let dir = TempDir::new().unwrap();
+        write_generations(&dir, &[at(0), at(60)]);
+
+        let generations = list(dir.path()).unwrap();
+        assert_eq!(generations.len(), 2);
+        assert_eq!(fs::read_to_string(&generations[0].path).unwrap(), at(0).to_rfc3339());
+        assert_eq!(fs::read_to_string(&generations[1].path).unwrap(), at(60).to_rfc3339());
+    }
+
+    #[test]
+    fn test_prune_by_count() {
+        let dir = TempDir::new().unwrap();
+        write_generations(&dir, &[at(0), at(60), at(120), at(180)]);
+
+        assert_eq!(prune(dir.path(), &Retention { keep: 2, max_age: None }, at(180)).unwrap(), 2);
+        let remaining: Vec<_> = list(dir.path()).unwrap().into_iter().map(|g| g.created_at).collect();
+        assert_eq!(remaining, vec![at(120), at(180)]);
+    }
+
+    #[test]
+    fn test_prune_by_age_keeps_newest() {
+        let dir = TempDir::new().unwrap();
+        write_generations(&dir, &[at(0), at(60)]);
+
+        let retention = Retention { keep: 10, max_age: Some(Duration::from_secs(30)) };
+        prune(dir.path(), &retention, at(60) + TimeDelta::try_hours(1).unwrap()).unwrap();
+
+        let remaining: Vec<_> = list(dir.path()).unwrap().into_iter().map(|g| g.created_at).collect();
+        assert_eq!(remaining, vec![at(60)]);
+    }
+
+    #[test]
+    fn test_find_targets() {
+        let dir = TempDir::new().unwrap();
+        write_generations(&dir, &[at(0), at(60), at(120)]);
+
+        assert_eq!(find(dir.path(), &RestoreTarget::Latest).unwrap().created_at, at(120));
+        assert_eq!(find(dir.path(), &RestoreTarget::Before(at(90))).unwrap().created_at, at(60));
+        assert_eq!(find(dir.path(), &RestoreTarget::Before(at(60))).unwrap().created_at, at(60));
+        assert!(find(dir.path(), &RestoreTarget::Before(at(-1))).is_err());
+
+        let name = generation_name(at(0));
+        assert_eq!(find(dir.path(), &RestoreTarget::Named(name)).unwrap().created_at, at(0));
+        assert!(find(dir.path(), &RestoreTarget::Named("cache-missing.snap".to_string())).is_err());
+    }
+}
@@ -0,0 +1,263 @@
+use std::{fs, io, path::Path};
+use chrono::{TimeDelta, Utc};
+use crate::{snapshot::{self, Codec, Header, LEGACY_RECORD_SIZE, RECORD_SIZE}, storage::{self, Value}, utils::parse_cache_metadata};
+
+// Records claiming to be written further in the future than this are treated as corrupt
+const MAX_CLOCK_SKEW_SECS: i64 = 86400;
+// Upper bounds of the size histogram buckets, in bytes
+const SIZE_BUCKETS: [usize; 5] = [8, 16, 32, 56, 63];
+
+// What could be read from a snapshot without trusting it
+#[derive(Debug, Default)]
+pub struct Inspection {
+    pub file_bytes: usize,
+    pub header: Option<Header>,
+    // Records that passed validation as namespace, key and value, in file order
+    pub records: Vec<(u8, [u8; 63], Value)>,
+    pub invalid_records: usize,
+    // Bytes after the last whole record
+    pub trailing_bytes: usize,
+    pub problems: Vec<String>,
+}
+
+impl Inspection {
+    pub fn is_healthy(&self) -> bool {
+        self.problems.is_empty()
+    }
+}
+
+// Read a snapshot as far as possible, collecting problems instead of failing on the first one
+pub fn inspect(path: &Path, codec: &Codec) -> io::Result<Inspection> {
+    let buffer = fs::read(path)?;
+    let mut inspection = Inspection { file_bytes: buffer.len(), ..Default::default() };
+
+    let header = match Header::parse(&buffer) {
+        Ok(header) => header,
+        Err(e) => {
+            inspection.problems.push(format!("unreadable header: {}", e));
+            return Ok(inspection);
+        }
+    };
+    inspection.header = header;
+
+    let records = match &header {
+        Some(header) => {
+            // Encrypted payloads are authenticated, so nothing in them can be trusted if opening fails
+            let payload = match codec.open(header, &buffer) {
+                Ok(payload) => payload,
+                Err(e) => {
+                    inspection.problems.push(format!("cannot open payload: {}", e));
+                    return Ok(inspection);
+                }
+            };
+
+            let (records, error) = header.compression.decompress_partial(&payload);
+            match error {
+                Some(e) => inspection.problems.push(format!(
+                    "{} payload is damaged, recovered {} bytes before: {}",
+                    header.compression, records.len(), e
+                )),
+                None => {
+                    if let Err(e) = header.verify(&records) {
+                        inspection.problems.push(e.to_string());
+                    }
+                }
+            }
+            records
+        }
+        None => buffer,
+    };
+
+    let record_size = header.as_ref().map_or(LEGACY_RECORD_SIZE, Header::record_size);
+    inspection.trailing_bytes = records.len() % record_size;
+    if inspection.trailing_bytes > 0 {
+        inspection.problems.push(format!(
+            "{} trailing bytes do not form a whole record, the snapshot is truncated",
+            inspection.trailing_bytes
+        ));
+    }
+
+    let latest_plausible = (Utc::now() + TimeDelta::seconds(MAX_CLOCK_SKEW_SECS)).timestamp();
+    // Older records are read at the current size, with version 1
+    let records = snapshot::upgrade_records(records, record_size);
+    let total = records.len() / RECORD_SIZE;
+    for (namespace, key, value) in snapshot::decode(&records) {
+        // Read the raw timestamp, parse_cache_metadata falls back to now for out of range values
+        let mut timestamp_bytes = [0u8; 8];
+        timestamp_bytes[2..8].copy_from_slice(&value[56..62]);
+        if i64::from_be_bytes(timestamp_bytes) > latest_plausible {
+            inspection.invalid_records += 1;
+            continue;
+        }
+        inspection.records.push((namespace, key, value));
+    }
+
+    // Snapshots never contain empty keys, so skipped ones count as damage too
+    inspection.invalid_records += total - inspection.records.len() - inspection.invalid_records;
+    if inspection.invalid_records > 0 {
+        inspection.problems.push(format!("{} records have an empty key or impossible timestamp", inspection.invalid_records));
+    }
+
+    Ok(inspection)
+}
+
+// Count and size distribution of the records in a snapshot
+#[derive(Debug, Default, PartialEq)]
+pub struct Stats {
+    pub records: usize,
+    pub expired: usize,
+    pub with_ttl: usize,
+    // Counts per SIZE_BUCKETS entry, by length without zero padding
+    pub key_sizes: [usize; SIZE_BUCKETS.len()],
+    pub value_sizes: [usize; SIZE_BUCKETS.len()],
+}
+
+impl Stats {
+    pub fn collect(records: &[(u8, [u8; 63], Value)]) -> Self {
+        let now = Utc::now();
+        let mut stats = Stats { records: records.len(), ..Default::default() };
+
+        for (_, key, value) in records {
+            let (_, expires_at) = parse_cache_metadata(storage::wire(value));
+            if let Some(expires_at) = expires_at {
+                stats.with_ttl += 1;
+                if expires_at <= now {
+                    stats.expired += 1;
+                }
+            }
+
+            stats.key_sizes[bucket(padded_len(key))] += 1;
+            stats.value_sizes[bucket(padded_len(&value[0..56]))] += 1;
+        }
+
+        stats
+    }
+
+    // Histogram rows as (label, keys, values)
+    pub fn histogram(&self) -> Vec<(String, usize, usize)> {
+        let mut lower = 0;
+        SIZE_BUCKETS
+            .iter()
+            .enumerate()
+            .map(|(i, &upper)| {
+                let label = format!("{}-{}", lower, upper);
+                lower = upper + 1;
+                (label, self.key_sizes[i], self.value_sizes[i])
+            })
+            .collect()
+    }
+}
+
+fn padded_len(bytes: &[u8]) -> usize {
+    bytes.iter().rposition(|&b| b != 0).map_or(0, |i| i + 1)
+}
+
+fn bucket(len: usize) -> usize {
+    SIZE_BUCKETS.iter().position(|&upper| len <= upper).unwrap_or(SIZE_BUCKETS.len() - 1)
+}
+
+// Write the valid records of an inspection to a new snapshot. Returns the number of bytes written.
+pub fn salvage(inspection: &Inspection, output: &Path, codec: &Codec) -> io::Result<usize> {
+    let mut records = Vec::with_capacity(inspection.records.len() * RECORD_SIZE);
+    for (namespace, key, value) in &inspection.records {
+        records.push(*namespace);
+        records.extend_from_slice(key);
+        records.extend_from_slice(value);
+    }
+
+    snapshot::write_atomic(output, &codec.wrap(&records)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::snapshot::{Compression, SnapshotView, HEADER_SIZE};
+    use tempfile::TempDir;
+
+    fn view(count: usize) -> SnapshotView {
+        let now = Utc::now().timestamp();
+        let mut view = SnapshotView::new();
+        for i in 0..count {
+            let mut key = [0u8; 63];
+            let name = format!("key:{}", i);
+            key[..name.len()].copy_from_slice(name.as_bytes());
+            let mut value = [0u8; 64];
+            value[0..10].copy_from_slice(b"0123456789");
+            value[56..62].copy_from_slice(&now.to_be_bytes()[2..8]);
+            value[62..64].copy_from_slice(&(if i % 2 == 0 { 3600u16 } else { 0 }).to_be_bytes());
+            view.insert(key, crate::storage::stored(&value, 1));
+        }
+        view
+    }
+
+    fn write_snapshot(dir: &TempDir, codec: &Codec, count: usize) -> std::path::PathBuf {
+        let path = dir.path().join(snapshot::SNAPSHOT_FILE);
+        fs::write(&path, codec.wrap(&snapshot::encode(&[view(count)])).unwrap()).unwrap();
+        path
+    }
+
+    #[test]
+    fn test_healthy_snapshot() {
+        let dir = TempDir::new().unwrap();
+        let path = write_snapshot(&dir, &Codec::default(), 10);
+
+        let inspection = inspect(&path, &Codec::default()).unwrap();
+        assert!(inspection.is_healthy(), "{:?}", inspection.problems);
+        assert_eq!(inspection.records.len(), 10);
+
+        let stats = Stats::collect(&inspection.records);
+        assert_eq!(stats.records, 10);
+        assert_eq!(stats.with_ttl, 5);
+        assert_eq!(stats.expired, 0);
+        assert_eq!(stats.key_sizes[0], 10);
+        assert_eq!(stats.value_sizes[1], 10);
+    }
+
+    #[test]
+    fn test_truncated_snapshot_is_salvaged() {
+        let dir = TempDir::new().unwrap();
+        let path = write_snapshot(&dir, &Codec::default(), 10);
+        let buffer = fs::read(&path).unwrap();
+        fs::write(&path, &buffer[..HEADER_SIZE + RECORD_SIZE * 7 + 50]).unwrap();
+
+        let inspection = inspect(&path, &Codec::default()).unwrap();
+        assert!(!inspection.is_healthy());
+        assert_eq!(inspection.records.len(), 7);
+        assert_eq!(inspection.trailing_bytes, 50);
+
+        let output = dir.path().join("salvaged");
+        salvage(&inspection, &output, &Codec::default()).unwrap();
+        let salvaged = inspect(&output, &Codec::default()).unwrap();
+        assert!(salvaged.is_healthy());
+        assert_eq!(salvaged.records, inspection.records);
+    }
+
+    #[test]
+    fn test_corrupted_record_is_dropped() {
+        let dir = TempDir::new().unwrap();
+        let path = write_snapshot(&dir, &Codec::default(), 4);
+        let mut buffer = fs::read(&path).unwrap();
+        // Garble the timestamp of the second record
+        buffer[HEADER_SIZE + RECORD_SIZE + 1 + 63 + 56] = 0xff;
+        fs::write(&path, &buffer).unwrap();
+
+        let inspection = inspect(&path, &Codec::default()).unwrap();
+        assert!(inspection.problems.iter().any(|problem| problem.contains("checksum")));
+        assert_eq!(inspection.invalid_records, 1);
+        assert_eq!(inspection.records.len(), 3);
+    }
+
+    #[test]
+    fn test_damaged_compressed_snapshot() {
+        let dir = TempDir::new().unwrap();
+        let codec = Codec { compression: Compression::Zstd, keyring: None };
+        let path = write_snapshot(&dir, &codec, 20_000);
+        let buffer = fs::read(&path).unwrap();
+        fs::write(&path, &buffer[..buffer.len() / 2]).unwrap();
+
+        let inspection = inspect(&path, &codec).unwrap();
+        assert!(!inspection.is_healthy());
+        assert!(!inspection.records.is_empty());
+        assert!(inspection.records.len() < 20_000);
+    }
+}
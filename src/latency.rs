@@ -0,0 +1,120 @@
+use std::{fmt, time::Duration};
+use hdrhistogram::Histogram;
+use parking_lot::Mutex;
+
+/*
+    Latency of G, I and R commands, whole input batches and snapshot writes, in HDR histograms with
+    microsecond resolution and 3 significant digits. Commands are timed from parsing to the flushed
+    reply; snapshots from capturing the view to the file being renamed into place.
+ */
+// Anything slower is recorded as an hour
+const MAX_MICROS: u64 = 3_600_000_000;
+const SIGNIFICANT_DIGITS: u8 = 3;
+pub const QUANTILES: [(&str, f64); 3] = [("p50", 0.5), ("p99", 0.99), ("p999", 0.999)];
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Operation {
+    Get,
+    Insert,
+    Remove,
+    Batch,
+    Snapshot,
+}
+
+impl Operation {
+    pub const ALL: [Operation; 5] = [Operation::Get, Operation::Insert, Operation::Remove, Operation::Batch, Operation::Snapshot];
+
+    // The timed operation of a command byte, None for commands that are not timed
+    pub fn from_command(command: u8) -> Option<Operation> {
+        match command {
+            b'G' => Some(Operation::Get),
+            b'I' => Some(Operation::Insert),
+            b'R' => Some(Operation::Remove),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for Operation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Operation::Get => "G",
+            Operation::Insert => "I",
+            Operation::Remove => "R",
+            Operation::Batch => "batch",
+            Operation::Snapshot => "snapshot",
+        })
+    }
+}
+
+// Quantiles of one operation, in microseconds
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Summary {
+    pub count: u64,
+    pub mean: f64,
+    pub quantiles: [u64; QUANTILES.len()],
+}
+
+pub struct Latency {
+    histograms: [Mutex<Histogram<u64>>; Operation::ALL.len()],
+}
+
+impl Latency {
+    pub fn new() -> Self {
+        Latency {
+            histograms: std::array::from_fn(|_| {
+                Mutex::new(Histogram::new_with_bounds(1, MAX_MICROS, SIGNIFICANT_DIGITS).expect("valid histogram bounds"))
+            }),
+        }
+    }
+
+    pub fn record(&self, operation: Operation, elapsed: Duration) {
+        let micros = (elapsed.as_micros() as u64).clamp(1, MAX_MICROS);
+        self.histograms[operation as usize].lock().saturating_record(micros);
+    }
+
+    pub fn summary(&self, operation: Operation) -> Summary {
+        let histogram = self.histograms[operation as usize].lock();
+        Summary {
+            count: histogram.len(),
+            mean: histogram.mean(),
+            quantiles: QUANTILES.map(|(_, quantile)| histogram.value_at_quantile(quantile)),
+        }
+    }
+}
+
+impl Default for Latency {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_quantiles() {
+        let latency = Latency::new();
+        for micros in 1..=1000 {
+            latency.record(Operation::Get, Duration::from_micros(micros));
+        }
+        latency.record(Operation::Get, Duration::from_secs(7200));
+
+        let summary = latency.summary(Operation::Get);
+        assert_eq!(summary.count, 1001);
+        assert!((499..=501).contains(&summary.quantiles[0]));
+        assert!((989..=991).contains(&summary.quantiles[1]));
+        // The outlier is clamped to the maximum
+        assert!(summary.quantiles[2] >= 1000);
+        assert_eq!(latency.summary(Operation::Insert), Summary { count: 0, mean: 0.0, quantiles: [0; 3] });
+    }
+
+    #[test]
+    fn test_timed_commands() {
+        assert_eq!(Operation::from_command(b'G'), Some(Operation::Get));
+        assert_eq!(Operation::from_command(b'R'), Some(Operation::Remove));
+        assert_eq!(Operation::from_command(b'V'), None);
+        assert_eq!(Operation::Snapshot.to_string(), "snapshot");
+    }
+}
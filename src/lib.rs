@@ -0,0 +1,625 @@
+// Stdout carries protocol replies only, see response.rs
+#![cfg_attr(not(test), deny(clippy::print_stdout))]
+
+use std::{ops::ControlFlow, path::{Path, PathBuf}, sync::{atomic::{AtomicBool, AtomicUsize, Ordering}, Arc, Mutex}};
+use chrono::{DateTime, Utc};
+use config::Config;
+use generations::{Generation, RestoreTarget};
+use logger::Logger;
+use serde_json::json;
+use rayon::prelude::*;
+use response::ResponseWriter;
+use shutdown::ShutdownSignal;
+use slowlog::SlowLog;
+use snapshot::{Codec, SaveStatus, SnapshotView};
+use stats::{Info, Stats};
+use storage::{Namespaces, StorageBackend};
+
+/*
+    Cache format:
+    - First byte: command (G=get, V=get with version, I=insert, N=insert if absent, X=insert if present,
+      C=compare-and-swap, M=multi-get, S=multi-set, P=scan, K=keys, R=remove, D=remove matching,
+      +=increment, -=decrement, U=select namespace, Z=key count, W=flush, T=stats, O=slow log,
+      H=save, B=background save, L=last save, Q=shutdown)
+    - Next 63 bytes: key
+    - Next 56 bytes: value
+    - Last 8 bytes:
+      - First 6 bytes: timestamp (epoch seconds)
+      - Last 2 bytes: expiration time in seconds
+ */
+
+pub mod logger;
+pub mod buffer;
+pub mod cli;
+pub mod conditional;
+pub mod config;
+pub mod counter;
+pub mod crypto;
+pub mod export;
+pub mod generations;
+pub mod inspect;
+pub mod latency;
+pub mod metrics;
+pub mod multi;
+pub mod response;
+pub mod scan;
+pub mod shutdown;
+pub mod slowlog;
+pub mod snapshot;
+pub mod stats;
+pub mod storage;
+pub mod tasks;
+pub mod utils;
+
+// Thread pool for background tasks
+const THREAD_POOL_SIZE: usize = 4;
+const DEFAULT_INVALIDATION_THRESHOLD: usize = 100;
+// Snapshot batches read ahead of the parser while loading
+const LOAD_QUEUE_DEPTH: usize = 2;
+
+pub use logger::LogLevel;
+
+// Cache entry with metadata for more efficient expiration handling, derived from a stored value
+#[derive(Clone, Debug)]
+pub struct CacheEntry {
+    pub value: [u8; 56],
+    pub created_at: DateTime<Utc>,
+    pub expires_at: Option<DateTime<Utc>>,
+    // Bumped on every write, for compare-and-swap
+    pub version: u64,
+}
+
+pub struct Cache {
+    cur_buf: Arc<Mutex<[u8; 128]>>,
+    // Multi-key request still waiting for key frames
+    pending: Option<multi::Request>,
+    // Key space of the selected namespace, in memory or on disk depending on the configured backend
+    storage: Arc<dyn StorageBackend>,
+    namespaces: Arc<Namespaces>,
+    // Namespace the commands apply to, chosen with U
+    namespace: usize,
+    // Coordinates graceful shutdown between the signal handler, stdin loop and background threads
+    shutdown: ShutdownSignal,
+    logger: Logger,
+    // Sole writer of replies; stdout unless a test captures them
+    responses: ResponseWriter,
+    // Track operations since last invalidation for batched invalidation
+    ops_since_invalidation: Arc<AtomicUsize>,
+    invalidation_threshold: usize,
+    // Thread pool for background tasks
+    thread_pool: Arc<threadpool::ThreadPool>,
+    // Flag to indicate when to save
+    pub save_flag: Arc<AtomicBool>,
+    // Keys changed since the last captured snapshot, checked against the save policies
+    dirty: Arc<AtomicUsize>,
+    // Outcome of the most recent save and whether a background save is running
+    save_status: Arc<SaveStatus>,
+    // Hit, miss, expiry and per-command counters for T
+    stats: Arc<Stats>,
+    slowlog: SlowLog,
+    // Frames in the batch being processed and how long it waited for the cache lock, for the slow log
+    batch_size: usize,
+    lock_wait: std::time::Duration,
+    // Data directory
+    data_dir: PathBuf,
+    config: Config,
+    started_at: DateTime<Utc>,
+}
+
+impl Cache {
+    pub fn new(log_path: &str, level: LogLevel) -> Self {
+        Self::with_config(log_path, level, Config::from_env().expect("Invalid cache configuration"))
+    }
+
+    pub fn with_config(log_path: &str, level: LogLevel, config: Config) -> Self {
+        std::fs::create_dir_all(&config.data_dir).ok();
+        let storage = storage::open(&config).expect("Unable to open cache storage");
+        Self::with_storage(log_path, level, config, storage)
+    }
+
+    // `storage` becomes namespace 0; the other namespaces are opened from the config
+    pub fn with_storage(log_path: &str, level: LogLevel, config: Config, storage: Arc<dyn StorageBackend>) -> Self {
+        // Create a single, reusable buffer
+        let cur_buf = Arc::new(Mutex::new([0u8; 128]));
+        
+        // Use Path API properly
+        let path = Path::new(log_path);
+        
+        // Create logger
+        let logger = Logger::with_rotation(path.to_str().unwrap(), level, config.log_sinks, config.log_rotation);
+        let shutdown = ShutdownSignal::new(config.shutdown_timeout, Some(logger.clone()));
+        
+        // Create thread pool
+        let thread_pool = Arc::new(threadpool::ThreadPool::new(THREAD_POOL_SIZE));
+        
+        // Set up data directory
+        let data_dir = config.data_dir.clone();
+        
+        // Create directory if it doesn't exist
+        std::fs::create_dir_all(&data_dir).ok();
+        
+        let namespaces = Arc::new(Namespaces::new(&config, Arc::clone(&storage)).expect("Unable to open cache namespaces"));
+        
+        Cache {
+            cur_buf,
+            pending: None,
+            storage,
+            namespaces,
+            namespace: 0,
+            shutdown,
+            logger,
+            responses: ResponseWriter::stdout(),
+            ops_since_invalidation: Arc::new(AtomicUsize::new(0)),
+            invalidation_threshold: DEFAULT_INVALIDATION_THRESHOLD,
+            thread_pool,
+            save_flag: Arc::new(AtomicBool::new(false)),
+            dirty: Arc::new(AtomicUsize::new(0)),
+            save_status: Arc::new(SaveStatus::new()),
+            stats: Arc::new(Stats::new()),
+            slowlog: SlowLog::new(config.slowlog_threshold, config.slowlog_max_len),
+            batch_size: 1,
+            lock_wait: std::time::Duration::ZERO,
+            data_dir,
+            config,
+            started_at: Utc::now(),
+        }
+    }
+
+    pub fn shutdown_signal(&self) -> ShutdownSignal {
+        self.shutdown.clone()
+    }
+
+    pub fn config(&self) -> &Config {
+        &self.config
+    }
+
+    // Storage of the selected namespace
+    pub fn storage(&self) -> &Arc<dyn StorageBackend> {
+        &self.storage
+    }
+
+    pub fn namespaces(&self) -> &Arc<Namespaces> {
+        &self.namespaces
+    }
+
+    pub fn namespace(&self) -> usize {
+        self.namespace
+    }
+
+    // Entries across every namespace
+    pub fn key_count(&self) -> std::io::Result<usize> {
+        let mut count = 0;
+        for (_, storage) in self.namespaces.opened() {
+            count += storage.len()?;
+        }
+        Ok(count)
+    }
+
+    // Tiered storage counters of each opened namespace
+    pub fn tier_stats(&self) -> Vec<(usize, storage::TierStats)> {
+        self.namespaces
+            .opened()
+            .iter()
+            .filter_map(|(namespace, storage)| Some((*namespace, storage.tier_stats()?)))
+            .collect()
+    }
+
+    pub fn stats(&self) -> &Stats {
+        &self.stats
+    }
+    
+    pub fn slowlog(&mut self) -> &mut SlowLog {
+        &mut self.slowlog
+    }
+    
+    // Called by the command loop before handing over a batch; the wait is charged to its first command
+    pub fn begin_batch(&mut self, size: usize, lock_wait: std::time::Duration) {
+        self.batch_size = size;
+        self.lock_wait = lock_wait;
+    }
+    
+    // Server statistics for T
+    pub fn info(&self) -> std::io::Result<Info> {
+        let mut info = Info::gather(&self.stats);
+        for (namespace, storage) in self.namespaces.opened() {
+            let len = storage.len()?;
+            info.keys += len;
+            let (hits, misses) = self.stats.namespace_lookups(namespace);
+            info.namespaces.push(stats::NamespaceInfo { namespace, keys: len, hits, misses });
+            
+            // Only entries held in memory count towards the estimate; disk backends are paged by the OS
+            let in_memory = match storage.tier_stats() {
+                Some(tiers) => tiers.hot_entries,
+                None if !storage.is_persistent() => len,
+                None => 0,
+            };
+            info.memory_bytes += in_memory * stats::ENTRY_BYTES;
+        }
+        
+        info.uptime = (Utc::now() - self.started_at).num_seconds();
+        info.last_save = self.save_status.last_save();
+        info.last_save_ms = self.save_status.last_duration_ms();
+        info.last_save_ok = self.save_status.last_ok();
+        info.saving = self.save_status.is_in_progress();
+        info.pool_active = self.thread_pool.active_count();
+        info.pool_queued = self.thread_pool.queued_count();
+        Ok(info)
+    }
+    
+    // Point later commands at another namespace, opening its storage if needed
+    pub fn select(&mut self, namespace: usize) -> std::io::Result<()> {
+        self.storage = self.namespaces.get(namespace)?;
+        self.namespace = namespace;
+        Ok(())
+    }
+
+    // Compression and encryption applied to persisted snapshots
+    pub fn codec(&self) -> Codec {
+        Codec {
+            compression: self.config.snapshot_compression,
+            keyring: self.config.encryption.clone(),
+        }
+    }
+
+    // Write any buffered log lines to disk
+    pub fn flush_log(&self) -> Result<(), Box<dyn std::error::Error>> {
+        self.logger.flush()
+    }
+    
+    pub fn logger(&self) -> &Logger {
+        &self.logger
+    }
+    
+    // Replies go through here and nowhere else, stdout is the protocol channel
+    pub fn responses(&mut self) -> &mut ResponseWriter {
+        &mut self.responses
+    }
+    
+    pub fn set_response_writer(&mut self, writer: ResponseWriter) {
+        self.responses = writer;
+    }
+    
+    pub fn log(&self, level: LogLevel, component: &str, message: impl std::fmt::Display) {
+        self.logger.log(level, component, message);
+    }
+    
+    pub fn log_with(&self, level: LogLevel, component: &str, message: impl std::fmt::Display, fields: &[(&str, serde_json::Value)]) {
+        self.logger.log_with(level, component, message, fields);
+    }
+    
+    // Optimized invalidation that runs in the background
+    pub fn invalidate_cache(&mut self) -> Result<(), Box<dyn std::error::Error>> {
+        let namespaces = self.namespaces.opened();
+        
+        // Use the thread pool for background invalidation
+        let logger = self.logger.clone();
+        let stats = Arc::clone(&self.stats);
+        
+        self.thread_pool.execute(move || {
+            for (_, storage) in namespaces {
+                let mut keys_to_remove = Vec::new();
+                let now = Utc::now();
+                
+                let scanned = storage.iter(&mut |key, value| {
+                    if let Some(expires_at) = utils::create_cache_entry(value).expires_at {
+                        if expires_at <= now {
+                            keys_to_remove.push(*key);
+                            if logger.enabled(LogLevel::Trace) {
+                                logger.log_with(LogLevel::Trace, "expiry", "removing expired key", &[
+                                    ("key_hash", json!(logger::key_hash(scan::trim(key)))),
+                                    ("expired_at", json!(expires_at.to_rfc3339())),
+                                ]);
+                            }
+                        }
+                    }
+                    ControlFlow::Continue(())
+                });
+                if let Err(e) = scanned {
+                    logger.log(LogLevel::Error, "expiry", format!("scanning storage for expired keys failed: {}", e));
+                }
+                
+                // Remove expired entries
+                for key in keys_to_remove {
+                    match storage.delete(&key) {
+                        Ok(Some(_)) => stats.expired(1),
+                        Ok(None) => {}
+                        Err(e) => logger.log(LogLevel::Error, "expiry", format!("removing expired key failed: {}", e)),
+                    }
+                }
+            }
+        });
+        
+        Ok(())
+    }
+    
+    // Load cache from disk
+    pub fn load(&mut self) -> Result<(), Box<dyn std::error::Error>> {
+        // A disk backend that already holds data is at least as recent as the last snapshot
+        if self.storage.is_persistent() {
+            let count = self.key_count()?;
+            if count > 0 {
+                self.log_with(LogLevel::Info, "load", "using entries already in storage", &[
+                    ("entries", json!(count)),
+                    ("storage", json!(self.storage.name())),
+                ]);
+                self.stats.loaded(count);
+                return self.invalidate_cache();
+            }
+        }
+        
+        let cache_path = self.data_dir.join(snapshot::SNAPSHOT_FILE);
+        self.load_from(&cache_path)
+    }
+    
+    // Replace the current snapshot with a past generation and load it
+    pub fn restore(&mut self, target: &RestoreTarget) -> Result<Generation, Box<dyn std::error::Error>> {
+        let generation = generations::find(&self.data_dir, target)?;
+        self.log_with(LogLevel::Info, "load", "restoring snapshot generation", &[
+            ("generation", json!(generation.name())),
+            ("created_at", json!(generation.created_at.to_rfc3339())),
+        ]);
+        
+        self.load_from(&generation.path)?;
+        
+        // Persist right away so a restart before the next save does not come back with the bad snapshot
+        self.clean_up()?;
+        
+        Ok(generation)
+    }
+    
+    // Load a snapshot file into the cache
+    pub fn load_from(&mut self, cache_path: &Path) -> Result<(), Box<dyn std::error::Error>> {
+        self.log(LogLevel::Debug, "load", format!("loading {}", cache_path.display()));
+        
+        if !cache_path.exists() {
+            self.log(LogLevel::Info, "load", "no snapshot found, starting with an empty cache");
+            return Ok(());
+        }
+        
+        let start = std::time::Instant::now();
+        
+        // Strips the snapshot header, decrypts and decompresses if needed
+        let mut reader = snapshot::RecordReader::open(cache_path, &self.codec())?;
+        
+        // Entries go straight into their namespace's list. Namespace 0 usually holds most keys, so it is
+        // presized for an uncompressed snapshot; compressed ones grow as they load.
+        let estimate = std::fs::metadata(cache_path).map_or(0, |meta| meta.len() as usize / snapshot::RECORD_SIZE);
+        let namespace_count = self.namespaces.count();
+        let mut per_namespace = vec![Vec::new(); namespace_count];
+        per_namespace[0].reserve(estimate);
+        let mut loaded = 0;
+        let now = Utc::now();
+        let expired = AtomicUsize::new(0);
+        
+        // One thread reads and decompresses batches while rayon parses the previous one. The bounded
+        // queue keeps at most a few raw batches in memory, so the peak is one copy of the live entries
+        // plus those batches. The entries replace the key spaces only after the whole file, including
+        // its checksum, has been read.
+        let (tx, rx) = std::sync::mpsc::sync_channel::<Vec<u8>>(LOAD_QUEUE_DEPTH);
+        let bytes_read = std::thread::scope(|scope| -> std::io::Result<usize> {
+            let reading = scope.spawn(move || -> std::io::Result<usize> {
+                let mut batch = Vec::new();
+                while reader.next_batch(&mut batch)? {
+                    if tx.send(std::mem::take(&mut batch)).is_err() {
+                        break;
+                    }
+                }
+                Ok(reader.bytes_read())
+            });
+            
+            for batch in rx {
+                let parsed: Vec<(u8, [u8; 63], storage::Value)> = batch
+                    .par_chunks_exact(snapshot::RECORD_SIZE)
+                    .filter_map(snapshot::decode_record)
+                    .filter(|(_, _, value)| {
+                        // Don't load expired entries
+                        let expired_now = utils::create_cache_entry(value).expires_at.is_some_and(|expires| expires <= now);
+                        if expired_now {
+                            expired.fetch_add(1, Ordering::Relaxed);
+                        }
+                        !expired_now
+                    })
+                    .collect();
+                
+                loaded += parsed.len();
+                for (namespace, key, value) in parsed {
+                    let Some(namespace_entries) = per_namespace.get_mut(namespace as usize) else {
+                        return Err(std::io::Error::new(
+                            std::io::ErrorKind::InvalidData,
+                            format!("snapshot has keys in namespace {} but only {} are configured", namespace, namespace_count),
+                        ));
+                    };
+                    namespace_entries.push((key, value));
+                }
+            }
+            
+            reading.join().expect("Snapshot reader thread panicked")
+        })?;
+        
+        let elapsed = start.elapsed();
+        self.stats.expired(expired.load(Ordering::Relaxed));
+        self.log_with(LogLevel::Info, "load", "loaded snapshot", &[
+            ("entries", json!(loaded)),
+            ("expired", json!(expired.load(Ordering::Relaxed))),
+            ("bytes", json!(bytes_read)),
+            ("duration_ms", json!(elapsed.as_millis() as u64)),
+            ("mb_per_sec", json!((bytes_read as f64 / 1_000_000.0 / elapsed.as_secs_f64().max(0.001)).round())),
+        ]);
+        
+        self.stats.loaded(loaded);
+        let opened: Vec<usize> = self.namespaces.opened().iter().map(|(namespace, _)| *namespace).collect();
+        for (namespace, namespace_entries) in per_namespace.into_iter().enumerate() {
+            if !namespace_entries.is_empty() || opened.contains(&namespace) {
+                self.namespaces.get(namespace)?.replace(namespace_entries)?;
+            }
+        }
+        
+        // Run initial invalidation to clean up any expired entries
+        self.invalidate_cache()?;
+        
+        Ok(())
+    }
+    
+    // Record a change to the key space for the save policies
+    pub fn mark_dirty(&self, changes: usize) {
+        self.dirty.fetch_add(changes, Ordering::SeqCst);
+        self.save_flag.store(true, Ordering::SeqCst);
+    }
+    
+    pub fn dirty(&self) -> usize {
+        self.dirty.load(Ordering::SeqCst)
+    }
+    
+    // Whether any save policy is satisfied by the changes since the last save
+    pub fn save_due(&self) -> bool {
+        if !self.save_flag.load(Ordering::SeqCst) {
+            return false;
+        }
+        
+        let last_save = self.save_status.last_attempt().max(self.started_at.timestamp());
+        let since_last_save = std::time::Duration::from_secs((Utc::now().timestamp() - last_save).max(0) as u64);
+        let changes = self.dirty();
+        
+        self.config.save_policies.iter().any(|policy| policy.is_due(since_last_save, changes))
+    }
+    
+    // Full copy of the key space, taken under the cache lock so serialization can happen without it.
+    // Commands wait for the copy, which takes time and memory proportional to the key space.
+    fn capture_snapshot(&self) -> std::io::Result<(u64, usize, Vec<SnapshotView>)> {
+        let seq = self.save_status.next_seq();
+        
+        // Changes after this point belong to the next save. Commands run under the cache lock,
+        // so nothing is written between the swap and the copy.
+        let changes = self.dirty.swap(0, Ordering::SeqCst);
+        self.save_flag.store(false, Ordering::SeqCst);
+        let mut views = vec![SnapshotView::new(); self.namespaces.count()];
+        for (namespace, storage) in self.namespaces.opened() {
+            match storage.snapshot() {
+                Ok(view) => views[namespace] = view,
+                Err(e) => {
+                    self.mark_dirty(changes);
+                    return Err(e);
+                }
+            }
+        }
+        Ok((seq, changes, views))
+    }
+    
+    // Save cache to disk
+    pub fn clean_up(&mut self) -> Result<(), Box<dyn std::error::Error>> {
+        self.log(LogLevel::Debug, "snapshot", "saving");
+        
+        let start = std::time::Instant::now();
+        let (seq, changes, view) = self.capture_snapshot()?;
+        match snapshot::save(&self.data_dir, seq, &view, &self.save_status, &self.codec(), &self.config.snapshot_retention, &self.logger) {
+            Ok(Some(bytes_written)) => {
+                self.stats.snapshot(start.elapsed());
+                self.log_with(LogLevel::Info, "snapshot", "saved", &[
+                    ("bytes", json!(bytes_written)),
+                    ("duration_ms", json!(start.elapsed().as_millis() as u64)),
+                ]);
+            }
+            Ok(None) => self.log(LogLevel::Debug, "snapshot", "skipped save, a newer snapshot is already on disk"),
+            Err(e) => {
+                // Keep the changes pending so the policies retry the save
+                self.mark_dirty(changes);
+                return Err(e.into());
+            }
+        }
+
+        Ok(())
+    }
+    
+    // Empty the selected namespace, or all of them, and persist the empty state right away so a restart
+    // cannot bring the keys back. In the background the snapshot is written on the thread pool instead.
+    pub fn flush(&mut self, all: bool, background: bool) -> Result<(), Box<dyn std::error::Error>> {
+        let targets = if all {
+            self.namespaces.opened().into_iter().map(|(_, storage)| storage).collect()
+        } else {
+            vec![Arc::clone(&self.storage)]
+        };
+        for storage in targets {
+            storage.replace(Vec::new())?;
+        }
+        let namespace = if all { json!("all") } else { json!(self.namespace) };
+        self.log_with(LogLevel::Info, "flush", "flushed keys", &[("namespace", namespace)]);
+        
+        // Saving takes the dirty count, so changes from before the flush no longer trigger a save
+        if background && self.bgsave() {
+            return Ok(());
+        }
+        self.clean_up()
+    }
+    
+    // Save cache to disk on the thread pool while traffic continues. Returns false if a save is already running.
+    pub fn bgsave(&mut self) -> bool {
+        if !self.save_status.begin_background() {
+            return false;
+        }
+        
+        self.log(LogLevel::Debug, "snapshot", "background save started");
+        let start = std::time::Instant::now();
+        let (seq, changes, view) = match self.capture_snapshot() {
+            Ok(captured) => captured,
+            Err(e) => {
+                self.save_status.finish_background();
+                self.log(LogLevel::Error, "snapshot", format!("background save failed: {}", e));
+                return false;
+            }
+        };
+        
+        let data_dir = self.data_dir.clone();
+        let status = Arc::clone(&self.save_status);
+        let dirty = Arc::clone(&self.dirty);
+        let save_flag = Arc::clone(&self.save_flag);
+        let codec = self.codec();
+        let retention = self.config.snapshot_retention;
+        let logger = self.logger.clone();
+        let stats = Arc::clone(&self.stats);
+        
+        self.thread_pool.execute(move || {
+            let result = snapshot::save(&data_dir, seq, &view, &status, &codec, &retention, &logger);
+            status.finish_background();
+            
+            match result {
+                Ok(written) => {
+                    match written {
+                        Some(bytes) => {
+                            stats.snapshot(start.elapsed());
+                            logger.log_with(LogLevel::Info, "snapshot", "background save finished", &[
+                                ("bytes", json!(bytes)),
+                                ("duration_ms", json!(start.elapsed().as_millis() as u64)),
+                            ]);
+                        }
+                        None => logger.log(LogLevel::Debug, "snapshot", "skipped background save, a newer snapshot is already on disk"),
+                    }
+                }
+                Err(e) => {
+                    // Keep the changes pending so the policies retry the save
+                    dirty.fetch_add(changes, Ordering::SeqCst);
+                    save_flag.store(true, Ordering::SeqCst);
+                    logger.log(LogLevel::Error, "snapshot", format!("background save failed: {}", e));
+                }
+            }
+        });
+        
+        true
+    }
+    
+    // Persist everything on shutdown. Disk backends are flushed; the snapshot is skipped only when
+    // saving is turned off, since the storage then already is the durable copy.
+    pub fn final_save(&mut self) -> Result<(), Box<dyn std::error::Error>> {
+        for (_, storage) in self.namespaces.opened() {
+            storage.flush()?;
+        }
+        if self.storage.is_persistent() && self.config.save_policies.is_empty() {
+            self.log(LogLevel::Info, "snapshot", format!("flushed {} storage, snapshots are off", self.storage.name()));
+            return Ok(());
+        }
+        self.clean_up()
+    }
+    
+    pub fn save_status(&self) -> &SaveStatus {
+        &self.save_status
+    }
+}
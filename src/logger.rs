@@ -1,123 +1,346 @@
-use std::{fmt::{self, Display}, fs::{self, OpenOptions}, io::Write, path::{Path, PathBuf}, sync::{Arc, Mutex, RwLock}};
-use chrono::Utc;
-use crate::Cache;
+use std::{fmt::{self, Display, Write as _}, fs::{self, OpenOptions}, io::{self, Write}, path::{Path, PathBuf}, str::FromStr, sync::{Arc, Mutex, RwLock}, thread::{self, JoinHandle}, time::{Duration, SystemTime}};
+use chrono::{SecondsFormat, Utc};
+use serde_json::Value;
+use sha2::{Digest, Sha256};
+use crate::snapshot::{self, Compression};
+
+/*
+    Log records are JSON objects, one per line:
+    {"ts":"2025-01-01T00:00:00.000Z","level":"info","component":"snapshot","msg":"saved","bytes":1024,"duration_ms":3}
+    ts, level, component and msg are always present, followed by any fields of the record. Keys are
+    logged as key_hash, never in the clear. Records below the configured level are dropped before
+    they are formatted.
+
+    Rotation: before buffered lines are written, the file is rotated if they would take it past
+    max_bytes or it is older than max_age. log.log becomes log.log.1, log.log.1 becomes log.log.2
+    and so on, keeping `keep` files; with compression they are log.log.1.lz4 or .zst. Files are renamed
+    under the buffer lock, so no line is written to a file while it is being renamed. Compression
+    runs on its own thread after the lock is released; the next rotation and flush wait for it.
+ */
+const BUFFER_SIZE: usize = 8192;
+// Hex digits of the key hash, enough to tell keys apart in a log
+const KEY_HASH_LEN: usize = 16;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord)]
+pub enum LogLevel {
+    Error,
+    Warn,
+    #[default]
+    Info,
+    Debug,
+    Trace,
+}
+
+impl FromStr for LogLevel {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "error" => Ok(LogLevel::Error),
+            "warn" | "warning" => Ok(LogLevel::Warn),
+            "info" => Ok(LogLevel::Info),
+            "debug" => Ok(LogLevel::Debug),
+            "trace" => Ok(LogLevel::Trace),
+            other => Err(format!("unknown log level '{}', expected error, warn, info, debug or trace", other)),
+        }
+    }
+}
+
+impl Display for LogLevel {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            LogLevel::Error => "error",
+            LogLevel::Warn => "warn",
+            LogLevel::Info => "info",
+            LogLevel::Debug => "debug",
+            LogLevel::Trace => "trace",
+        })
+    }
+}
+
+// Where records go: the buffered log file, stderr, or both
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Sinks {
+    pub file: bool,
+    pub stderr: bool,
+}
+
+impl Default for Sinks {
+    fn default() -> Self {
+        Sinks { file: true, stderr: false }
+    }
+}
+
+impl FromStr for Sinks {
+    type Err = String;
+
+    // Comma separated sink names, e.g. "file,stderr", or "none"
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut sinks = Sinks { file: false, stderr: false };
+        for name in s.split(',').map(|name| name.trim().to_ascii_lowercase()) {
+            match name.as_str() {
+                "file" => sinks.file = true,
+                "stderr" => sinks.stderr = true,
+                "none" => {}
+                other => return Err(format!("unknown log sink '{}', expected file or stderr", other)),
+            }
+        }
+        Ok(sinks)
+    }
+}
+
+// When the log file is rotated and how many rotated files are kept
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Rotation {
+    // 0 turns size based rotation off
+    pub max_bytes: u64,
+    pub max_age: Option<Duration>,
+    pub keep: usize,
+    pub compression: Compression,
+}
+
+impl Default for Rotation {
+    fn default() -> Self {
+        Rotation { max_bytes: 64 * 1024 * 1024, max_age: None, keep: 5, compression: Compression::None }
+    }
+}
+
+impl Rotation {
+    // Whether a file of `size` bytes opened `age` ago is rotated before `pending` more bytes are written
+    fn is_due(&self, size: u64, pending: usize, age: Duration) -> bool {
+        size > 0 && ((self.max_bytes > 0 && size + pending as u64 > self.max_bytes) || self.max_age.is_some_and(|max| age >= max))
+    }
+
+    // The n-th rotated file, 1 being the newest
+    fn rotated(path: &Path, index: usize, compression: Compression) -> PathBuf {
+        let mut name = path.file_name().map(|name| name.to_os_string()).unwrap_or_default();
+        name.push(format!(".{}", index));
+        if let Some(extension) = compression.extension() {
+            name.push(format!(".{}", extension));
+        }
+        path.with_file_name(name)
+    }
+
+    // Rename the file out of the way. Returns the rotated file if it still has to be compressed.
+    fn rotate(&self, path: &Path) -> io::Result<Option<PathBuf>> {
+        // Shift older files up, dropping the last. Files from before a compression change are shifted too.
+        for index in (1..=self.keep).rev() {
+            for compression in [Compression::None, Compression::Lz4, Compression::Zstd] {
+                let from = Self::rotated(path, index, compression);
+                if !from.exists() {
+                    continue;
+                }
+                if index == self.keep {
+                    fs::remove_file(&from)?;
+                } else {
+                    fs::rename(&from, Self::rotated(path, index + 1, compression))?;
+                }
+            }
+        }
+
+        if self.keep == 0 {
+            fs::remove_file(path)?;
+            return Ok(None);
+        }
+        let newest = Self::rotated(path, 1, Compression::None);
+        fs::rename(path, &newest)?;
+        Ok((self.compression != Compression::None).then_some(newest))
+    }
+
+    // Replace the newest rotated file of `path` with its compressed form
+    fn compress(&self, path: &Path, newest: &Path) -> io::Result<()> {
+        let compressed = self.compression.compress(&fs::read(newest)?)?;
+        snapshot::write_atomic(&Self::rotated(path, 1, self.compression), &compressed)?;
+        fs::remove_file(newest)
+    }
+}
+
+#[derive(Debug)]
+struct FileState {
+    bytes: Vec<u8>,
+    // Size and creation time of the file on disk, for rotation
+    size: u64,
+    opened: SystemTime,
+}
+
+// Pending lines of the log file, written out when full, on flush and when the last logger is dropped
+#[derive(Debug)]
+struct FileBuffer {
+    path: RwLock<PathBuf>,
+    rotation: Rotation,
+    state: Mutex<FileState>,
+    // Compression of the last rotated file, if it may still be running
+    compressing: Mutex<Option<JoinHandle<()>>>,
+}
+
+impl FileBuffer {
+    fn write_out(&self, state: &mut FileState) -> io::Result<()> {
+        if state.bytes.is_empty() {
+            return Ok(());
+        }
+        let path = self.path.read().unwrap();
+
+        let age = state.opened.elapsed().unwrap_or_default();
+        if self.rotation.is_due(state.size, state.bytes.len(), age) {
+            // Rotated files are not shifted while one of them is being compressed
+            self.wait_for_compression();
+
+            // If rotating fails keep logging into the current file, and retry once it has grown or aged again
+            match self.rotation.rotate(&path) {
+                Ok(Some(newest)) => {
+                    let (rotation, path) = (self.rotation, path.clone());
+                    *self.compressing.lock().unwrap() = Some(thread::spawn(move || {
+                        if let Err(e) = rotation.compress(&path, &newest) {
+                            eprintln!("Error compressing log {}: {}", newest.display(), e);
+                        }
+                    }));
+                }
+                Ok(None) => {}
+                Err(e) => eprintln!("Error rotating log {}: {}", path.display(), e),
+            }
+            state.size = 0;
+            state.opened = SystemTime::now();
+        }
+
+        let mut file = OpenOptions::new().append(true).create(true).open(&*path)?;
+        file.write_all(&state.bytes)?;
+        state.size += state.bytes.len() as u64;
+        state.bytes.clear();
+        Ok(())
+    }
+
+    fn wait_for_compression(&self) {
+        let handle = self.compressing.lock().unwrap().take();
+        if let Some(handle) = handle {
+            handle.join().ok();
+        }
+    }
+}
+
+impl Drop for FileBuffer {
+    fn drop(&mut self) {
+        if let Ok(mut state) = self.state.lock() {
+            if let Err(e) = self.write_out(&mut state) {
+                eprintln!("Error flushing log: {}", e);
+            }
+        }
+        self.wait_for_compression();
+    }
+}
 
 #[derive(Clone, Debug)]
 pub struct Logger {
-    pub log_path: Arc<RwLock<PathBuf>>,
-    buffer: Arc<Mutex<Vec<u8>>>,
-    out: bool,
+    file: Arc<FileBuffer>,
+    level: LogLevel,
+    sinks: Sinks,
     buffer_size: usize,
 }
 
 impl Logger {
-    pub fn new(log_path: &str, out: bool) -> Self {
+    pub fn new(log_path: &str, level: LogLevel, sinks: Sinks) -> Self {
+        Self::with_rotation(log_path, level, sinks, Rotation::default())
+    }
+
+    pub fn with_rotation(log_path: &str, level: LogLevel, sinks: Sinks, rotation: Rotation) -> Self {
         let path = Path::new(log_path);
-        let log_path = Arc::new(RwLock::new(path.to_path_buf()));
-        
+
         // Initialize the file if it doesn't exist
-        if !path.exists() {
+        if sinks.file && !path.exists() {
             if let Some(parent) = path.parent() {
                 fs::create_dir_all(parent).ok();
             }
             fs::write(path, b"").ok();
         }
-        
-        // Create buffer with optimized initial capacity
-        let buffer = Arc::new(Mutex::new(Vec::with_capacity(8192)));
-        
+
+        // An existing file keeps aging from when it was created
+        let metadata = fs::metadata(path).ok();
+        let state = FileState {
+            bytes: Vec::with_capacity(BUFFER_SIZE),
+            size: metadata.as_ref().map_or(0, |meta| meta.len()),
+            opened: metadata.and_then(|meta| meta.created().ok()).unwrap_or_else(SystemTime::now),
+        };
+
         Logger {
-            log_path,
-            buffer,
-            out,
-            buffer_size: 8192, // 8KB buffer
+            file: Arc::new(FileBuffer {
+                path: RwLock::new(path.to_path_buf()),
+                rotation,
+                state: Mutex::new(state),
+                compressing: Mutex::new(None),
+            }),
+            level,
+            sinks,
+            buffer_size: BUFFER_SIZE,
         }
     }
-    
-    // Flush the log buffer to disk, improving I/O efficiency
-    pub fn flush(&self) -> Result<(), Box<dyn std::error::Error>> {
-        let mut buffer = self.buffer.lock().unwrap();
-        if buffer.is_empty() {
-            return Ok(());
-        }
-        
-        let path = self.log_path.read().unwrap();
-        let mut file = OpenOptions::new()
-            .append(true)
-            .create(true)
-            .open(&*path)?;
-        
-        file.write_all(&buffer)?;
-        buffer.clear();
-        
-        Ok(())
+
+    pub fn level(&self) -> LogLevel {
+        self.level
     }
-}
 
-pub trait Log<T>
-where T: Display
-{
-    fn write_log(&mut self, input: T) -> Result<(), Box<dyn std::error::Error>>;
-}
-
-impl<T> Log<T> for Logger
-where
-    T: Display + fmt::Write + std::marker::Send + std::marker::Sync,
-{
-    // Optimized to use a buffer and minimize file I/O
-    fn write_log(&mut self, input: T) -> Result<(), Box<dyn std::error::Error>> 
-        where T: std::fmt::Display + Send,
-    {
-        let input_string = input.to_string();
-        
-        // Use Cow for zero-copy when possible
-        let log_entry = format!("\n\r[LOG@{}]{}", Utc::now(), input_string);
-        
-        // Add to buffer
-        {
-            let mut buffer = self.buffer.lock().unwrap();
-            buffer.extend_from_slice(log_entry.as_bytes());
-            
+    // Whether records at `level` are written; lets callers skip building expensive messages
+    pub fn enabled(&self, level: LogLevel) -> bool {
+        level <= self.level && (self.sinks.file || self.sinks.stderr)
+    }
+
+    pub fn log(&self, level: LogLevel, component: &str, message: impl Display) {
+        self.log_with(level, component, message, &[]);
+    }
+
+    pub fn log_with(&self, level: LogLevel, component: &str, message: impl Display, fields: &[(&str, Value)]) {
+        if !self.enabled(level) {
+            return;
+        }
+        let line = record(level, component, &message.to_string(), fields);
+
+        if self.sinks.stderr {
+            eprint!("{}", line);
+        }
+        if self.sinks.file {
+            let mut state = self.file.state.lock().unwrap();
+            state.bytes.extend_from_slice(line.as_bytes());
+
             // Flush if buffer exceeds threshold
-            if buffer.len() >= self.buffer_size {
-                let path = self.log_path.read().unwrap();
-                let mut file = OpenOptions::new()
-                    .append(true)
-                    .create(true)
-                    .open(&*path)?;
-                file.write_all(&buffer)?;
-                buffer.clear();
+            if state.bytes.len() >= self.buffer_size {
+                if let Err(e) = self.file.write_out(&mut state) {
+                    eprintln!("Error writing log: {}", e);
+                }
             }
         }
-        
-        if self.out {
-            println!("{}", input_string);
-        }
-        
+    }
+
+    // Flush the log buffer to disk, improving I/O efficiency
+    pub fn flush(&self) -> Result<(), Box<dyn std::error::Error>> {
+        self.file.write_out(&mut self.file.state.lock().unwrap())?;
+        // A rotated file is complete once it is compressed, so wait for that outside the buffer lock
+        self.file.wait_for_compression();
         Ok(())
     }
 }
 
-impl<T> Log<T> for Cache
-where
-    T: Display + fmt::Write + std::marker::Send + std::marker::Sync,
-{
-    fn write_log(&mut self, input: T) -> Result<(), Box<dyn std::error::Error>> 
-        where T: std::fmt::Display + Send,
-    {
-        let input_clone = input.to_string();
-        
-        if let Some(logger) = &mut self.logger {
-            logger.write_log(input_clone)?;
-        } else {
-            // Fallback if logger not initialized
-            if self.level == crate::LogLevel::DEBUG {
-                println!("[LOG] {}", input_clone);
-            }
-        }
-        
-        Ok(())
+// One JSON line
+fn record(level: LogLevel, component: &str, message: &str, fields: &[(&str, Value)]) -> String {
+    let mut line = format!(
+        "{{\"ts\":\"{}\",\"level\":\"{}\",\"component\":{},\"msg\":{}",
+        Utc::now().to_rfc3339_opts(SecondsFormat::Millis, true),
+        level,
+        Value::from(component),
+        Value::from(message)
+    );
+    for (name, value) in fields {
+        let _ = write!(line, ",{}:{}", Value::from(*name), value);
     }
+    line.push_str("}\n");
+    line
+}
+
+// Stable short hash of a key, so records about the same key can be matched without logging it
+pub fn key_hash(key: &[u8]) -> String {
+    let digest = Sha256::digest(key);
+    let mut hash = String::with_capacity(KEY_HASH_LEN);
+    for byte in &digest[..KEY_HASH_LEN / 2] {
+        let _ = write!(hash, "{:02x}", byte);
+    }
+    hash
 }
 
 #[cfg(test)]
@@ -130,47 +353,157 @@ mod tests {
     fn test_write_log_success() {
         let temp_file = NamedTempFile::new().expect("Failed to create temp file");
         let path = temp_file.path().to_str().unwrap().to_string();
-        let mut logger = Logger::new(&path, false);
-        
-        let input = "Test message".to_string();
-        
-        // Call write_log
-        let result = logger.write_log(input);
+        let logger = Logger::new(&path, LogLevel::Info, Sinks::default());
+
+        logger.log_with(LogLevel::Info, "test", "Test message", &[("duration_ms", Value::from(3))]);
         logger.flush().unwrap();
-        
-        assert!(result.is_ok(), "write_log failed: {:?}", result.err());
-        
+
         // Read the file contents
         let contents = fs::read_to_string(temp_file.path()).expect("Failed to read temp file");
-        assert!(contents.contains("Test message"));
+        let record: Value = serde_json::from_str(contents.lines().next().unwrap()).unwrap();
+        assert_eq!(record["level"], "info");
+        assert_eq!(record["component"], "test");
+        assert_eq!(record["msg"], "Test message");
+        assert_eq!(record["duration_ms"], 3);
+        assert!(record["ts"].as_str().unwrap().ends_with('Z'));
     }
 
     #[test]
     fn test_write_log_buffering() {
         let temp_file = NamedTempFile::new().expect("Failed to create temp file");
         let path = temp_file.path().to_str().unwrap().to_string();
-        let mut logger = Logger::new(&path, false);
-        
+        let mut logger = Logger::new(&path, LogLevel::Info, Sinks::default());
+
         // Set small buffer size for testing
         logger.buffer_size = 100;
-        
+
         // Write multiple messages
         for i in 0..20 {
-            let input = format!("Message {}", i);
-            logger.write_log(input).expect("Write failed");
+            logger.log(LogLevel::Info, "test", format!("Message {}", i));
         }
-        
+
         // Flush remaining messages
         logger.flush().unwrap();
-        
+
         // Read the file contents
         let contents = fs::read_to_string(temp_file.path()).expect("Failed to read temp file");
-        
+
         // Verify all messages were written
         for i in 0..20 {
-            assert!(contents.contains(&format!("Message {}", i)));
+            assert!(contents.contains(&format!("Message {}\"", i)));
         }
     }
-}
 
+    #[test]
+    fn test_levels_filter_records() {
+        let temp_file = NamedTempFile::new().expect("Failed to create temp file");
+        let logger = Logger::new(temp_file.path().to_str().unwrap(), LogLevel::Warn, Sinks::default());
+
+        logger.log(LogLevel::Error, "test", "kept error");
+        logger.log(LogLevel::Warn, "test", "kept warning");
+        logger.log(LogLevel::Info, "test", "dropped info");
+        logger.log(LogLevel::Trace, "test", "dropped trace");
+        assert!(!logger.enabled(LogLevel::Debug));
+        logger.flush().unwrap();
+
+        let contents = fs::read_to_string(temp_file.path()).unwrap();
+        assert_eq!(contents.lines().count(), 2);
+        assert!(!contents.contains("dropped"));
+    }
+
+    #[test]
+    fn test_dropping_last_logger_flushes() {
+        let temp_file = NamedTempFile::new().expect("Failed to create temp file");
+        let logger = Logger::new(temp_file.path().to_str().unwrap(), LogLevel::Info, Sinks::default());
+        let clone = logger.clone();
+
+        logger.log(LogLevel::Info, "test", "last words");
+        drop(logger);
+        assert_eq!(fs::read_to_string(temp_file.path()).unwrap(), "");
+        drop(clone);
+        assert!(fs::read_to_string(temp_file.path()).unwrap().contains("last words"));
+    }
+
+    #[test]
+    fn test_parse_levels_and_sinks() {
+        assert_eq!("WARN".parse(), Ok(LogLevel::Warn));
+        assert!("verbose".parse::<LogLevel>().is_err());
+        assert!(LogLevel::Error < LogLevel::Trace);
+        assert_eq!("file, stderr".parse(), Ok(Sinks { file: true, stderr: true }));
+        assert_eq!("stderr".parse(), Ok(Sinks { file: false, stderr: true }));
+        assert!("syslog".parse::<Sinks>().is_err());
+    }
+
+    #[test]
+    fn test_rotation_by_size() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let path = dir.path().join("log.log");
+        let rotation = Rotation { max_bytes: 300, keep: 2, ..Rotation::default() };
+        let logger = Logger::with_rotation(path.to_str().unwrap(), LogLevel::Info, Sinks::default(), rotation);
+
+        // Each flush writes one record of roughly 120 bytes
+        for i in 0..10 {
+            logger.log(LogLevel::Info, "test", format!("Message {}", i));
+            logger.flush().unwrap();
+        }
+
+        let current = fs::read_to_string(&path).unwrap();
+        let newest = fs::read_to_string(dir.path().join("log.log.1")).unwrap();
+        let oldest = fs::read_to_string(dir.path().join("log.log.2")).unwrap();
+        assert!(!dir.path().join("log.log.3").exists());
+        assert!(current.contains("Message 9") && current.len() <= 300);
+        assert!(newest.contains("Message 7") && newest.len() <= 300);
+        assert!(oldest.contains("Message 5"));
+        assert!(!oldest.contains("Message 0"));
+    }
 
+    #[test]
+    fn test_rotation_by_age_with_compression() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let path = dir.path().join("log.log");
+        let rotation = Rotation { max_bytes: 0, max_age: Some(Duration::ZERO), keep: 3, compression: Compression::Lz4 };
+        let logger = Logger::with_rotation(path.to_str().unwrap(), LogLevel::Info, Sinks::default(), rotation);
+
+        logger.log(LogLevel::Info, "test", "first file");
+        logger.flush().unwrap();
+        logger.log(LogLevel::Info, "test", "second file");
+        logger.flush().unwrap();
+
+        let compressed = fs::read(dir.path().join("log.log.1.lz4")).unwrap();
+        let mut rotated = String::new();
+        io::Read::read_to_string(&mut lz4_flex::frame::FrameDecoder::new(&compressed[..]), &mut rotated).unwrap();
+        assert!(rotated.contains("first file"));
+        assert!(!dir.path().join("log.log.1").exists());
+        assert!(fs::read_to_string(&path).unwrap().contains("second file"));
+    }
+
+    #[test]
+    fn test_back_to_back_rotations_keep_compressed_files() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let path = dir.path().join("log.log");
+        let rotation = Rotation { max_bytes: 0, max_age: Some(Duration::ZERO), keep: 3, compression: Compression::Zstd };
+        let logger = Logger::with_rotation(path.to_str().unwrap(), LogLevel::Info, Sinks::default(), rotation);
+
+        // Written out without flush, so only the next rotation and the drop wait for compression
+        for i in 0..3 {
+            logger.log(LogLevel::Info, "test", format!("file {}", i));
+            logger.file.write_out(&mut logger.file.state.lock().unwrap()).unwrap();
+        }
+        drop(logger);
+
+        for (index, expected) in [(1, "file 1"), (2, "file 0")] {
+            let compressed = fs::read(dir.path().join(format!("log.log.{}.zst", index))).unwrap();
+            let rotated = String::from_utf8(zstd::decode_all(&compressed[..]).unwrap()).unwrap();
+            assert!(rotated.contains(expected));
+            assert!(!dir.path().join(format!("log.log.{}", index)).exists());
+        }
+        assert!(fs::read_to_string(&path).unwrap().contains("file 2"));
+    }
+
+    #[test]
+    fn test_key_hash() {
+        assert_eq!(key_hash(b"user:1").len(), KEY_HASH_LEN);
+        assert_eq!(key_hash(b"user:1"), key_hash(b"user:1"));
+        assert_ne!(key_hash(b"user:1"), key_hash(b"user:2"));
+    }
+}
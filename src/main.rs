@@ -1,387 +1,166 @@
-use std::{io::{Read, Write}, path::{Path, PathBuf}, sync::{atomic::{AtomicBool, AtomicUsize, Ordering}, Arc, Mutex, RwLock}};
-use chrono::{DateTime, TimeDelta, Utc};
-use dashmap::DashMap;
-use hashbrown;
-use logger::{Log, Logger};
+use std::{path::PathBuf, sync::{Arc, Mutex}};
+use chrono::Utc;
+use cacherebbok::{cli, config::Config, export, generations, shutdown::ShutdownSignal, storage, tasks, Cache, LogLevel};
 
-/*
-    Cache format:
-    - First byte: command (G=get, I=insert, R=remove, H=halt)
-    - Next 63 bytes: key
-    - Next 56 bytes: value
-    - Last 8 bytes:
-      - First 6 bytes: timestamp (epoch seconds)
-      - Last 2 bytes: expiration time in seconds
- */
-
-pub mod logger;
-pub mod buffer;
-pub mod tasks;
-pub mod utils;
-
-// Thread pool for background tasks
-const THREAD_POOL_SIZE: usize = 4;
-const DEFAULT_INVALIDATION_THRESHOLD: usize = 100;
-const PERSISTENCE_INTERVAL_SECS: u64 = 10; // More frequent persistence
-
-#[derive(Clone, Copy, PartialEq)]
-pub enum LogLevel {
-    NORMAL,
-    DEBUG,
-}
-
-// Cache entry with metadata for more efficient expiration handling
-#[derive(Clone, Debug)]
-pub struct CacheEntry {
-    pub value: [u8; 56],
-    pub created_at: DateTime<Utc>,
-    pub expires_at: Option<DateTime<Utc>>,
-}
-
-pub struct Cache {
-    cur_buf: Arc<Mutex<[u8; 128]>>,
-    log_path: Arc<RwLock<PathBuf>>,
-    // Using RwLock instead of Mutex for better read concurrency
-    vals: Arc<RwLock<hashbrown::HashMap<[u8;63], [u8;64]>>>,
-    // Using DashMap for highly concurrent access patterns
-    entries: Arc<DashMap<[u8;63], CacheEntry>>,
-    should_exit: Arc<AtomicBool>,
-    level: LogLevel,
-    logger: Option<Logger>,
-    // Track operations since last invalidation for batched invalidation
-    ops_since_invalidation: Arc<AtomicUsize>,
-    invalidation_threshold: usize,
-    // Thread pool for background tasks
-    thread_pool: Arc<threadpool::ThreadPool>,
-    // Flag to indicate when to save
-    pub save_flag: Arc<AtomicBool>,
-    // Data directory
-    data_dir: PathBuf,
+fn handle_close(shutdown: &ShutdownSignal) {
+    // Stdout carries replies, so this goes to stderr
+    eprintln!("Received signal! Cleaning up...");
+    
+    // The main loop drains input, saves and joins threads; the watchdog bounds how long that may take
+    shutdown.request();
+    shutdown.arm_watchdog();
 }
 
-impl Cache {
-    pub fn new(log_path: &str, level: LogLevel) -> Self {
-        // Create a single, reusable buffer
-        let cur_buf = Arc::new(Mutex::new([0u8; 128]));
-        
-        // Use Path API properly
-        let path = Path::new(log_path);
-        let log_path = Arc::new(RwLock::new(path.to_path_buf()));
-        
-        // Create logger
-        let logger = Some(Logger::new(log_path.as_ref().read().unwrap().to_str().unwrap(), level == LogLevel::DEBUG));
-        
-        // Create thread pool
-        let thread_pool = Arc::new(threadpool::ThreadPool::new(THREAD_POOL_SIZE));
-        
-        // Set up data directory
-        let data_dir = std::env::current_dir().unwrap_or_else(|_| PathBuf::from(".")).join("data");
-        
-        // Create directory if it doesn't exist
-        std::fs::create_dir_all(&data_dir).ok();
-        
-        Cache {
-            cur_buf,
-            log_path,
-            vals: Arc::new(RwLock::new(hashbrown::HashMap::with_capacity(10000))),
-            entries: Arc::new(DashMap::with_capacity(10000)),
-            should_exit: Arc::new(AtomicBool::new(false)),
-            level,
-            logger,
-            ops_since_invalidation: Arc::new(AtomicUsize::new(0)),
-            invalidation_threshold: DEFAULT_INVALIDATION_THRESHOLD,
-            thread_pool,
-            save_flag: Arc::new(AtomicBool::new(false)),
-            data_dir,
+fn main() {
+    let args = match cli::parse(std::env::args().skip(1)) {
+        Ok(args) => args,
+        Err(e) => {
+            eprintln!("{}\n\n{}", e, cli::USAGE);
+            std::process::exit(2);
         }
-    }
+    };
     
-    pub fn log_debug(&mut self, log: String) {
-        if self.level == LogLevel::DEBUG {
-            let _ = self.write_log(log);
-        }
+    if args.help {
+        println!("{}", cli::USAGE);
+        return;
     }
     
-    // Optimized invalidation that runs in the background
-    pub fn invalidate_cache(&mut self) -> Result<(), Box<dyn std::error::Error>> {
-        let entries = Arc::clone(&self.entries);
-        let vals = Arc::clone(&self.vals);
-        let level = self.level;
-        
-        // Use the thread pool for background invalidation
-        let logger_clone = self.logger.clone();
-        
-        self.thread_pool.execute(move || {
-            let mut keys_to_remove = Vec::new();
-            let now = Utc::now();
-            
-            // Efficient iteration with DashMap
-            for entry in entries.iter() {
-                let key = entry.key();
-                let cache_entry = entry.value();
-                
-                if let Some(expires_at) = cache_entry.expires_at {
-                    if expires_at <= now {
-                        keys_to_remove.push(*key);
-                        
-                        if level == LogLevel::DEBUG {
-                            if let Some(mut logger) = logger_clone.clone() {
-                                let _ = logger.write_log(format!(
-                                    "REMOVED KEY DUE TO EXPIRATION: {:?}, EXPIRED AT: {}",
-                                    key, expires_at
-                                ));
-                            }
-                        }
-                    }
-                }
-            }
-            
-            // Remove expired entries
-            for key in keys_to_remove {
-                entries.remove(&key);
-                vals.write().unwrap().remove(&key);
-            }
-        });
-        
-        Ok(())
-    }
-    
-    // Load cache from disk
-    pub fn load(&mut self) -> Result<(), Box<dyn std::error::Error>> {
-        self.log_debug("LOADING CACHE FROM DISK".to_owned());
-        
-        let cache_path = self.data_dir.join("cache.json");
-        if !cache_path.exists() {
-            self.log_debug("No cache file found, starting with empty cache".to_owned());
-            return Ok(());
+    let config = match Config::from_env() {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("Invalid configuration: {}", e);
+            std::process::exit(1);
         }
-        
-        let mut file = std::fs::File::open(&cache_path)?;
-        let mut buf = Vec::with_capacity(1024 * 1024); // Pre-allocate 1MB
-        
-        let bytes_read = file.read_to_end(&mut buf)?;
-        self.log_debug(format!("Read {} bytes from cache file", bytes_read));
-        
-        if buf.is_empty() {
-            self.log_debug("Cache file is empty".to_owned());
-            return Ok(());
-        }
-        
-        // Process the data in chunks of 127 bytes (key+value)
-        let mut vals = hashbrown::HashMap::with_capacity(bytes_read / 127);
-        let entries = DashMap::with_capacity(bytes_read / 127);
-        
-        for chunk in buf.chunks_exact(127) {
-            if chunk.len() == 127 {
-                let mut key = [0u8; 63];
-                let mut value = [0u8; 64];
-                
-                key.copy_from_slice(&chunk[0..63]);
-                value.copy_from_slice(&chunk[63..127]);
-                
-                // Skip empty keys
-                if key.iter().all(|&b| b == 0) {
-                    continue;
-                }
-                
-                // Extract timestamp and expiration
-                let mut timestamp_bytes = [0u8; 8];
-                timestamp_bytes[2..8].copy_from_slice(&value[56..62]);
-                let timestamp = i64::from_be_bytes(timestamp_bytes);
-                
-                let mut expiry_bytes = [0u8; 2];
-                expiry_bytes.copy_from_slice(&value[62..64]);
-                let expiry_seconds = u16::from_be_bytes(expiry_bytes);
-                
-                // Create cache entry
-                let created_at = DateTime::<Utc>::from_timestamp(timestamp, 0)
-                    .unwrap_or_else(|| Utc::now());
-                
-                let expires_at = if expiry_seconds > 0 {
-                    Some(created_at + TimeDelta::try_seconds(expiry_seconds as i64).unwrap_or_default())
-                } else {
-                    None
-                };
-                
-                let mut value_only = [0u8; 56];
-                value_only.copy_from_slice(&value[0..56]);
-                
-                let entry = CacheEntry {
-                    value: value_only,
-                    created_at,
-                    expires_at,
-                };
-                
-                // Don't load expired entries
-                if let Some(expires) = expires_at {
-                    if expires <= Utc::now() {
-                        self.log_debug(format!("Skipping expired entry from disk"));
-                        continue;
-                    }
-                }
-                
-                vals.insert(key, value);
-                entries.insert(key, entry);
-            }
-        }
-        
-        self.log_debug(format!("Loaded {} entries into cache", vals.len()));
-        
-        // Update the cache
-        *self.vals.write().unwrap() = vals;
-        self.entries = Arc::new(entries);
-        
-        // Run initial invalidation to clean up any expired entries
-        self.invalidate_cache()?;
-        
-        Ok(())
-    }
+    };
     
-    // Save cache to disk
-    pub fn clean_up(&mut self) -> Result<(), Box<dyn std::error::Error>> {
-        self.log_debug("SAVING CACHE TO DISK".to_owned());
-        
-        // Ensure the data directory exists
-        if !self.data_dir.exists() {
-            std::fs::create_dir_all(&self.data_dir)?;
-        }
-        
-        let cache_path = self.data_dir.join("cache.json");
-        let mut file = std::fs::File::create(&cache_path)?;
-        
-        // Serialize the cache efficiently
-        let kv = self.vals.read().unwrap();
-        let len = kv.len();
-        
-        // Pre-allocate buffer with exact size needed
-        let mut buffer = Vec::with_capacity(len * 127);
-        
-        for (key, value) in kv.iter() {
-            // Don't persist empty keys
-            if key.iter().all(|&b| b == 0) {
-                continue;
-            }
-            
-            // Check if entry is expired before persisting
-            let mut timestamp_bytes = [0u8; 8];
-            timestamp_bytes[2..8].copy_from_slice(&value[56..62]);
-            let timestamp = i64::from_be_bytes(timestamp_bytes);
-            
-            let mut expiry_bytes = [0u8; 2];
-            expiry_bytes.copy_from_slice(&value[62..64]);
-            let expiry_seconds = u16::from_be_bytes(expiry_bytes);
-            
-            if expiry_seconds > 0 {
-                let created_at = DateTime::<Utc>::from_timestamp(timestamp, 0)
-                    .unwrap_or_else(|| Utc::now());
-                
-                let expires_at = created_at + TimeDelta::try_seconds(expiry_seconds as i64).unwrap_or_default();
-                
-                // Skip if expired
-                if expires_at <= Utc::now() {
-                    continue;
+    if args.list_generations {
+        match generations::list(&config.data_dir) {
+            Ok(generations) => {
+                for generation in generations {
+                    println!("{}\t{}", generation.name(), generation.created_at.to_rfc3339());
                 }
             }
-            
-            buffer.extend_from_slice(key);
-            buffer.extend_from_slice(value);
+            Err(e) => {
+                eprintln!("Error listing generations: {}", e);
+                std::process::exit(1);
+            }
         }
-        
-        let bytes_written = file.write(&buffer)?;
-        
-        // Flush to ensure data is written
-        file.flush()?;
-        
-        // Reset save flag
-        self.save_flag.store(false, Ordering::SeqCst);
-        drop(kv);
-
-        self.log_debug(format!("Wrote {} bytes to cache file", bytes_written));
-
-        Ok(())
+        return;
     }
-}
-
-fn handle_close(cache: Arc<Mutex<Cache>>) {
-    println!("Received signal! Cleaning up...");
     
-    // Get a lock on the cache
-    if let Ok(mut cache) = cache.lock() {
-        cache.log_debug("HANDLING SHUTDOWN".to_string());
-        
-        // Save cache to disk
-        if let Err(e) = cache.clean_up() {
-            eprintln!("Error during cleanup: {}", e);
-        }
-        
-        // Set the exit flag
-        cache.should_exit.store(true, Ordering::SeqCst);
-        
-        cache.log_debug(format!("EXIT AT: {}", Utc::now()));
-    }
-}
-
-#[cfg(not(test))]
-fn main() {
     // Initialize the cache
     let log_dir = std::env::current_dir()
         .unwrap_or_else(|_| PathBuf::from("."))
         .join("log/log.log");
     
     let log_path = log_dir.to_str().unwrap_or("./log/log.log");
-    let mut cache = Cache::new(log_path, LogLevel::DEBUG);
     
-    // Load existing cache data
+    std::fs::create_dir_all(&config.data_dir).ok();
+    let storage = match storage::open(&config) {
+        Ok(storage) => storage,
+        Err(e) => {
+            eprintln!("Error opening {} storage: {}", config.storage, e);
+            std::process::exit(1);
+        }
+    };
+    
+    let level = config.log_level;
+    if let Some(command) = &args.command {
+        if let Err(e) = run_command(command, Cache::with_storage(log_path, level, config, storage)) {
+            eprintln!("Error: {}", e);
+            std::process::exit(1);
+        }
+        return;
+    }
+    
+    let mut cache = Cache::with_storage(log_path, level, config, storage);
+    
+    // Load existing cache data, or a past generation when restoring
     let init_time = Utc::now();
-    if let Err(e) = cache.load() {
+    let loaded = match &args.restore {
+        Some(target) => cache.restore(target).map(|generation| {
+            eprintln!("Restored snapshot generation {}", generation.name());
+        }),
+        None => cache.load(),
+    };
+    if let Err(e) = loaded {
+        // Starting empty would overwrite the unreadable snapshot on the next save
         eprintln!("Error loading cache: {}", e);
+        cache.log(LogLevel::Error, "load", format!("loading failed: {}", e));
+        // exit skips destructors, so the buffered log lines are written here
+        let _ = cache.flush_log();
+        std::process::exit(1);
     }
     
     let final_time = Utc::now();
     let time_delta = final_time - init_time;
     
-    cache.log_debug(format!("Cache initialization took {} ms", time_delta.num_milliseconds()));
-    cache.log_debug(format!("START AT: {}", Utc::now()));
+    cache.log_with(LogLevel::Info, "startup", "cache initialized", &[("duration_ms", serde_json::json!(time_delta.num_milliseconds()))]);
+    
+    // Set up signal handlers for proper cleanup
+    setup_signal_handlers(cache.shutdown_signal());
     
     // Wrap the cache in an Arc<Mutex<>> for thread-safe access
     let cache = Arc::new(Mutex::new(cache));
     
-    // Set up signal handlers for proper cleanup
-    setup_signal_handlers(Arc::clone(&cache));
-    
-    // Run the main task loop
+    // Run the main task loop; it returns once shutdown has completed
     if let Err(e) = tasks::run_tasks(&cache) {
         eprintln!("Error in main task loop: {}", e);
     }
 }
 
+// Export or import the saved snapshot without starting the cache
+fn run_command(command: &cli::Command, mut cache: Cache) -> Result<(), Box<dyn std::error::Error>> {
+    use std::{fs::File, io::{self, BufReader, BufWriter}};
+    
+    cache.load()?;
+    
+    match command {
+        cli::Command::Export { format, encoding, path } => {
+            let records = export::records(&cache, *encoding)?;
+            match path {
+                Some(path) => export::write_records(&records, *format, BufWriter::new(File::create(path)?))?,
+                // Export runs instead of the protocol loop, so stdout is free for the records
+                #[allow(clippy::disallowed_methods)]
+                None => export::write_records(&records, *format, BufWriter::new(io::stdout().lock()))?,
+            }
+            eprintln!("Exported {} records", records.len());
+        }
+        cli::Command::Import { format, path } => {
+            let records = match path {
+                Some(path) => export::read_records(*format, BufReader::new(File::open(path)?))?,
+                None => export::read_records(*format, io::stdin().lock())?,
+            };
+            let imported = export::import_records(&mut cache, &records)?;
+            cache.final_save()?;
+            eprintln!("Imported {} of {} records", imported, records.len());
+        }
+    }
+    
+    Ok(())
+}
+
 #[cfg(windows)]
-fn setup_signal_handlers(cache: Arc<Mutex<Cache>>) {
-    let cache_for_cleanup = Arc::clone(&cache);
+fn setup_signal_handlers(shutdown: ShutdownSignal) {
     ctrlc::set_handler(move || {
-        handle_close(cache_for_cleanup.clone());
-        std::process::exit(0);
+        handle_close(&shutdown);
     }).expect("Error setting Ctrl-C handler");
 }
 
 #[cfg(unix)]
-fn setup_signal_handlers(cache: Arc<Mutex<Cache>>) {
+fn setup_signal_handlers(shutdown: ShutdownSignal) {
     use signal_hook::{consts::{SIGHUP, SIGINT, SIGTERM}, iterator::Signals};
     
-    let cache_for_cleanup = Arc::clone(&cache);
-    
     std::thread::spawn(move || {
-        let signals = Signals::new(&[SIGINT, SIGHUP, SIGTERM]).expect("Cannot set signal handlers");
+        let mut signals = Signals::new([SIGINT, SIGHUP, SIGTERM]).expect("Cannot set signal handlers");
         
         for signal in signals.forever() {
             match signal {
                 SIGINT | SIGHUP | SIGTERM => {
-                    handle_close(cache_for_cleanup.clone());
-                    std::process::exit(0);
+                    handle_close(&shutdown);
                 }
                 _ => {}
             }
         }
     });
 }
-
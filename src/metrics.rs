@@ -0,0 +1,225 @@
+use std::{fmt::Write as _, io::{self, Read, Write}, net::{TcpListener, TcpStream}, sync::{Arc, Mutex}, thread::JoinHandle, time::Duration};
+use crate::{latency::QUANTILES, shutdown::{ShutdownSignal, SHUTDOWN_POLL_INTERVAL}, stats::SNAPSHOT_BUCKETS_MS, Cache, LogLevel};
+
+/*
+    Prometheus metrics, served at GET /metrics when CACHE_METRICS_ADDR is set. Every other path is a 404.
+    Counters come from the same Stats the T command reads; scrapes take the cache lock only long
+    enough to read them.
+ */
+const PREFIX: &str = "cacherebbok";
+// Slow or idle scrapers are dropped instead of holding up the next one
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(2);
+const MAX_REQUEST_BYTES: usize = 8192;
+
+// Metrics in the Prometheus text exposition format
+pub fn render(cache: &Cache) -> io::Result<String> {
+    let info = cache.info()?;
+    let stats = cache.stats();
+    let evictions: u64 = cache.tier_stats().iter().map(|(_, tiers)| tiers.evictions).sum();
+    let mut out = String::new();
+
+    header(&mut out, "commands_total", "counter", "Commands received, by command byte.");
+    for (command, count) in &info.ops {
+        let _ = writeln!(out, "{}_commands_total{{command=\"{}\"}} {}", PREFIX, label(*command), count);
+    }
+    metric(&mut out, "hits_total", "counter", "G, V and M lookups that found the key.", info.hits);
+    metric(&mut out, "misses_total", "counter", "G, V and M lookups that did not find the key.", info.misses);
+    metric(&mut out, "evictions_total", "counter", "Entries spilled from the hot tier to the cold tier.", evictions);
+    metric(&mut out, "expired_keys_total", "counter", "Keys removed or skipped on load because their TTL ran out.", info.expired);
+    metric(&mut out, "keys", "gauge", "Keys across all namespaces.", info.keys);
+    header(&mut out, "namespace_keys", "gauge", "Keys per namespace.");
+    for namespace in &info.namespaces {
+        let _ = writeln!(out, "{}_namespace_keys{{namespace=\"{}\"}} {}", PREFIX, namespace.namespace, namespace.keys);
+    }
+    header(&mut out, "namespace_hits_total", "counter", "G, V and M lookups that found the key, per namespace.");
+    for namespace in &info.namespaces {
+        let _ = writeln!(out, "{}_namespace_hits_total{{namespace=\"{}\"}} {}", PREFIX, namespace.namespace, namespace.hits);
+    }
+    header(&mut out, "namespace_misses_total", "counter", "G, V and M lookups that did not find the key, per namespace.");
+    for namespace in &info.namespaces {
+        let _ = writeln!(out, "{}_namespace_misses_total{{namespace=\"{}\"}} {}", PREFIX, namespace.namespace, namespace.misses);
+    }
+    metric(&mut out, "memory_bytes", "gauge", "Estimated memory used by entries held in memory.", info.memory_bytes);
+    metric(&mut out, "loaded_entries", "gauge", "Entries in the cache when startup loading finished.", stats.loaded_entries());
+    metric(&mut out, "uptime_seconds", "gauge", "Seconds since the cache started.", info.uptime);
+
+    let (buckets, count, sum_ms) = stats.snapshot_histogram();
+    header(&mut out, "snapshot_duration_seconds", "histogram", "Time taken to write snapshots.");
+    for (bound, total) in SNAPSHOT_BUCKETS_MS.iter().zip(&buckets) {
+        let _ = writeln!(out, "{}_snapshot_duration_seconds_bucket{{le=\"{}\"}} {}", PREFIX, *bound as f64 / 1000.0, total);
+    }
+    let _ = writeln!(out, "{}_snapshot_duration_seconds_bucket{{le=\"+Inf\"}} {}", PREFIX, count);
+    let _ = writeln!(out, "{}_snapshot_duration_seconds_sum {}", PREFIX, sum_ms as f64 / 1000.0);
+    let _ = writeln!(out, "{}_snapshot_duration_seconds_count {}", PREFIX, count);
+    header(&mut out, "latency_seconds", "summary", "Latency of G, I and R commands, input batches and snapshots.");
+    for (operation, summary) in &info.latency {
+        for ((_, quantile), micros) in QUANTILES.iter().zip(summary.quantiles) {
+            let _ = writeln!(out, "{}_latency_seconds{{operation=\"{}\",quantile=\"{}\"}} {}", PREFIX, operation, quantile, micros as f64 / 1e6);
+        }
+        // Histograms keep no exact sum, the mean is within their precision
+        let _ = writeln!(out, "{}_latency_seconds_sum{{operation=\"{}\"}} {}", PREFIX, operation, summary.mean * summary.count as f64 / 1e6);
+        let _ = writeln!(out, "{}_latency_seconds_count{{operation=\"{}\"}} {}", PREFIX, operation, summary.count);
+    }
+    metric(&mut out, "snapshot_bytes", "gauge", "Size of the last snapshot written.", cache.save_status().last_bytes());
+    metric(&mut out, "last_save_timestamp_seconds", "gauge", "Epoch seconds of the last successful save.", info.last_save);
+    metric(&mut out, "save_in_progress", "gauge", "1 while a background save is running.", info.saving as u8);
+
+    Ok(out)
+}
+
+// Accept scrapes on a background thread until shutdown
+pub fn serve(listener: TcpListener, cache: Arc<Mutex<Cache>>, shutdown: ShutdownSignal) -> io::Result<JoinHandle<()>> {
+    // Non-blocking so the loop notices shutdown while nobody scrapes
+    listener.set_nonblocking(true)?;
+    let logger = cache.lock().map_err(|_| io::Error::other("cache lock poisoned"))?.logger().clone();
+
+    Ok(std::thread::spawn(move || {
+        while !shutdown.is_requested() {
+            match listener.accept() {
+                Ok((stream, _)) => {
+                    if let Err(e) = respond(stream, &cache) {
+                        logger.log(LogLevel::Warn, "metrics", format!("serving a scrape failed: {}", e));
+                    }
+                }
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => std::thread::sleep(SHUTDOWN_POLL_INTERVAL),
+                Err(e) => {
+                    logger.log(LogLevel::Warn, "metrics", format!("accepting a connection failed: {}", e));
+                    std::thread::sleep(SHUTDOWN_POLL_INTERVAL);
+                }
+            }
+        }
+    }))
+}
+
+fn respond(mut stream: TcpStream, cache: &Mutex<Cache>) -> io::Result<()> {
+    stream.set_nonblocking(false)?;
+    stream.set_read_timeout(Some(REQUEST_TIMEOUT))?;
+    stream.set_write_timeout(Some(REQUEST_TIMEOUT))?;
+
+    // Only the request line matters; read until the end of the headers
+    let mut request = Vec::new();
+    let mut chunk = [0u8; 1024];
+    while !request.windows(4).any(|window| window == b"\r\n\r\n") && request.len() < MAX_REQUEST_BYTES {
+        let read = stream.read(&mut chunk)?;
+        if read == 0 {
+            break;
+        }
+        request.extend_from_slice(&chunk[..read]);
+    }
+
+    let request_line = String::from_utf8_lossy(&request).lines().next().unwrap_or_default().to_string();
+    let mut parts = request_line.split_whitespace();
+    let (status, body) = match (parts.next(), parts.next()) {
+        (Some("GET"), Some("/metrics")) => {
+            let cache = cache.lock().map_err(|_| io::Error::other("cache lock poisoned"))?;
+            ("200 OK", render(&cache)?)
+        }
+        (Some("GET"), _) => ("404 Not Found", "not found\n".to_string()),
+        _ => ("405 Method Not Allowed", "method not allowed\n".to_string()),
+    };
+
+    write!(
+        stream,
+        "HTTP/1.1 {}\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        body.len(),
+        body
+    )?;
+    stream.flush()
+}
+
+fn header(out: &mut String, name: &str, kind: &str, help: &str) {
+    let _ = writeln!(out, "# HELP {}_{} {}", PREFIX, name, help);
+    let _ = writeln!(out, "# TYPE {}_{} {}", PREFIX, name, kind);
+}
+
+fn metric(out: &mut String, name: &str, kind: &str, help: &str, value: impl std::fmt::Display) {
+    header(out, name, kind, help);
+    let _ = writeln!(out, "{}_{} {}", PREFIX, name, value);
+}
+
+// Command byte as a label value, escaped as the text format requires
+fn label(command: u8) -> String {
+    match command {
+        b'"' => "\\\"".to_string(),
+        b'\\' => "\\\\".to_string(),
+        command if command.is_ascii_graphic() => (command as char).to_string(),
+        command => format!("0x{:02x}", command),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{buffer::BufferAccess, config::Config, LogLevel};
+
+    fn setup_cache(dir: &tempfile::TempDir) -> Cache {
+        let config = Config { data_dir: dir.path().to_path_buf(), ..Config::default() };
+        Cache::with_config(dir.path().join("log.log").to_str().unwrap(), LogLevel::Info, config)
+    }
+
+    fn frame(command: u8, key: u8) -> [u8; 128] {
+        let mut frame = [0u8; 128];
+        frame[0] = command;
+        frame[1] = key;
+        frame
+    }
+
+    #[test]
+    fn test_render() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let mut cache = setup_cache(&dir);
+        cache.handle_in(frame(b'I', b'a')).unwrap();
+        cache.handle_in(frame(b'G', b'a')).unwrap();
+        cache.handle_in(frame(b'G', b'b')).unwrap();
+        cache.clean_up().unwrap();
+
+        let text = render(&cache).unwrap();
+        assert!(text.contains("# TYPE cacherebbok_commands_total counter\n"));
+        assert!(text.contains("cacherebbok_commands_total{command=\"G\"} 2\n"));
+        assert!(text.contains("cacherebbok_hits_total 1\n"));
+        assert!(text.contains("cacherebbok_misses_total 1\n"));
+        assert!(text.contains("cacherebbok_keys 1\n"));
+        assert!(text.contains("cacherebbok_namespace_keys{namespace=\"0\"} 1\n"));
+        assert!(text.contains("cacherebbok_namespace_misses_total{namespace=\"0\"} 1\n"));
+        assert!(text.contains("cacherebbok_snapshot_duration_seconds_bucket{le=\"+Inf\"} 1\n"));
+        assert!(text.contains("cacherebbok_snapshot_duration_seconds_count 1\n"));
+        assert!(!text.contains("cacherebbok_snapshot_bytes 0\n"));
+        assert!(text.contains("cacherebbok_latency_seconds_count{operation=\"G\"} 2\n"));
+        assert!(text.contains("cacherebbok_latency_seconds{operation=\"snapshot\",quantile=\"0.99\"} "));
+    }
+
+    #[test]
+    fn test_label_escaping() {
+        assert_eq!(label(b'G'), "G");
+        assert_eq!(label(b'"'), "\\\"");
+        assert_eq!(label(b'\\'), "\\\\");
+        assert_eq!(label(0), "0x00");
+    }
+
+    #[test]
+    fn test_serve() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let cache = Arc::new(Mutex::new(setup_cache(&dir)));
+        let shutdown = cache.lock().unwrap().shutdown_signal();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = serve(listener, Arc::clone(&cache), shutdown.clone()).unwrap();
+
+        let get = |path: &str| {
+            let mut stream = TcpStream::connect(addr).unwrap();
+            write!(stream, "GET {} HTTP/1.1\r\nHost: localhost\r\n\r\n", path).unwrap();
+            let mut response = String::new();
+            stream.read_to_string(&mut response).unwrap();
+            response
+        };
+
+        let response = get("/metrics");
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(response.contains("cacherebbok_uptime_seconds "));
+        assert!(get("/").starts_with("HTTP/1.1 404 Not Found\r\n"));
+
+        shutdown.request();
+        server.join().unwrap();
+    }
+}
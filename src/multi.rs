@@ -0,0 +1,147 @@
+use std::time::{Duration, Instant};
+use crate::{counter, storage::{Key, WireValue}};
+
+/*
+    Multi-key commands, a header frame followed by one frame per key:
+    - M (MGET): the header's value bytes hold the key count as ASCII decimal, then one frame per key
+    - S (MSET): as M, then one frame per key laid out like an I frame (key, value, timestamp, expiry)
+    The command byte of the following frames is ignored. Replies:
+    - M: 65 bytes per key in request order, 1 and the 64 stored bytes for a hit or 0 and 64 zero bytes
+      for a miss, then a newline
+    - S: I once every key has been written in one atomic storage update
+    - E for a count outside 1..=MAX_KEYS, replied to the header, or an empty key in any frame
+ */
+pub const MAX_KEYS: usize = 1024;
+pub const GET_RECORD_SIZE: usize = 65;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Kind {
+    Get,
+    Set,
+}
+
+impl Kind {
+    // The header's command byte
+    pub fn command(self) -> u8 {
+        match self {
+            Kind::Get => b'M',
+            Kind::Set => b'S',
+        }
+    }
+}
+
+// A multi-key request waiting for its key frames
+#[derive(Debug)]
+pub struct Request {
+    pub kind: Kind,
+    expected: usize,
+    pub keys: Vec<Key>,
+    pub values: Vec<WireValue>,
+    // Set when a key frame was unusable; the remaining frames are still consumed
+    pub invalid: bool,
+    // For the slow log, which times the request from its header frame to the reply
+    pub started: Instant,
+    pub wait: Duration,
+}
+
+impl Request {
+    // The request started by a header frame, None if the count is unusable
+    pub fn start(kind: Kind, frame: &[u8; 64]) -> Option<Request> {
+        let count = counter::parse(frame)?;
+        if count < 1 || count as usize > MAX_KEYS {
+            return None;
+        }
+        let count = count as usize;
+        Some(Request {
+            kind,
+            expected: count,
+            keys: Vec::with_capacity(count),
+            values: Vec::with_capacity(if kind == Kind::Set { count } else { 0 }),
+            invalid: false,
+            started: Instant::now(),
+            wait: Duration::ZERO,
+        })
+    }
+
+    // Take the next key frame, returning true once the request is complete
+    pub fn push(&mut self, input: &[u8; 128]) -> bool {
+        let key: Key = input[1..64].try_into().unwrap();
+        if key.iter().all(|&b| b == 0) {
+            self.invalid = true;
+        }
+        self.keys.push(key);
+        if self.kind == Kind::Set {
+            self.values.push(input[64..128].try_into().unwrap());
+        }
+        self.keys.len() == self.expected
+    }
+}
+
+// The combined MGET reply
+pub fn get_response(values: &[Option<WireValue>]) -> Vec<u8> {
+    let mut response = Vec::with_capacity(values.len() * GET_RECORD_SIZE + 1);
+    for value in values {
+        match value {
+            Some(value) => {
+                response.push(b'1');
+                response.extend_from_slice(value);
+            }
+            None => {
+                response.push(b'0');
+                response.extend_from_slice(&[0u8; 64]);
+            }
+        }
+    }
+    response.push(b'\n');
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn header(count: &str) -> [u8; 64] {
+        let mut frame = [0u8; 64];
+        frame[..count.len()].copy_from_slice(count.as_bytes());
+        frame
+    }
+
+    #[test]
+    fn test_request_collects_frames() {
+        let mut request = Request::start(Kind::Set, &header("2")).unwrap();
+        let mut frame = [0u8; 128];
+        frame[1] = b'a';
+        frame[64] = b'x';
+        assert!(!request.push(&frame));
+        frame[1] = b'b';
+        assert!(request.push(&frame));
+        assert!(!request.invalid);
+        assert_eq!(request.keys[1][0], b'b');
+        assert_eq!(request.values[0][0], b'x');
+
+        let mut request = Request::start(Kind::Get, &header("1")).unwrap();
+        assert!(request.push(&[0u8; 128]));
+        assert!(request.invalid);
+        assert!(request.values.is_empty());
+    }
+
+    #[test]
+    fn test_bad_counts() {
+        assert!(Request::start(Kind::Get, &header("0")).is_none());
+        assert!(Request::start(Kind::Get, &header("-3")).is_none());
+        assert!(Request::start(Kind::Get, &header("many")).is_none());
+        assert!(Request::start(Kind::Get, &header(&(MAX_KEYS + 1).to_string())).is_none());
+        assert!(Request::start(Kind::Get, &header(&MAX_KEYS.to_string())).is_some());
+    }
+
+    #[test]
+    fn test_get_response() {
+        let response = get_response(&[Some([7; 64]), None]);
+        assert_eq!(response.len(), 2 * GET_RECORD_SIZE + 1);
+        assert_eq!(response[0], b'1');
+        assert_eq!(&response[1..65], &[7; 64]);
+        assert_eq!(response[65], b'0');
+        assert!(response[66..130].iter().all(|&b| b == 0));
+        assert_eq!(response[130], b'\n');
+    }
+}
@@ -0,0 +1,70 @@
+use std::{io::{self, Write}, sync::{Arc, Mutex}};
+
+/*
+    Stdout is the protocol channel: the client reads every byte on it as a reply. ResponseWriter is
+    the only thing allowed to write there, one whole reply at a time. Diagnostics go to the logger
+    or stderr; clippy rejects print!/println! in the library and std::io::stdout everywhere else
+    (see clippy.toml).
+ */
+pub struct ResponseWriter {
+    out: Box<dyn Write + Send>,
+}
+
+impl ResponseWriter {
+    #[allow(clippy::disallowed_methods)]
+    pub fn stdout() -> Self {
+        ResponseWriter { out: Box::new(io::stdout()) }
+    }
+
+    // A writer whose replies are kept in memory, and the handle to read them back
+    pub fn capture() -> (Self, Captured) {
+        let captured = Captured::default();
+        (ResponseWriter { out: Box::new(captured.clone()) }, captured)
+    }
+
+    // Write one complete reply and flush it so the client sees it right away
+    pub fn send(&mut self, reply: &[u8]) -> io::Result<()> {
+        self.out.write_all(reply)?;
+        self.out.flush()
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.out.flush()
+    }
+}
+
+// Replies written to a captured ResponseWriter
+#[derive(Clone, Default)]
+pub struct Captured(Arc<Mutex<Vec<u8>>>);
+
+impl Captured {
+    // Everything written since the last take
+    pub fn take(&self) -> Vec<u8> {
+        std::mem::take(&mut *self.0.lock().unwrap())
+    }
+}
+
+impl Write for Captured {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.lock().unwrap().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_capture() {
+        let (mut writer, captured) = ResponseWriter::capture();
+        writer.send(b"I\n").unwrap();
+        writer.send(b"G\n").unwrap();
+        assert_eq!(captured.take(), b"I\nG\n");
+        assert!(captured.take().is_empty());
+    }
+}
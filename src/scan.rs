@@ -0,0 +1,255 @@
+use std::{collections::BTreeSet, io, ops::ControlFlow};
+use chrono::{DateTime, Utc};
+use crate::{conditional, storage::{Key, StorageBackend}};
+
+/*
+    Key listing:
+    - P (SCAN): key bytes hold the cursor, all zeros to start. Value bytes: count hint (u16, big
+      endian, 0 for DEFAULT_COUNT) followed by a 62 byte glob pattern, empty to match every key.
+      Pages are in key order and the cursor for the next page is the last key of this one.
+    - K (KEYS): key bytes hold the glob pattern. For small key spaces and debugging; replies E when
+      more than MAX_KEYS_REPLY keys match.
+    Both reply "<count> <more|done>\n" followed by count keys of 63 bytes each. Expired keys are skipped.
+    Patterns support * (any run of bytes), ? (one byte), [abc] and [a-z] classes and \ escapes, so
+    a prefix is matched with "prefix*".
+ */
+pub const DEFAULT_COUNT: usize = 100;
+pub const MAX_COUNT: usize = 1000;
+pub const MAX_KEYS_REPLY: usize = 10_000;
+
+#[derive(Debug, PartialEq, Eq)]
+pub struct Page {
+    pub keys: Vec<Key>,
+    // Whether the key space has no more matching keys after this page
+    pub done: bool,
+}
+
+impl Page {
+    pub fn response(&self) -> Vec<u8> {
+        let mut response = format!("{} {}\n", self.keys.len(), if self.done { "done" } else { "more" }).into_bytes();
+        for key in &self.keys {
+            response.extend_from_slice(key);
+        }
+        response
+    }
+}
+
+// The count hint and pattern of a SCAN frame's value bytes
+pub fn scan_args(frame: &[u8; 64]) -> (usize, &[u8]) {
+    let count = match u16::from_be_bytes([frame[0], frame[1]]) as usize {
+        0 => DEFAULT_COUNT,
+        count => count.min(MAX_COUNT),
+    };
+    (count, trim(&frame[2..]))
+}
+
+// Up to `count` matching keys after `cursor`, in key order. Keys present for the whole scan are
+// returned exactly once however the key space changes between pages.
+pub fn scan(storage: &dyn StorageBackend, cursor: Option<&Key>, pattern: &[u8], count: usize, now: DateTime<Utc>) -> io::Result<Page> {
+    // One more than asked for tells whether anything is left
+    let mut smallest = BTreeSet::new();
+    storage.iter(&mut |key, value| {
+        let after_cursor = cursor.is_none_or(|cursor| key > cursor);
+        if after_cursor && conditional::live(Some(value), now).is_some() && glob_match(pattern, trim(key)) {
+            smallest.insert(*key);
+            if smallest.len() > count + 1 {
+                smallest.pop_last();
+            }
+        }
+        ControlFlow::Continue(())
+    })?;
+
+    let done = smallest.len() <= count;
+    Ok(Page { keys: smallest.into_iter().take(count).collect(), done })
+}
+
+// Every matching key, None once there are more than `limit`
+pub fn keys(storage: &dyn StorageBackend, pattern: &[u8], limit: usize, now: DateTime<Utc>) -> io::Result<Option<Page>> {
+    let page = scan(storage, None, pattern, limit, now)?;
+    Ok(page.done.then_some(page))
+}
+
+// A key or pattern without its zero padding
+pub fn trim(bytes: &[u8]) -> &[u8] {
+    let end = bytes.iter().rposition(|&b| b != 0).map_or(0, |last| last + 1);
+    &bytes[..end]
+}
+
+pub fn glob_match(pattern: &[u8], text: &[u8]) -> bool {
+    if pattern.is_empty() {
+        return true;
+    }
+
+    // Iterative matching with backtracking to the last star
+    let (mut p, mut t) = (0, 0);
+    let mut star: Option<(usize, usize)> = None;
+    while t < text.len() {
+        if p < pattern.len() {
+            match pattern[p] {
+                b'*' => {
+                    star = Some((p, t));
+                    p += 1;
+                    continue;
+                }
+                b'?' => {
+                    p += 1;
+                    t += 1;
+                    continue;
+                }
+                b'[' => {
+                    if let Some((matched, next)) = match_class(pattern, p, text[t]) {
+                        if matched {
+                            p = next;
+                            t += 1;
+                            continue;
+                        }
+                    }
+                }
+                b'\\' if p + 1 < pattern.len() && pattern[p + 1] == text[t] => {
+                    p += 2;
+                    t += 1;
+                    continue;
+                }
+                b'\\' if p + 1 < pattern.len() => {}
+                byte if byte == text[t] => {
+                    p += 1;
+                    t += 1;
+                    continue;
+                }
+                _ => {}
+            }
+        }
+
+        // Mismatch: let the last star take one more byte
+        match star {
+            Some((star_p, star_t)) => {
+                star = Some((star_p, star_t + 1));
+                p = star_p + 1;
+                t = star_t + 1;
+            }
+            None => return false,
+        }
+    }
+
+    pattern[p..].iter().all(|&b| b == b'*')
+}
+
+// Whether `byte` is in the class starting at `pattern[start]`, and the index after the class.
+// None for an unterminated class, which then only matches a literal [.
+fn match_class(pattern: &[u8], start: usize, byte: u8) -> Option<(bool, usize)> {
+    let mut i = start + 1;
+    let negated = pattern.get(i) == Some(&b'^');
+    if negated {
+        i += 1;
+    }
+
+    let mut matched = false;
+    let mut first = true;
+    while i < pattern.len() {
+        match pattern[i] {
+            b']' if !first => return Some((matched != negated, i + 1)),
+            b'\\' if i + 1 < pattern.len() => {
+                matched |= pattern[i + 1] == byte;
+                i += 2;
+            }
+            low if i + 2 < pattern.len() && pattern[i + 1] == b'-' && pattern[i + 2] != b']' => {
+                matched |= (low..=pattern[i + 2]).contains(&byte);
+                i += 3;
+            }
+            literal => {
+                matched |= literal == byte;
+                i += 1;
+            }
+        }
+        first = false;
+    }
+
+    (byte == b'[').then_some((true, start + 1))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::{self, MemoryBackend};
+
+    fn key(name: &str) -> Key {
+        let mut key = [0u8; 63];
+        key[..name.len()].copy_from_slice(name.as_bytes());
+        key
+    }
+
+    fn value(timestamp: i64, ttl: u16) -> storage::Value {
+        let mut wire = [0u8; 64];
+        wire[56..62].copy_from_slice(&timestamp.to_be_bytes()[2..8]);
+        wire[62..64].copy_from_slice(&ttl.to_be_bytes());
+        storage::stored(&wire, 1)
+    }
+
+    #[test]
+    fn test_glob_match() {
+        assert!(glob_match(b"", b"anything"));
+        assert!(glob_match(b"user:*", b"user:42"));
+        assert!(!glob_match(b"user:*", b"session:42"));
+        assert!(glob_match(b"*:42", b"user:42"));
+        assert!(glob_match(b"u?er:*2", b"user:42"));
+        assert!(glob_match(b"user:[0-9][0-9]", b"user:42"));
+        assert!(!glob_match(b"user:[^0-9]*", b"user:42"));
+        assert!(glob_match(b"a*b*c", b"axxbyyc"));
+        assert!(!glob_match(b"a*b*c", b"axxbyy"));
+        assert!(glob_match(b"literal\\*", b"literal*"));
+        assert!(!glob_match(b"literal\\*", b"literally"));
+        assert!(glob_match(b"[", b"["));
+    }
+
+    #[test]
+    fn test_scan_pages_through_matches() {
+        let storage = MemoryBackend::new();
+        let now = Utc::now();
+        for i in 0..25 {
+            storage.put(key(&format!("user:{:02}", i)), value(now.timestamp(), 0)).unwrap();
+        }
+        storage.put(key("session:1"), value(now.timestamp(), 0)).unwrap();
+        storage.put(key("user:expired"), value(now.timestamp() - 120, 60)).unwrap();
+
+        let mut seen = Vec::new();
+        let mut cursor = None;
+        loop {
+            let page = scan(&storage, cursor.as_ref(), b"user:*", 10, now).unwrap();
+            seen.extend(page.keys.iter().copied());
+            cursor = page.keys.last().copied();
+            // Writes between pages do not disturb the cursor
+            storage.put(key("user:zz"), value(now.timestamp(), 0)).unwrap();
+            if page.done {
+                break;
+            }
+        }
+
+        assert_eq!(seen.len(), 26);
+        assert_eq!(seen[0], key("user:00"));
+        assert_eq!(seen[25], key("user:zz"));
+        assert!(seen.windows(2).all(|pair| pair[0] < pair[1]));
+    }
+
+    #[test]
+    fn test_keys_limit_and_response() {
+        let storage = MemoryBackend::new();
+        let now = Utc::now();
+        storage.put(key("a"), value(now.timestamp(), 0)).unwrap();
+        storage.put(key("b"), value(now.timestamp(), 0)).unwrap();
+
+        let page = keys(&storage, b"", 2, now).unwrap().unwrap();
+        let response = page.response();
+        assert!(response.starts_with(b"2 done\n"));
+        assert_eq!(response.len(), 7 + 2 * 63);
+        assert_eq!(keys(&storage, b"", 1, now).unwrap(), None);
+    }
+
+    #[test]
+    fn test_scan_args() {
+        let mut frame = [0u8; 64];
+        assert_eq!(scan_args(&frame), (DEFAULT_COUNT, &b""[..]));
+        frame[0..2].copy_from_slice(&5000u16.to_be_bytes());
+        frame[2..8].copy_from_slice(b"user:*");
+        assert_eq!(scan_args(&frame), (MAX_COUNT, &b"user:*"[..]));
+    }
+}
@@ -0,0 +1,106 @@
+use std::{sync::{atomic::{AtomicBool, Ordering}, Arc}, time::{Duration, Instant}};
+use crate::logger::Logger;
+
+// How often sleeping threads wake up to check for a shutdown request
+pub const SHUTDOWN_POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+// Shared shutdown state, cheap to clone into signal handlers and background threads
+#[derive(Clone, Debug)]
+pub struct ShutdownSignal {
+    requested: Arc<AtomicBool>,
+    watchdog_armed: Arc<AtomicBool>,
+    timeout: Duration,
+    logger: Option<Logger>,
+}
+
+impl ShutdownSignal {
+    pub fn new(timeout: Duration, logger: Option<Logger>) -> Self {
+        ShutdownSignal {
+            requested: Arc::new(AtomicBool::new(false)),
+            watchdog_armed: Arc::new(AtomicBool::new(false)),
+            timeout,
+            logger,
+        }
+    }
+
+    // Ask the main loop to stop accepting input and shut down
+    pub fn request(&self) {
+        self.requested.store(true, Ordering::SeqCst);
+    }
+
+    pub fn is_requested(&self) -> bool {
+        self.requested.load(Ordering::SeqCst)
+    }
+
+    pub fn timeout(&self) -> Duration {
+        self.timeout
+    }
+
+    // Force the process down if shutdown has not finished within the deadline
+    pub fn arm_watchdog(&self) {
+        if self.watchdog_armed.swap(true, Ordering::SeqCst) {
+            return;
+        }
+
+        let timeout = self.timeout;
+        let logger = self.logger.clone();
+        std::thread::spawn(move || {
+            std::thread::sleep(timeout);
+            eprintln!("Shutdown did not finish within {} ms, forcing exit", timeout.as_millis());
+            if let Some(logger) = logger {
+                let _ = logger.flush();
+            }
+            std::process::exit(1);
+        });
+    }
+
+    // Sleep for the given duration, waking early on shutdown. Returns true if shutdown was requested.
+    pub fn sleep(&self, duration: Duration) -> bool {
+        let deadline = Instant::now() + duration;
+        loop {
+            if self.is_requested() {
+                return true;
+            }
+            let now = Instant::now();
+            if now >= deadline {
+                return false;
+            }
+            std::thread::sleep(SHUTDOWN_POLL_INTERVAL.min(deadline - now));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_request_is_shared_between_clones() {
+        let signal = ShutdownSignal::new(Duration::from_secs(1), None);
+        let clone = signal.clone();
+        assert!(!clone.is_requested());
+
+        signal.request();
+        assert!(clone.is_requested());
+    }
+
+    #[test]
+    fn test_sleep_wakes_on_request() {
+        let signal = ShutdownSignal::new(Duration::from_secs(1), None);
+        let clone = signal.clone();
+        std::thread::spawn(move || {
+            std::thread::sleep(Duration::from_millis(20));
+            clone.request();
+        });
+
+        let start = Instant::now();
+        assert!(signal.sleep(Duration::from_secs(30)));
+        assert!(start.elapsed() < Duration::from_secs(5));
+    }
+
+    #[test]
+    fn test_sleep_runs_full_duration_without_request() {
+        let signal = ShutdownSignal::new(Duration::from_secs(1), None);
+        assert!(!signal.sleep(Duration::from_millis(10)));
+    }
+}
@@ -0,0 +1,164 @@
+use std::{collections::VecDeque, time::Duration};
+use chrono::{DateTime, Utc};
+use crate::scan;
+
+/*
+    Slow log (O command). Value bytes:
+    - empty: every entry, newest first, or an ASCII decimal count for only the newest ones
+    - "reset": clear the log, replied with O
+    Replies "<count>\n" then one line per entry:
+    "<id> <epoch ms> <duration us> <wait us> <command> <batch size> <key>"
+    The duration covers processing the command. The wait is how long its batch waited for the cache lock,
+    and it is given on the first command of the batch only. A command is logged once the duration plus
+    the wait reach CACHE_SLOWLOG_THRESHOLD_US. M and S requests are one entry under their first key, timed
+    from the header frame to the reply, with the waits of every batch they span. The key shows its first KEY_BYTES bytes, with bytes
+    that are not printable as \xNN, or "-" for commands without a key.
+ */
+pub const KEY_BYTES: usize = 32;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Entry {
+    pub id: u64,
+    pub at: DateTime<Utc>,
+    pub duration: Duration,
+    pub wait: Duration,
+    pub command: u8,
+    pub key: Vec<u8>,
+    pub batch: usize,
+}
+
+impl Entry {
+    fn line(&self) -> String {
+        format!(
+            "{} {} {} {} {} {} {}\n",
+            self.id,
+            self.at.timestamp_millis(),
+            self.duration.as_micros(),
+            self.wait.as_micros(),
+            escape(&[self.command]),
+            self.batch,
+            if self.key.is_empty() { "-".to_string() } else { escape(&self.key) }
+        )
+    }
+}
+
+pub struct SlowLog {
+    threshold: Duration,
+    max_len: usize,
+    entries: VecDeque<Entry>,
+    // Ids keep counting across resets so readers can tell entries apart
+    next_id: u64,
+}
+
+impl SlowLog {
+    pub fn new(threshold: Duration, max_len: usize) -> Self {
+        SlowLog { threshold, max_len, entries: VecDeque::with_capacity(max_len), next_id: 0 }
+    }
+
+    // Log the command if it was slow, dropping the oldest entry when the log is full
+    pub fn observe(&mut self, command: u8, key: &[u8], duration: Duration, wait: Duration, batch: usize) {
+        if self.max_len == 0 || duration + wait < self.threshold {
+            return;
+        }
+        if self.entries.len() == self.max_len {
+            self.entries.pop_back();
+        }
+
+        let key = scan::trim(key);
+        self.entries.push_front(Entry {
+            id: self.next_id,
+            at: Utc::now(),
+            duration,
+            wait,
+            command,
+            key: key[..key.len().min(KEY_BYTES)].to_vec(),
+            batch,
+        });
+        self.next_id += 1;
+    }
+
+    // Newest first
+    pub fn entries(&self) -> impl Iterator<Item = &Entry> {
+        self.entries.iter()
+    }
+
+    pub fn reset(&mut self) {
+        self.entries.clear();
+    }
+
+    // The reply to O for the newest `count` entries
+    pub fn response(&self, count: usize) -> Vec<u8> {
+        let entries: Vec<&Entry> = self.entries.iter().take(count).collect();
+        let mut response = format!("{}\n", entries.len());
+        for entry in entries {
+            response.push_str(&entry.line());
+        }
+        response.into_bytes()
+    }
+}
+
+// Printable bytes as they are, anything else (including spaces) as \xNN so fields stay one word
+fn escape(bytes: &[u8]) -> String {
+    bytes
+        .iter()
+        .map(|&byte| match byte {
+            b'\\' => "\\\\".to_string(),
+            byte if byte.is_ascii_graphic() => (byte as char).to_string(),
+            byte => format!("\\x{:02x}", byte),
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const THRESHOLD: Duration = Duration::from_millis(10);
+
+    #[test]
+    fn test_threshold_and_bound() {
+        let mut log = SlowLog::new(THRESHOLD, 2);
+        log.observe(b'G', b"fast", Duration::from_millis(1), Duration::ZERO, 1);
+        log.observe(b'G', b"slow", Duration::from_millis(11), Duration::ZERO, 1);
+        // Waiting for the lock counts towards the threshold
+        log.observe(b'I', b"waited", Duration::from_millis(1), Duration::from_millis(20), 16);
+        log.observe(b'R', b"newest", Duration::from_millis(30), Duration::ZERO, 1);
+
+        let entries: Vec<&Entry> = log.entries().collect();
+        assert_eq!(entries.len(), 2);
+        assert_eq!((entries[0].id, entries[0].key.as_slice()), (2, &b"newest"[..]));
+        assert_eq!((entries[1].id, entries[1].batch), (1, 16));
+
+        log.reset();
+        assert_eq!(log.response(10), b"0\n");
+        log.observe(b'G', b"again", THRESHOLD, Duration::ZERO, 1);
+        assert_eq!(log.entries().next().unwrap().id, 3);
+    }
+
+    #[test]
+    fn test_response_lines() {
+        let mut log = SlowLog::new(THRESHOLD, 8);
+        let mut key = [0u8; 63];
+        key[..5].copy_from_slice(b"a b\x01c");
+        log.observe(b'G', &key, Duration::from_micros(12_345), Duration::from_micros(7), 4);
+        log.observe(b'H', &[0u8; 63], Duration::from_secs(1), Duration::ZERO, 1);
+        log.observe(b'I', &[b'k'; 63], THRESHOLD, Duration::ZERO, 1);
+
+        let response = String::from_utf8(log.response(2)).unwrap();
+        let lines: Vec<&str> = response.lines().collect();
+        assert_eq!(lines.len(), 3);
+        assert_eq!(lines[0], "2");
+        assert!(lines[1].ends_with(&format!(" 10000 0 I 1 {}", "k".repeat(KEY_BYTES))));
+        assert!(lines[2].ends_with(" 1000000 0 H 1 -"));
+
+        let response = String::from_utf8(log.response(10)).unwrap();
+        assert!(response.lines().last().unwrap().ends_with(" 12345 7 G 4 a\\x20b\\x01c"));
+    }
+
+    #[test]
+    fn test_disabled() {
+        let mut log = SlowLog::new(Duration::ZERO, 0);
+        log.observe(b'G', b"key", Duration::from_secs(1), Duration::ZERO, 1);
+        assert_eq!(log.entries().count(), 0);
+    }
+}
@@ -0,0 +1,847 @@
+use std::{fmt, fs, io::{self, Read, Write}, path::{Path, PathBuf}, str::FromStr, sync::{atomic::{AtomicBool, AtomicI64, AtomicU64, AtomicUsize, Ordering}, Mutex}, time::Instant};
+use chrono::Utc;
+use crate::{crypto::Keyring, generations::{self, Retention}, logger::{LogLevel, Logger}, storage::{self, Value}, utils::{parse_cache_metadata, read_file_to_buffer, write_buffer_to_file}};
+
+pub const SNAPSHOT_FILE: &str = "cache.json";
+// Each record is the namespace byte, a 63 byte key, the 64 byte value (56 value + 6 timestamp +
+// 2 expiry) and the entry's version (8 bytes, big endian)
+pub const RECORD_SIZE: usize = 1 + 63 + storage::VALUE_SIZE;
+// Version 4 records had no namespace; older ones, and headerless files, had no entry version either
+pub const V4_RECORD_SIZE: usize = 63 + storage::VALUE_SIZE;
+pub const LEGACY_RECORD_SIZE: usize = 127;
+
+/*
+    Snapshot header (12 bytes), followed by the possibly compressed records:
+    - 4 bytes: magic "KVOS"
+    - 1 byte: format version
+    - 1 byte: compression (0=none, 1=lz4, 2=zstd)
+    - 1 byte: flags (bit 0 = encrypted)
+    - 1 byte: reserved, zero
+    - 4 bytes: CRC32 of the uncompressed records, big endian (since version 3)
+    Encrypted payloads are sealed with the header as associated data (see crypto::Keyring::seal).
+    Files without the magic are legacy snapshots holding bare records.
+ */
+const SNAPSHOT_MAGIC: &[u8; 4] = b"KVOS";
+const SNAPSHOT_VERSION: u8 = 5;
+// Versions 1 and 2 had no checksum and an 8 byte header; version 1 also had no flags
+const MIN_SNAPSHOT_VERSION: u8 = 1;
+const CHECKSUM_VERSION: u8 = 3;
+const ENTRY_VERSION_VERSION: u8 = 4;
+const NAMESPACE_VERSION: u8 = 5;
+const FLAG_ENCRYPTED: u8 = 0b0000_0001;
+pub const HEADER_SIZE: usize = 12;
+// Records handed to the loader at a time, bounding memory while streaming a snapshot (about 8 MB)
+pub const LOAD_BATCH_RECORDS: usize = 65_536;
+const LEGACY_HEADER_SIZE: usize = 8;
+const ZSTD_LEVEL: i32 = 3;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Compression {
+    #[default]
+    None,
+    Lz4,
+    Zstd,
+}
+
+impl Compression {
+    fn id(self) -> u8 {
+        match self {
+            Compression::None => 0,
+            Compression::Lz4 => 1,
+            Compression::Zstd => 2,
+        }
+    }
+
+    fn from_id(id: u8) -> io::Result<Self> {
+        match id {
+            0 => Ok(Compression::None),
+            1 => Ok(Compression::Lz4),
+            2 => Ok(Compression::Zstd),
+            _ => Err(invalid_data(format!("unknown snapshot compression {}", id))),
+        }
+    }
+
+    // File extension of standalone compressed files, such as rotated logs
+    pub fn extension(self) -> Option<&'static str> {
+        match self {
+            Compression::None => None,
+            Compression::Lz4 => Some("lz4"),
+            Compression::Zstd => Some("zst"),
+        }
+    }
+
+    pub(crate) fn compress(self, records: &[u8]) -> io::Result<Vec<u8>> {
+        match self {
+            Compression::None => Ok(records.to_vec()),
+            Compression::Lz4 => {
+                let mut encoder = lz4_flex::frame::FrameEncoder::new(Vec::with_capacity(records.len() / 4));
+                encoder.write_all(records)?;
+                encoder.finish().map_err(io::Error::other)
+            }
+            Compression::Zstd => zstd::encode_all(records, ZSTD_LEVEL),
+        }
+    }
+
+    fn decompress(self, payload: &[u8]) -> io::Result<Vec<u8>> {
+        match self.decompress_partial(payload) {
+            (records, None) => Ok(records),
+            (_, Some(e)) => Err(e),
+        }
+    }
+
+    // Decompress as much as possible, returning what was recovered along with the error that stopped it
+    pub fn decompress_partial(self, payload: &[u8]) -> (Vec<u8>, Option<io::Error>) {
+        let mut records = Vec::with_capacity(payload.len() * 4);
+        let result = match self {
+            Compression::None => {
+                records.extend_from_slice(payload);
+                Ok(0)
+            }
+            Compression::Lz4 => lz4_flex::frame::FrameDecoder::new(payload).read_to_end(&mut records),
+            Compression::Zstd => zstd::stream::read::Decoder::new(payload)
+                .and_then(|mut decoder| decoder.read_to_end(&mut records)),
+        };
+        (records, result.err())
+    }
+}
+
+impl FromStr for Compression {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "" | "none" | "off" => Ok(Compression::None),
+            "lz4" => Ok(Compression::Lz4),
+            "zstd" => Ok(Compression::Zstd),
+            other => Err(format!("unknown compression '{}'", other)),
+        }
+    }
+}
+
+impl fmt::Display for Compression {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Compression::None => write!(f, "none"),
+            Compression::Lz4 => write!(f, "lz4"),
+            Compression::Zstd => write!(f, "zstd"),
+        }
+    }
+}
+
+// Parsed snapshot header
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Header {
+    pub version: u8,
+    pub compression: Compression,
+    pub encrypted: bool,
+    // CRC32 of the uncompressed records, None before version 3
+    pub checksum: Option<u32>,
+    // Bytes taken by the header, which are also the associated data for encryption
+    pub len: usize,
+}
+
+impl Header {
+    // Parse the header at the start of a snapshot, None for legacy files without one
+    pub fn parse(buffer: &[u8]) -> io::Result<Option<Header>> {
+        if buffer.len() < LEGACY_HEADER_SIZE || &buffer[0..4] != SNAPSHOT_MAGIC {
+            return Ok(None);
+        }
+
+        let version = buffer[4];
+        if !(MIN_SNAPSHOT_VERSION..=SNAPSHOT_VERSION).contains(&version) {
+            return Err(invalid_data(format!("unsupported snapshot version {}", version)));
+        }
+
+        let compression = Compression::from_id(buffer[5])?;
+        let encrypted = version >= 2 && buffer[6] & FLAG_ENCRYPTED != 0;
+
+        if version < CHECKSUM_VERSION {
+            return Ok(Some(Header { version, compression, encrypted, checksum: None, len: LEGACY_HEADER_SIZE }));
+        }
+
+        if buffer.len() < HEADER_SIZE {
+            return Err(invalid_data("snapshot header is truncated".to_string()));
+        }
+
+        let checksum = u32::from_be_bytes([buffer[8], buffer[9], buffer[10], buffer[11]]);
+        Ok(Some(Header { version, compression, encrypted, checksum: Some(checksum), len: HEADER_SIZE }))
+    }
+
+    // Size of the records that follow this header
+    pub fn record_size(&self) -> usize {
+        match self.version {
+            version if version >= NAMESPACE_VERSION => RECORD_SIZE,
+            version if version >= ENTRY_VERSION_VERSION => V4_RECORD_SIZE,
+            _ => LEGACY_RECORD_SIZE,
+        }
+    }
+
+    // Check decoded records against the stored checksum, if there is one
+    pub fn verify(&self, records: &[u8]) -> io::Result<()> {
+        self.verify_hash(crc32fast::hash(records))
+    }
+
+    fn verify_hash(&self, actual: u32) -> io::Result<()> {
+        match self.checksum {
+            Some(expected) if actual != expected => Err(invalid_data(format!(
+                "snapshot checksum mismatch, expected {:08x} but records hash to {:08x}",
+                expected, actual
+            ))),
+            _ => Ok(()),
+        }
+    }
+}
+
+fn invalid_data(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+// Full point-in-time copy of the key space, taken while holding the cache lock
+pub type SnapshotView = hashbrown::HashMap<[u8; 63], Value>;
+
+// Persistence state shared between foreground saves and the background save job
+#[derive(Debug, Default)]
+pub struct SaveStatus {
+    in_progress: AtomicBool,
+    next_seq: AtomicU64,
+    // Sequence of the newest view on disk; also serializes file writes
+    written_seq: Mutex<u64>,
+    last_attempt: AtomicI64,
+    last_save: AtomicI64,
+    last_duration_ms: AtomicU64,
+    last_bytes: AtomicUsize,
+    last_ok: AtomicBool,
+}
+
+impl SaveStatus {
+    pub fn new() -> Self {
+        SaveStatus {
+            last_ok: AtomicBool::new(true),
+            ..Default::default()
+        }
+    }
+
+    // Claim the background save slot, false if a background save is already running
+    pub fn begin_background(&self) -> bool {
+        self.in_progress.compare_exchange(false, true, Ordering::SeqCst, Ordering::SeqCst).is_ok()
+    }
+
+    pub fn finish_background(&self) {
+        self.in_progress.store(false, Ordering::SeqCst);
+    }
+
+    // Sequence numbers order views so an older view never overwrites a newer one
+    pub fn next_seq(&self) -> u64 {
+        self.last_attempt.store(Utc::now().timestamp(), Ordering::SeqCst);
+        self.next_seq.fetch_add(1, Ordering::SeqCst) + 1
+    }
+
+    pub fn is_in_progress(&self) -> bool {
+        self.in_progress.load(Ordering::SeqCst)
+    }
+
+    // Epoch seconds of the last successful save, 0 if nothing has been saved yet
+    pub fn last_save(&self) -> i64 {
+        self.last_save.load(Ordering::SeqCst)
+    }
+
+    // Epoch seconds of the last time a view was captured for saving, successful or not
+    pub fn last_attempt(&self) -> i64 {
+        self.last_attempt.load(Ordering::SeqCst)
+    }
+
+    pub fn last_ok(&self) -> bool {
+        self.last_ok.load(Ordering::SeqCst)
+    }
+
+    pub fn last_duration_ms(&self) -> u64 {
+        self.last_duration_ms.load(Ordering::SeqCst)
+    }
+
+    pub fn last_bytes(&self) -> usize {
+        self.last_bytes.load(Ordering::SeqCst)
+    }
+
+    // Write a view unless a newer one already reached disk. Returns None when superseded.
+    fn write(&self, data_dir: &Path, seq: u64, buffer: &[u8], retention: &Retention, logger: &Logger) -> io::Result<Option<usize>> {
+        let mut written_seq = self.written_seq.lock().unwrap();
+        if seq <= *written_seq {
+            return Ok(None);
+        }
+
+        let path = data_dir.join(SNAPSHOT_FILE);
+        let bytes_written = write_atomic(&path, buffer)?;
+        *written_seq = seq;
+
+        // Still under the lock so the generation is this exact snapshot
+        if retention.is_enabled() {
+            // The snapshot itself is safe on disk, so a failed generation only gets logged
+            if let Err(e) = generations::record(&path, data_dir, Utc::now(), retention) {
+                logger.log(LogLevel::Error, "snapshot", format!("recording snapshot generation failed: {}", e));
+            }
+        }
+
+        Ok(Some(bytes_written))
+    }
+}
+
+// Serialize the view of each namespace, indexed by namespace, into fixed size records. Empty keys
+// and expired entries are skipped.
+pub fn encode(views: &[SnapshotView]) -> Vec<u8> {
+    let now = Utc::now();
+    let mut buffer = Vec::with_capacity(views.iter().map(|view| view.len()).sum::<usize>() * RECORD_SIZE);
+
+    for (namespace, key, value) in views.iter().enumerate().flat_map(|(namespace, view)| view.iter().map(move |(key, value)| (namespace, key, value))) {
+        // Don't persist empty keys
+        if key.iter().all(|&b| b == 0) {
+            continue;
+        }
+
+        // Check if entry is expired before persisting
+        let (_, expires_at) = parse_cache_metadata(storage::wire(value));
+        if expires_at.is_some_and(|expires| expires <= now) {
+            continue;
+        }
+
+        buffer.push(namespace as u8);
+        buffer.extend_from_slice(key);
+        buffer.extend_from_slice(value);
+    }
+
+    buffer
+}
+
+// How snapshot payloads are compressed and encrypted
+#[derive(Clone, Debug, Default)]
+pub struct Codec {
+    pub compression: Compression,
+    pub keyring: Option<Keyring>,
+}
+
+impl Codec {
+    // Prefix records with a header, compress them and encrypt when a keyring is configured
+    pub fn wrap(&self, records: &[u8]) -> io::Result<Vec<u8>> {
+        let flags = if self.keyring.is_some() { FLAG_ENCRYPTED } else { 0 };
+        let checksum = crc32fast::hash(records).to_be_bytes();
+        let header = [
+            SNAPSHOT_MAGIC[0], SNAPSHOT_MAGIC[1], SNAPSHOT_MAGIC[2], SNAPSHOT_MAGIC[3],
+            SNAPSHOT_VERSION, self.compression.id(), flags, 0,
+            checksum[0], checksum[1], checksum[2], checksum[3],
+        ];
+
+        let mut payload = self.compression.compress(records)?;
+        if let Some(keyring) = &self.keyring {
+            payload = keyring.seal(&header, &payload)?;
+        }
+
+        let mut buffer = Vec::with_capacity(HEADER_SIZE + payload.len());
+        buffer.extend_from_slice(&header);
+        buffer.extend_from_slice(&payload);
+
+        Ok(buffer)
+    }
+
+    // Strip the header, decrypt, decompress and verify the checksum, returning bare records.
+    // Legacy snapshots are returned as-is.
+    pub fn unwrap(&self, buffer: Vec<u8>) -> io::Result<Vec<u8>> {
+        let Some(header) = Header::parse(&buffer)? else {
+            return Ok(upgrade_records(buffer, LEGACY_RECORD_SIZE));
+        };
+
+        let payload = self.open(&header, &buffer)?;
+        let records = header.compression.decompress(&payload)?;
+        header.verify(&records)?;
+
+        Ok(upgrade_records(records, header.record_size()))
+    }
+
+    // The compressed payload after the header, decrypted if the snapshot is encrypted
+    pub fn open(&self, header: &Header, buffer: &[u8]) -> io::Result<Vec<u8>> {
+        let (header_bytes, payload) = buffer.split_at(header.len);
+        if !header.encrypted {
+            return Ok(payload.to_vec());
+        }
+
+        let keyring = self
+            .keyring
+            .as_ref()
+            .ok_or_else(|| invalid_data("snapshot is encrypted but no encryption key is configured".to_string()))?;
+        keyring.open(header_bytes, payload)
+    }
+}
+
+// Bring older records to the current size. They belong to namespace 0, and entries without a
+// version get version 1. A trailing partial record is kept as it is.
+pub fn upgrade_records(records: Vec<u8>, record_size: usize) -> Vec<u8> {
+    if record_size == RECORD_SIZE {
+        return records;
+    }
+
+    let whole = records.len() - records.len() % record_size;
+    let mut upgraded = Vec::with_capacity(whole / record_size * RECORD_SIZE + records.len() - whole);
+    for chunk in records[..whole].chunks_exact(record_size) {
+        upgraded.push(0);
+        upgraded.extend_from_slice(chunk);
+        if record_size == LEGACY_RECORD_SIZE {
+            upgraded.extend_from_slice(&1u64.to_be_bytes());
+        }
+    }
+    upgraded.extend_from_slice(&records[whole..]);
+    upgraded
+}
+
+// Split bare records into namespace, key and value, skipping empty keys and any trailing partial record
+pub fn decode(records: &[u8]) -> impl Iterator<Item = (u8, [u8; 63], Value)> + '_ {
+    records.chunks_exact(RECORD_SIZE).filter_map(decode_record)
+}
+
+// Parse one whole record, None for an empty key
+pub fn decode_record(chunk: &[u8]) -> Option<(u8, [u8; 63], Value)> {
+    let mut key = [0u8; 63];
+    let mut value = [0u8; storage::VALUE_SIZE];
+    key.copy_from_slice(&chunk[1..64]);
+    value.copy_from_slice(&chunk[64..RECORD_SIZE]);
+
+    // Skip empty keys
+    (!key.iter().all(|&b| b == 0)).then_some((chunk[0], key, value))
+}
+
+// Streams bare records out of a snapshot file in batches, checking the checksum once the end is reached
+pub struct RecordReader {
+    inner: Box<dyn Read + Send>,
+    header: Option<Header>,
+    hasher: crc32fast::Hasher,
+    bytes_read: usize,
+    // Size of the records in the file; batches are always upgraded to RECORD_SIZE
+    record_size: usize,
+    finished: bool,
+}
+
+impl RecordReader {
+    // Plain and compressed snapshots are decoded as they are read. Encrypted snapshots are
+    // authenticated as a whole, so those are decrypted in memory first.
+    pub fn open(path: &Path, codec: &Codec) -> io::Result<Self> {
+        let mut file = io::BufReader::with_capacity(1 << 20, fs::File::open(path)?);
+
+        let mut prefix = Vec::with_capacity(HEADER_SIZE);
+        (&mut file).take(HEADER_SIZE as u64).read_to_end(&mut prefix)?;
+
+        let header = Header::parse(&prefix)?;
+        let inner: Box<dyn Read + Send> = match &header {
+            None => Box::new(io::Cursor::new(prefix).chain(file)),
+            Some(header) if header.encrypted => {
+                let mut buffer = prefix;
+                file.read_to_end(&mut buffer)?;
+                let payload = codec.open(header, &buffer)?;
+                Box::new(io::Cursor::new(header.compression.decompress(&payload)?))
+            }
+            Some(header) => {
+                // Version 1 and 2 headers are shorter, so part of the prefix may already be payload
+                let payload = io::Cursor::new(prefix[header.len..].to_vec()).chain(file);
+                match header.compression {
+                    Compression::None => Box::new(payload),
+                    Compression::Lz4 => Box::new(lz4_flex::frame::FrameDecoder::new(payload)),
+                    Compression::Zstd => Box::new(zstd::stream::read::Decoder::new(payload)?),
+                }
+            }
+        };
+
+        let record_size = header.as_ref().map_or(LEGACY_RECORD_SIZE, Header::record_size);
+        Ok(RecordReader { inner, header, hasher: crc32fast::Hasher::new(), bytes_read: 0, record_size, finished: false })
+    }
+
+    // Replace the batch with up to LOAD_BATCH_RECORDS whole records. Returns false once the snapshot is
+    // exhausted, after verifying its checksum. A trailing partial record is dropped, like decode does.
+    pub fn next_batch(&mut self, batch: &mut Vec<u8>) -> io::Result<bool> {
+        if self.finished {
+            return Ok(false);
+        }
+
+        batch.clear();
+        batch.resize(LOAD_BATCH_RECORDS * self.record_size, 0);
+        let mut filled = 0;
+
+        while filled < batch.len() {
+            match self.inner.read(&mut batch[filled..]) {
+                Ok(0) => break,
+                Ok(n) => filled += n,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e),
+            }
+        }
+
+        self.hasher.update(&batch[..filled]);
+        self.bytes_read += filled;
+        batch.truncate(filled - filled % self.record_size);
+        if self.record_size != RECORD_SIZE {
+            *batch = upgrade_records(std::mem::take(batch), self.record_size);
+        }
+
+        if filled < LOAD_BATCH_RECORDS * self.record_size {
+            self.finished = true;
+            if let Some(header) = &self.header {
+                header.verify_hash(self.hasher.clone().finalize())?;
+            }
+        }
+
+        Ok(!batch.is_empty())
+    }
+
+    // Bytes of records read so far, after decompression
+    pub fn bytes_read(&self) -> usize {
+        self.bytes_read
+    }
+}
+
+// Read a snapshot file into bare records, empty if the file does not exist
+pub fn read(path: &Path, codec: &Codec) -> io::Result<Vec<u8>> {
+    codec.unwrap(read_file_to_buffer(path)?)
+}
+
+// Encode and write the namespace views to the data directory, recording the outcome in the status
+pub fn save(data_dir: &Path, seq: u64, views: &[SnapshotView], status: &SaveStatus, codec: &Codec, retention: &Retention, logger: &Logger) -> io::Result<Option<usize>> {
+    let start = Instant::now();
+    let result = codec.wrap(&encode(views))
+        .and_then(|buffer| status.write(data_dir, seq, &buffer, retention, logger));
+
+    match result {
+        Ok(written) => {
+            if let Some(bytes) = written {
+                status.last_save.store(Utc::now().timestamp(), Ordering::SeqCst);
+                status.last_duration_ms.store(start.elapsed().as_millis() as u64, Ordering::SeqCst);
+                status.last_bytes.store(bytes, Ordering::SeqCst);
+            }
+            status.last_ok.store(true, Ordering::SeqCst);
+            Ok(written)
+        }
+        Err(e) => {
+            status.last_ok.store(false, Ordering::SeqCst);
+            Err(e)
+        }
+    }
+}
+
+// Write to a temporary file and rename it over the target so readers never see a partial snapshot
+pub(crate) fn write_atomic(path: &Path, buffer: &[u8]) -> io::Result<usize> {
+    let mut tmp_name = path.file_name().map(|name| name.to_os_string()).unwrap_or_default();
+    tmp_name.push(".tmp");
+    let tmp_path: PathBuf = path.with_file_name(tmp_name);
+
+    let bytes_written = write_buffer_to_file(&tmp_path, buffer)?;
+    fs::rename(&tmp_path, path)?;
+
+    Ok(bytes_written)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    fn logger(dir: &TempDir) -> Logger {
+        Logger::new(dir.path().join("log.log").to_str().unwrap(), LogLevel::Info, Default::default())
+    }
+
+    fn record(key_byte: u8, expiry_seconds: u16, created_at: i64) -> ([u8; 63], Value) {
+        let mut key = [0u8; 63];
+        key[0] = key_byte;
+        let mut value = [0u8; 64];
+        value[0] = key_byte;
+        value[56..62].copy_from_slice(&created_at.to_be_bytes()[2..8]);
+        value[62..64].copy_from_slice(&expiry_seconds.to_be_bytes());
+        (key, storage::stored(&value, 1))
+    }
+
+    #[test]
+    fn test_encode_skips_empty_and_expired() {
+        let now = Utc::now().timestamp();
+        let mut view = SnapshotView::new();
+
+        let (key, value) = record(1, 0, now);
+        view.insert(key, value);
+        let (key, value) = record(2, 10, now - 3600);
+        view.insert(key, value);
+        view.insert([0u8; 63], [1u8; storage::VALUE_SIZE]);
+
+        let buffer = encode(&[SnapshotView::new(), view]);
+        assert_eq!(buffer.len(), RECORD_SIZE);
+        assert_eq!(buffer[0], 1);
+        assert_eq!(decode(&buffer).next().unwrap().0, 1);
+    }
+
+    #[test]
+    fn test_save_records_status() {
+        let dir = TempDir::new().unwrap();
+        let status = SaveStatus::new();
+        let mut view = SnapshotView::new();
+        let (key, value) = record(1, 0, Utc::now().timestamp());
+        view.insert(key, value);
+
+        let written = save(dir.path(), status.next_seq(), &[view], &status, &Codec::default(), &Retention::default(), &logger(&dir)).unwrap();
+
+        assert_eq!(written, Some(HEADER_SIZE + RECORD_SIZE));
+        assert!(status.last_ok());
+        assert!(status.last_save() > 0);
+        assert_eq!(status.last_bytes(), HEADER_SIZE + RECORD_SIZE);
+        assert_eq!(read(&dir.path().join(SNAPSHOT_FILE), &Codec::default()).unwrap().len(), RECORD_SIZE);
+        assert!(!dir.path().join("cache.json.tmp").exists());
+    }
+
+    #[test]
+    fn test_older_view_does_not_overwrite_newer() {
+        let dir = TempDir::new().unwrap();
+        let status = SaveStatus::new();
+
+        let old_seq = status.next_seq();
+        let new_seq = status.next_seq();
+
+        let mut newer = SnapshotView::new();
+        let (key, value) = record(1, 0, Utc::now().timestamp());
+        newer.insert(key, value);
+
+        assert!(save(dir.path(), new_seq, &[newer], &status, &Codec::default(), &Retention::default(), &logger(&dir)).unwrap().is_some());
+        assert!(save(dir.path(), old_seq, &[SnapshotView::new()], &status, &Codec::default(), &Retention::default(), &logger(&dir)).unwrap().is_none());
+        assert_eq!(read(&dir.path().join(SNAPSHOT_FILE), &Codec::default()).unwrap().len(), RECORD_SIZE);
+    }
+
+    #[test]
+    fn test_single_background_slot() {
+        let status = SaveStatus::new();
+        assert!(status.begin_background());
+        assert!(!status.begin_background());
+        status.finish_background();
+        assert!(status.begin_background());
+    }
+
+    fn sample_view(count: usize) -> SnapshotView {
+        let now = Utc::now().timestamp();
+        let mut view = SnapshotView::with_capacity(count);
+        for i in 0..count {
+            let mut key = [0u8; 63];
+            let name = format!("session:{}:user", i);
+            key[..name.len()].copy_from_slice(name.as_bytes());
+            let mut value = [0u8; 64];
+            let data = format!("{{\"id\":{},\"role\":\"member\"}}", i);
+            value[..data.len()].copy_from_slice(data.as_bytes());
+            value[56..62].copy_from_slice(&now.to_be_bytes()[2..8]);
+            value[62..64].copy_from_slice(&3600u16.to_be_bytes());
+            view.insert(key, storage::stored(&value, 1));
+        }
+        view
+    }
+
+    // The same namespace 0 records without entry versions, as written before version 4
+    fn legacy(records: &[u8]) -> Vec<u8> {
+        records.chunks_exact(RECORD_SIZE).flat_map(|chunk| &chunk[1..1 + LEGACY_RECORD_SIZE]).copied().collect()
+    }
+
+    // The same namespace 0 records without namespaces, as written by version 4
+    fn version_four(records: &[u8]) -> Vec<u8> {
+        records.chunks_exact(RECORD_SIZE).flat_map(|chunk| &chunk[1..]).copied().collect()
+    }
+
+    #[test]
+    fn test_compression_round_trip() {
+        let records = encode(&[sample_view(100)]);
+        for compression in [Compression::None, Compression::Lz4, Compression::Zstd] {
+            let codec = Codec { compression, keyring: None };
+            let wrapped = codec.wrap(&records).unwrap();
+            assert_eq!(&wrapped[0..4], SNAPSHOT_MAGIC);
+            assert_eq!(codec.unwrap(wrapped).unwrap(), records, "{}", compression);
+        }
+    }
+
+    #[test]
+    fn test_compression_shrinks_padded_records() {
+        let records = encode(&[sample_view(100)]);
+        let lz4 = Codec { compression: Compression::Lz4, keyring: None };
+        let zstd = Codec { compression: Compression::Zstd, keyring: None };
+        assert!(lz4.wrap(&records).unwrap().len() < records.len() / 2);
+        assert!(zstd.wrap(&records).unwrap().len() < records.len() / 2);
+    }
+
+    #[test]
+    fn test_legacy_snapshot_is_read_as_records() {
+        // sample_view entries are at version 1, which is what legacy records are upgraded to
+        let records = encode(&[sample_view(3)]);
+        assert_eq!(Codec::default().unwrap(legacy(&records)).unwrap(), records);
+    }
+
+    #[test]
+    fn test_unknown_compression_is_rejected() {
+        let mut wrapped = Codec::default().wrap(&encode(&[sample_view(1)])).unwrap();
+        wrapped[5] = 9;
+        assert!(Codec::default().unwrap(wrapped).is_err());
+    }
+
+    #[test]
+    fn test_version_one_snapshot_still_loads() {
+        let records = encode(&[sample_view(3)]);
+        let mut wrapped = b"KVOS\x01\x00\x00\x00".to_vec();
+        wrapped.extend_from_slice(&legacy(&records));
+        assert_eq!(Codec::default().unwrap(wrapped).unwrap(), records);
+    }
+
+    #[test]
+    fn test_version_three_snapshot_is_streamed_with_versions() {
+        let dir = TempDir::new().unwrap();
+        let records = encode(&[sample_view(5)]);
+        let old = legacy(&records);
+        let checksum = crc32fast::hash(&old).to_be_bytes();
+        let mut wrapped = vec![b'K', b'V', b'O', b'S', 3, 0, 0, 0, checksum[0], checksum[1], checksum[2], checksum[3]];
+        wrapped.extend_from_slice(&old);
+        let path = dir.path().join(SNAPSHOT_FILE);
+        fs::write(&path, wrapped).unwrap();
+
+        let mut reader = RecordReader::open(&path, &Codec::default()).unwrap();
+        let mut batch = Vec::new();
+        assert!(reader.next_batch(&mut batch).unwrap());
+        assert_eq!(batch, records);
+        assert!(decode(&batch).all(|(namespace, _, value)| namespace == 0 && storage::version(&value) == 1));
+    }
+
+    #[test]
+    fn test_version_four_snapshot_loads_into_default_namespace() {
+        let records = encode(&[sample_view(3)]);
+        let old = version_four(&records);
+        let checksum = crc32fast::hash(&old).to_be_bytes();
+        let mut wrapped = vec![b'K', b'V', b'O', b'S', 4, 0, 0, 0, checksum[0], checksum[1], checksum[2], checksum[3]];
+        wrapped.extend_from_slice(&old);
+        assert_eq!(Codec::default().unwrap(wrapped).unwrap(), records);
+    }
+
+    #[test]
+    fn test_checksum_detects_corruption() {
+        let records = encode(&[sample_view(3)]);
+        let mut wrapped = Codec::default().wrap(&records).unwrap();
+        wrapped[HEADER_SIZE + 70] ^= 0xff;
+        assert!(Codec::default().unwrap(wrapped).is_err());
+    }
+
+    #[test]
+    fn test_partial_decompression_recovers_prefix() {
+        let records = encode(&[sample_view(20_000)]);
+        let compressed = Compression::Zstd.compress(&records).unwrap();
+        let (recovered, error) = Compression::Zstd.decompress_partial(&compressed[..compressed.len() / 2]);
+
+        assert!(error.is_some());
+        assert!(!recovered.is_empty());
+        assert_eq!(&records[..recovered.len()], &recovered[..]);
+    }
+
+    #[test]
+    fn test_decode_skips_empty_and_partial_records() {
+        let mut records = encode(&[sample_view(2)]);
+        records.extend_from_slice(&[0u8; RECORD_SIZE]);
+        records.extend_from_slice(&[1u8; 10]);
+        assert_eq!(decode(&records).count(), 2);
+    }
+
+    fn encrypted_codec(keys: &str) -> Codec {
+        Codec { compression: Compression::Lz4, keyring: Some(Keyring::parse(keys).unwrap()) }
+    }
+
+    #[test]
+    fn test_encrypted_round_trip() {
+        let records = encode(&[sample_view(10)]);
+        let codec = encrypted_codec(&"11".repeat(32));
+        let wrapped = codec.wrap(&records).unwrap();
+
+        assert_eq!(wrapped[6] & FLAG_ENCRYPTED, FLAG_ENCRYPTED);
+        assert!(!wrapped.windows(8).any(|w| w == b"session:"));
+        assert_eq!(codec.unwrap(wrapped).unwrap(), records);
+    }
+
+    #[test]
+    fn test_encrypted_snapshot_needs_key() {
+        let wrapped = encrypted_codec(&"11".repeat(32)).wrap(&encode(&[sample_view(1)])).unwrap();
+        assert!(Codec::default().unwrap(wrapped.clone()).is_err());
+        assert!(encrypted_codec(&"22".repeat(32)).unwrap(wrapped).is_err());
+    }
+
+    #[test]
+    fn test_header_is_authenticated() {
+        let codec = encrypted_codec(&"11".repeat(32));
+        let mut wrapped = codec.wrap(&encode(&[sample_view(1)])).unwrap();
+        wrapped[5] = Compression::Zstd.id();
+        assert!(codec.unwrap(wrapped).is_err());
+    }
+
+    #[test]
+    fn test_plain_snapshot_loads_with_key_configured() {
+        let records = encode(&[sample_view(3)]);
+        let wrapped = Codec::default().wrap(&records).unwrap();
+        assert_eq!(encrypted_codec(&"11".repeat(32)).unwrap(wrapped).unwrap(), records);
+    }
+
+    #[test]
+    fn test_parse_compression() {
+        assert_eq!("LZ4".parse::<Compression>(), Ok(Compression::Lz4));
+        assert_eq!("zstd".parse::<Compression>(), Ok(Compression::Zstd));
+        assert_eq!("none".parse::<Compression>(), Ok(Compression::None));
+        assert!("gzip".parse::<Compression>().is_err());
+    }
+
+    #[test]
+    fn test_failed_generation_is_logged() {
+        let dir = TempDir::new().unwrap();
+        let status = SaveStatus::new();
+        let logger = logger(&dir);
+        // A file where the generations directory should be
+        fs::write(dir.path().join(generations::GENERATIONS_DIR), b"").unwrap();
+
+        let retention = Retention { keep: 2, max_age: None };
+        assert!(save(dir.path(), status.next_seq(), &[sample_view(1)], &status, &Codec::default(), &retention, &logger).unwrap().is_some());
+        logger.flush().unwrap();
+
+        let log = fs::read_to_string(dir.path().join("log.log")).unwrap();
+        assert!(log.contains("\"level\":\"error\",\"component\":\"snapshot\",\"msg\":\"recording snapshot generation failed"));
+    }
+
+    #[test]
+    fn test_save_records_generations() {
+        let dir = TempDir::new().unwrap();
+        let status = SaveStatus::new();
+        let retention = Retention { keep: 2, max_age: None };
+
+        for count in 1..=3 {
+            save(dir.path(), status.next_seq(), &[sample_view(count)], &status, &Codec::default(), &retention, &logger(&dir)).unwrap();
+            std::thread::sleep(std::time::Duration::from_millis(2));
+        }
+
+        let kept = generations::list(dir.path()).unwrap();
+        assert_eq!(kept.len(), 2);
+        assert_eq!(read(&kept[0].path, &Codec::default()).unwrap().len(), 2 * RECORD_SIZE);
+        assert_eq!(read(&kept[1].path, &Codec::default()).unwrap().len(), 3 * RECORD_SIZE);
+    }
+
+    // Size and load time per codec: cargo test --release bench_snapshot_compression -- --ignored --nocapture
+    #[test]
+    #[ignore]
+    fn bench_snapshot_compression() {
+        let dir = TempDir::new().unwrap();
+        let records = encode(&[sample_view(500_000)]);
+        println!("{:<6} {:>12} {:>8} {:>10} {:>10}", "codec", "bytes", "ratio", "save ms", "load ms");
+
+        for compression in [Compression::None, Compression::Lz4, Compression::Zstd] {
+            let path = dir.path().join(format!("bench-{}", compression));
+
+            let codec = Codec { compression, keyring: None };
+
+            let start = Instant::now();
+            let wrapped = codec.wrap(&records).unwrap();
+            write_atomic(&path, &wrapped).unwrap();
+            let save_ms = start.elapsed().as_millis();
+
+            let start = Instant::now();
+            let loaded = read(&path, &codec).unwrap();
+            let load_ms = start.elapsed().as_millis();
+
+            assert_eq!(loaded.len(), records.len());
+            println!(
+                "{:<6} {:>12} {:>8.3} {:>10} {:>10}",
+                compression.to_string(), wrapped.len(), wrapped.len() as f64 / records.len() as f64, save_ms, load_ms
+            );
+        }
+    }
+}
@@ -0,0 +1,249 @@
+use std::{fmt, mem, sync::atomic::{AtomicU64, Ordering}, time::Duration};
+use crate::{config::MAX_NAMESPACES, latency::{self, Latency, Operation, Summary}, storage::{Key, Value}};
+
+/*
+    T (STATS) replies with one line of space separated name=value fields:
+    - keys, expired (keys removed or skipped on load because their TTL ran out), hits and misses of
+      G, V and M lookups, memory_bytes (estimate for entries held in memory), uptime (seconds)
+    - last_save (epoch seconds, 0 before the first save), last_save_ms, last_save_ok, save (running or
+      idle), pool_active and pool_queued (background jobs running and waiting)
+    - ns<n>_keys, ns<n>_hits and ns<n>_misses for each namespace opened since startup
+    - latency_<operation>_<p50|p99|p999>_us for G, I, R, batch and snapshot, 0 until one has been timed
+    - ops_<command> for each command received at least once, e.g. ops_G=12
+ */
+
+// Upper bounds of the snapshot duration histogram buckets, in milliseconds
+pub const SNAPSHOT_BUCKETS_MS: [u64; 12] = [1, 5, 10, 25, 50, 100, 250, 500, 1000, 2500, 5000, 10000];
+
+// Key, value and the hash table's control byte
+pub const ENTRY_BYTES: usize = mem::size_of::<Key>() + mem::size_of::<Value>() + 1;
+
+// Counters updated by the command loop and background jobs
+pub struct Stats {
+    hits: AtomicU64,
+    misses: AtomicU64,
+    // Hits and misses per namespace
+    namespace_lookups: [[AtomicU64; 2]; MAX_NAMESPACES],
+    expired: AtomicU64,
+    commands: [AtomicU64; 256],
+    // Entries in the cache once startup loading finished
+    loaded: AtomicU64,
+    // Snapshots written, per duration bucket and in total, for the metrics endpoint
+    snapshot_buckets: [AtomicU64; SNAPSHOT_BUCKETS_MS.len()],
+    snapshot_count: AtomicU64,
+    snapshot_ms: AtomicU64,
+    latency: Latency,
+}
+
+impl Stats {
+    pub fn new() -> Self {
+        Stats {
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+            namespace_lookups: std::array::from_fn(|_| [AtomicU64::new(0), AtomicU64::new(0)]),
+            expired: AtomicU64::new(0),
+            commands: std::array::from_fn(|_| AtomicU64::new(0)),
+            loaded: AtomicU64::new(0),
+            snapshot_buckets: std::array::from_fn(|_| AtomicU64::new(0)),
+            snapshot_count: AtomicU64::new(0),
+            snapshot_ms: AtomicU64::new(0),
+            latency: Latency::new(),
+        }
+    }
+
+    pub fn command(&self, command: u8) {
+        self.commands[command as usize].fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn lookup(&self, namespace: usize, hit: bool) {
+        let counter = if hit { &self.hits } else { &self.misses };
+        counter.fetch_add(1, Ordering::Relaxed);
+        self.namespace_lookups[namespace][!hit as usize].fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn expired(&self, count: usize) {
+        self.expired.fetch_add(count as u64, Ordering::Relaxed);
+    }
+
+    pub fn loaded(&self, entries: usize) {
+        self.loaded.store(entries as u64, Ordering::Relaxed);
+    }
+
+    pub fn loaded_entries(&self) -> u64 {
+        self.loaded.load(Ordering::Relaxed)
+    }
+
+    pub fn latency(&self) -> &Latency {
+        &self.latency
+    }
+
+    // A snapshot written to disk, from capturing the view to the finished file
+    pub fn snapshot(&self, elapsed: Duration) {
+        self.latency.record(Operation::Snapshot, elapsed);
+        let ms = elapsed.as_millis() as u64;
+        if let Some(bucket) = SNAPSHOT_BUCKETS_MS.iter().position(|&bound| ms <= bound) {
+            self.snapshot_buckets[bucket].fetch_add(1, Ordering::Relaxed);
+        }
+        self.snapshot_count.fetch_add(1, Ordering::Relaxed);
+        self.snapshot_ms.fetch_add(ms, Ordering::Relaxed);
+    }
+
+    // Cumulative counts per bucket, then the total count and the sum in milliseconds
+    pub fn snapshot_histogram(&self) -> (Vec<u64>, u64, u64) {
+        let buckets = self
+            .snapshot_buckets
+            .iter()
+            .scan(0, |total, bucket| {
+                *total += bucket.load(Ordering::Relaxed);
+                Some(*total)
+            })
+            .collect();
+        (buckets, self.snapshot_count.load(Ordering::Relaxed), self.snapshot_ms.load(Ordering::Relaxed))
+    }
+
+    pub fn hits(&self) -> u64 {
+        self.hits.load(Ordering::Relaxed)
+    }
+
+    pub fn misses(&self) -> u64 {
+        self.misses.load(Ordering::Relaxed)
+    }
+
+    // Hits and misses of lookups in one namespace
+    pub fn namespace_lookups(&self, namespace: usize) -> (u64, u64) {
+        let [hits, misses] = &self.namespace_lookups[namespace];
+        (hits.load(Ordering::Relaxed), misses.load(Ordering::Relaxed))
+    }
+
+    // Commands received so far, in byte order, skipping those never seen
+    pub fn ops(&self) -> Vec<(u8, u64)> {
+        (0..=u8::MAX)
+            .map(|command| (command, self.commands[command as usize].load(Ordering::Relaxed)))
+            .filter(|(_, count)| *count > 0)
+            .collect()
+    }
+}
+
+impl Default for Stats {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct NamespaceInfo {
+    pub namespace: usize,
+    pub keys: usize,
+    pub hits: u64,
+    pub misses: u64,
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Info {
+    pub keys: usize,
+    pub expired: u64,
+    pub hits: u64,
+    pub misses: u64,
+    pub memory_bytes: usize,
+    pub uptime: i64,
+    pub last_save: i64,
+    pub last_save_ms: u64,
+    pub last_save_ok: bool,
+    pub saving: bool,
+    pub pool_active: usize,
+    pub pool_queued: usize,
+    pub namespaces: Vec<NamespaceInfo>,
+    pub ops: Vec<(u8, u64)>,
+    pub latency: Vec<(Operation, Summary)>,
+}
+
+impl Info {
+    pub fn gather(stats: &Stats) -> Self {
+        Info {
+            expired: stats.expired.load(Ordering::Relaxed),
+            hits: stats.hits(),
+            misses: stats.misses(),
+            ops: stats.ops(),
+            latency: Operation::ALL.iter().map(|&operation| (operation, stats.latency.summary(operation))).collect(),
+            ..Default::default()
+        }
+    }
+}
+
+impl fmt::Display for Info {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "keys={} expired={} hits={} misses={} memory_bytes={} uptime={} last_save={} last_save_ms={} last_save_ok={} save={} pool_active={} pool_queued={}",
+            self.keys, self.expired, self.hits, self.misses, self.memory_bytes, self.uptime,
+            self.last_save, self.last_save_ms, self.last_save_ok,
+            if self.saving { "running" } else { "idle" },
+            self.pool_active, self.pool_queued
+        )?;
+        for namespace in &self.namespaces {
+            write!(f, " ns{0}_keys={1} ns{0}_hits={2} ns{0}_misses={3}", namespace.namespace, namespace.keys, namespace.hits, namespace.misses)?;
+        }
+        for (operation, summary) in &self.latency {
+            for ((name, _), micros) in latency::QUANTILES.iter().zip(summary.quantiles) {
+                write!(f, " latency_{}_{}_us={}", operation, name, micros)?;
+            }
+        }
+        for (command, count) in &self.ops {
+            // Printable commands by name, anything else as hex so the line stays one word per field
+            if command.is_ascii_graphic() {
+                write!(f, " ops_{}={}", *command as char, count)?;
+            } else {
+                write!(f, " ops_0x{:02x}={}", command, count)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_counters_and_line() {
+        let stats = Stats::new();
+        stats.command(b'G');
+        stats.command(b'G');
+        stats.command(b'+');
+        stats.command(0);
+        stats.lookup(0, true);
+        stats.lookup(0, false);
+        stats.lookup(2, false);
+        stats.expired(3);
+
+        let mut info = Info::gather(&stats);
+        assert_eq!((info.hits, info.misses, info.expired), (1, 2, 3));
+        assert_eq!(info.ops, vec![(0, 1), (b'+', 1), (b'G', 2)]);
+        assert_eq!((stats.namespace_lookups(0), stats.namespace_lookups(2)), ((1, 1), (0, 1)));
+
+        info.keys = 7;
+        info.saving = true;
+        info.namespaces = vec![NamespaceInfo { namespace: 2, keys: 7, hits: 0, misses: 1 }];
+        let line = info.to_string();
+        assert!(line.starts_with("keys=7 expired=3 hits=1 misses=2 "));
+        assert!(line.contains(" save=running "));
+        assert!(line.contains(" pool_queued=0 ns2_keys=7 ns2_hits=0 ns2_misses=1 latency_G_p50_us=0 "));
+        assert!(line.contains(" latency_G_p50_us=0 latency_G_p99_us=0 latency_G_p999_us=0 latency_I_p50_us=0 "));
+        assert!(line.ends_with(" ops_0x00=1 ops_+=1 ops_G=2"));
+        assert!(!line.contains('\n'));
+    }
+
+    #[test]
+    fn test_snapshot_histogram() {
+        let stats = Stats::new();
+        stats.snapshot(Duration::ZERO);
+        stats.snapshot(Duration::from_millis(7));
+        stats.snapshot(Duration::from_secs(60));
+
+        let (buckets, count, sum) = stats.snapshot_histogram();
+        assert_eq!(&buckets[..4], &[1, 1, 2, 2]);
+        assert_eq!(*buckets.last().unwrap(), 2);
+        assert_eq!((count, sum), (3, 60_007));
+        assert_eq!(stats.latency().summary(Operation::Snapshot).count, 3);
+    }
+}
@@ -0,0 +1,166 @@
+use std::{io, ops::ControlFlow, path::Path};
+use redb::{Database, Durability, ReadableTable, ReadableTableMetadata, TableDefinition, TableHandle};
+use crate::snapshot::SnapshotView;
+use super::{stored, Key, StorageBackend, Value, WireValue};
+
+pub const FILE_NAME: &str = "cache.redb";
+
+const ENTRIES: TableDefinition<Key, Value> = TableDefinition::new("entries_v2");
+// Before versions were stored, values were the bare wire value
+const LEGACY_ENTRIES: TableDefinition<Key, WireValue> = TableDefinition::new("entries");
+
+// Embedded copy-on-write B-tree (redb). Every write is its own transaction; commits are flushed to
+// disk shortly after they return, and immediately on flush.
+pub struct BtreeBackend {
+    db: Database,
+}
+
+impl BtreeBackend {
+    pub fn open(path: &Path) -> io::Result<Self> {
+        let db = Database::create(path).map_err(db_error)?;
+
+        // Create the table up front so readers never see it missing
+        let txn = db.begin_write().map_err(db_error)?;
+        {
+            let mut table = txn.open_table(ENTRIES).map_err(db_error)?;
+
+            // Upgrade entries from before versions; they start at version 1
+            let has_legacy = txn.list_tables().map_err(db_error)?.any(|handle| handle.name() == LEGACY_ENTRIES.name());
+            if has_legacy {
+                let legacy = txn.open_table(LEGACY_ENTRIES).map_err(db_error)?;
+                for entry in legacy.iter().map_err(db_error)? {
+                    let (key, value) = entry.map_err(db_error)?;
+                    table.insert(key.value(), stored(&value.value(), 1)).map_err(db_error)?;
+                }
+                drop(legacy);
+                txn.delete_table(LEGACY_ENTRIES).map_err(db_error)?;
+            }
+        }
+        txn.commit().map_err(db_error)?;
+
+        Ok(BtreeBackend { db })
+    }
+
+    fn write<T>(&self, durability: Durability, apply: impl FnOnce(&mut redb::Table<Key, Value>) -> Result<T, redb::StorageError>) -> io::Result<T> {
+        let mut txn = self.db.begin_write().map_err(db_error)?;
+        txn.set_durability(durability);
+        let result = {
+            let mut table = txn.open_table(ENTRIES).map_err(db_error)?;
+            apply(&mut table).map_err(db_error)?
+        };
+        txn.commit().map_err(db_error)?;
+        Ok(result)
+    }
+}
+
+impl StorageBackend for BtreeBackend {
+    fn name(&self) -> &'static str {
+        "btree"
+    }
+
+    fn get(&self, key: &Key) -> io::Result<Option<Value>> {
+        let txn = self.db.begin_read().map_err(db_error)?;
+        let table = txn.open_table(ENTRIES).map_err(db_error)?;
+        Ok(table.get(key).map_err(db_error)?.map(|value| value.value()))
+    }
+
+    fn put(&self, key: Key, value: Value) -> io::Result<Option<Value>> {
+        self.write(Durability::Eventual, |table| {
+            Ok(table.insert(key, value)?.map(|previous| previous.value()))
+        })
+    }
+
+    fn update(&self, key: &Key, apply: &mut dyn FnMut(Option<&Value>) -> Option<Value>) -> io::Result<bool> {
+        self.write(Durability::Eventual, |table| {
+            let current = table.get(key)?.map(|value| value.value());
+            let Some(value) = apply(current.as_ref()) else {
+                return Ok(false);
+            };
+            table.insert(key, value)?;
+            Ok(true)
+        })
+    }
+
+    fn update_many(&self, keys: &[Key], apply: &mut dyn FnMut(usize, Option<&Value>) -> Option<Value>) -> io::Result<usize> {
+        self.write(Durability::Eventual, |table| {
+            let mut written = 0;
+            for (index, key) in keys.iter().enumerate() {
+                let current = table.get(key)?.map(|value| value.value());
+                if let Some(value) = apply(index, current.as_ref()) {
+                    table.insert(key, value)?;
+                    written += 1;
+                }
+            }
+            Ok(written)
+        })
+    }
+
+    fn delete(&self, key: &Key) -> io::Result<Option<Value>> {
+        self.write(Durability::Eventual, |table| {
+            Ok(table.remove(key)?.map(|previous| previous.value()))
+        })
+    }
+
+    fn delete_where(&self, matches: &mut dyn FnMut(&Key, &Value) -> bool) -> io::Result<usize> {
+        self.write(Durability::Eventual, |table| {
+            let mut removed = 0;
+            table.retain(|key, value| {
+                let matched = matches(&key, &value);
+                removed += matched as usize;
+                !matched
+            })?;
+            Ok(removed)
+        })
+    }
+
+    fn len(&self) -> io::Result<usize> {
+        let txn = self.db.begin_read().map_err(db_error)?;
+        let table = txn.open_table(ENTRIES).map_err(db_error)?;
+        Ok(table.len().map_err(db_error)? as usize)
+    }
+
+    // Runs inside one read transaction, so concurrent writes are not observed
+    fn iter(&self, visit: &mut dyn FnMut(&Key, &Value) -> ControlFlow<()>) -> io::Result<()> {
+        let txn = self.db.begin_read().map_err(db_error)?;
+        let table = txn.open_table(ENTRIES).map_err(db_error)?;
+        for entry in table.iter().map_err(db_error)? {
+            let (key, value) = entry.map_err(db_error)?;
+            if visit(&key.value(), &value.value()).is_break() {
+                break;
+            }
+        }
+        Ok(())
+    }
+
+    fn snapshot(&self) -> io::Result<SnapshotView> {
+        let mut view = SnapshotView::with_capacity(self.len()?);
+        self.iter(&mut |key, value| {
+            view.insert(*key, *value);
+            ControlFlow::Continue(())
+        })?;
+        Ok(view)
+    }
+
+    fn replace(&self, entries: Vec<(Key, Value)>) -> io::Result<()> {
+        self.write(Durability::Immediate, |table| {
+            table.retain(|_, _| false)?;
+            for (key, value) in &entries {
+                table.insert(key, value)?;
+            }
+            Ok(())
+        })
+    }
+
+    fn is_persistent(&self) -> bool {
+        true
+    }
+
+    fn flush(&self) -> io::Result<()> {
+        // An immediate commit also makes the earlier eventual ones durable
+        self.write(Durability::Immediate, |_| Ok(()))
+    }
+}
+
+fn db_error(e: impl Into<redb::Error>) -> io::Error {
+    io::Error::other(e.into())
+}
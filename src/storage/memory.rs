@@ -0,0 +1,88 @@
+use std::{io, ops::ControlFlow, sync::RwLock};
+use crate::snapshot::SnapshotView;
+use super::{Key, StorageBackend, Value};
+
+// The original storage: everything in one hash map, persisted only through snapshots
+#[derive(Debug, Default)]
+pub struct MemoryBackend {
+    // Using RwLock instead of Mutex for better read concurrency
+    vals: RwLock<hashbrown::HashMap<Key, Value>>,
+}
+
+impl MemoryBackend {
+    pub fn new() -> Self {
+        MemoryBackend {
+            vals: RwLock::new(hashbrown::HashMap::with_capacity(10000)),
+        }
+    }
+}
+
+impl StorageBackend for MemoryBackend {
+    fn name(&self) -> &'static str {
+        "memory"
+    }
+
+    fn get(&self, key: &Key) -> io::Result<Option<Value>> {
+        Ok(self.vals.read().unwrap().get(key).copied())
+    }
+
+    fn put(&self, key: Key, value: Value) -> io::Result<Option<Value>> {
+        Ok(self.vals.write().unwrap().insert(key, value))
+    }
+
+    fn update(&self, key: &Key, apply: &mut dyn FnMut(Option<&Value>) -> Option<Value>) -> io::Result<bool> {
+        let mut vals = self.vals.write().unwrap();
+        let Some(value) = apply(vals.get(key)) else {
+            return Ok(false);
+        };
+        vals.insert(*key, value);
+        Ok(true)
+    }
+
+    fn update_many(&self, keys: &[Key], apply: &mut dyn FnMut(usize, Option<&Value>) -> Option<Value>) -> io::Result<usize> {
+        let mut vals = self.vals.write().unwrap();
+        let mut written = 0;
+        for (index, key) in keys.iter().enumerate() {
+            if let Some(value) = apply(index, vals.get(key)) {
+                vals.insert(*key, value);
+                written += 1;
+            }
+        }
+        Ok(written)
+    }
+
+    fn delete(&self, key: &Key) -> io::Result<Option<Value>> {
+        Ok(self.vals.write().unwrap().remove(key))
+    }
+
+    fn delete_where(&self, matches: &mut dyn FnMut(&Key, &Value) -> bool) -> io::Result<usize> {
+        let mut vals = self.vals.write().unwrap();
+        let before = vals.len();
+        vals.retain(|key, value| !matches(key, value));
+        Ok(before - vals.len())
+    }
+
+    fn len(&self) -> io::Result<usize> {
+        Ok(self.vals.read().unwrap().len())
+    }
+
+    fn iter(&self, visit: &mut dyn FnMut(&Key, &Value) -> ControlFlow<()>) -> io::Result<()> {
+        for (key, value) in self.vals.read().unwrap().iter() {
+            if visit(key, value).is_break() {
+                break;
+            }
+        }
+        Ok(())
+    }
+
+    fn snapshot(&self) -> io::Result<SnapshotView> {
+        Ok(self.vals.read().unwrap().clone())
+    }
+
+    fn replace(&self, entries: Vec<(Key, Value)>) -> io::Result<()> {
+        let mut vals = hashbrown::HashMap::with_capacity(entries.len().max(10000));
+        vals.extend(entries);
+        *self.vals.write().unwrap() = vals;
+        Ok(())
+    }
+}
@@ -0,0 +1,382 @@
+use std::{fs::{self, File, OpenOptions}, io::{self, Read}, ops::ControlFlow, path::{Path, PathBuf}, sync::RwLock};
+use memmap2::{Mmap, MmapMut};
+use crate::snapshot::SnapshotView;
+use super::{stored, Key, StorageBackend, Value, VALUE_SIZE};
+
+pub const FILE_NAME: &str = "cache.mmap";
+
+/*
+    Memory-mapped hash table, so the key space can exceed RAM and is paged by the OS:
+    - 128 byte header: magic "KVOM", version (u32), capacity, live and deleted slot counts (u64 each)
+    - capacity slots of 136 bytes: state byte, 63 byte key, 72 byte value (wire value and version)
+    Lookups use linear probing from an FNV-1a hash of the key. The table is rebuilt into a new file
+    when it gets too full. There is no journal; writes reach disk on flush or when the OS evicts pages.
+ */
+const MAGIC: &[u8; 4] = b"KVOM";
+const VERSION: u32 = 2;
+// Version 1 slots held the 64 byte wire value without a version
+const V1_SLOT_SIZE: usize = 128;
+const HEADER_SIZE: usize = 128;
+const SLOT_SIZE: usize = 64 + VALUE_SIZE;
+const INITIAL_CAPACITY: usize = 1024;
+// Rebuild once live and deleted slots take up this share of the table, in percent
+const MAX_LOAD_PERCENT: usize = 70;
+
+const EMPTY: u8 = 0;
+const USED: u8 = 1;
+const DELETED: u8 = 2;
+
+struct Table {
+    map: MmapMut,
+    capacity: usize,
+    len: usize,
+    deleted: usize,
+}
+
+impl Table {
+    fn create(path: &Path, capacity: usize) -> io::Result<Table> {
+        let file = OpenOptions::new().read(true).write(true).create(true).truncate(true).open(path)?;
+        file.set_len((HEADER_SIZE + capacity * SLOT_SIZE) as u64)?;
+
+        // Safety: the file is private to this process and only accessed through this mapping
+        let map = unsafe { MmapMut::map_mut(&file)? };
+        let mut table = Table { map, capacity, len: 0, deleted: 0 };
+        table.map[0..4].copy_from_slice(MAGIC);
+        table.map[4..8].copy_from_slice(&VERSION.to_le_bytes());
+        table.write_counts();
+        Ok(table)
+    }
+
+    fn open(path: &Path) -> io::Result<Table> {
+        if !path.exists() {
+            return Table::create(path, INITIAL_CAPACITY);
+        }
+
+        let mut file = OpenOptions::new().read(true).write(true).open(path)?;
+        let Some(header) = read_header(&mut file)? else {
+            return Err(invalid_data(format!("{} is not a mmap storage file", path.display())));
+        };
+        let version = header_version(&header);
+        if version != VERSION {
+            return Err(invalid_data(format!("unsupported mmap storage version {}", version)));
+        }
+
+        // The header is untrusted, so check it against the file before mapping
+        let (capacity, len, deleted) = (header_u64(&header, 8), header_u64(&header, 16), header_u64(&header, 24));
+        let expected_len = capacity.checked_mul(SLOT_SIZE).and_then(|slots| slots.checked_add(HEADER_SIZE));
+        let used = len.checked_add(deleted);
+        if capacity == 0 || expected_len != Some(file.metadata()?.len() as usize) || used.is_none_or(|used| used > capacity) {
+            return Err(invalid_data(format!("{} has an inconsistent header", path.display())));
+        }
+
+        // Safety: as in create
+        let map = unsafe { MmapMut::map_mut(&file)? };
+        Ok(Table { map, capacity, len, deleted })
+    }
+
+    fn write_counts(&mut self) {
+        self.map[8..16].copy_from_slice(&(self.capacity as u64).to_le_bytes());
+        self.map[16..24].copy_from_slice(&(self.len as u64).to_le_bytes());
+        self.map[24..32].copy_from_slice(&(self.deleted as u64).to_le_bytes());
+    }
+
+    fn slot(&self, index: usize) -> &[u8] {
+        let start = HEADER_SIZE + index * SLOT_SIZE;
+        &self.map[start..start + SLOT_SIZE]
+    }
+
+    fn slot_mut(&mut self, index: usize) -> &mut [u8] {
+        let start = HEADER_SIZE + index * SLOT_SIZE;
+        &mut self.map[start..start + SLOT_SIZE]
+    }
+
+    // Ok with the slot holding the key, or Err with the slot an insert should use
+    fn find(&self, key: &Key) -> Result<usize, usize> {
+        let start = (fnv1a(key) % self.capacity as u64) as usize;
+        let mut first_deleted = None;
+
+        for probe in 0..self.capacity {
+            let index = (start + probe) % self.capacity;
+            let slot = self.slot(index);
+            match slot[0] {
+                EMPTY => return Err(first_deleted.unwrap_or(index)),
+                DELETED => {
+                    first_deleted.get_or_insert(index);
+                }
+                _ if &slot[1..64] == key => return Ok(index),
+                _ => {}
+            }
+        }
+
+        // The load limit always leaves an empty or deleted slot
+        Err(first_deleted.expect("mmap storage table is full"))
+    }
+
+    fn value_at(&self, index: usize) -> Value {
+        self.slot(index)[64..SLOT_SIZE].try_into().unwrap()
+    }
+
+    fn insert_new(&mut self, index: usize, key: &Key, value: &Value) {
+        if self.slot(index)[0] == DELETED {
+            self.deleted -= 1;
+        }
+        let slot = self.slot_mut(index);
+        slot[0] = USED;
+        slot[1..64].copy_from_slice(key);
+        slot[64..SLOT_SIZE].copy_from_slice(value);
+        self.len += 1;
+    }
+
+    fn entries(&self) -> impl Iterator<Item = (&[u8], &[u8])> {
+        (0..self.capacity)
+            .map(|index| self.slot(index))
+            .filter(|slot| slot[0] == USED)
+            .map(|slot| (&slot[1..64], &slot[64..SLOT_SIZE]))
+    }
+}
+
+pub struct MmapBackend {
+    path: PathBuf,
+    table: RwLock<Table>,
+}
+
+impl MmapBackend {
+    pub fn open(path: &Path) -> io::Result<Self> {
+        let version = match File::open(path) {
+            Ok(mut file) => read_header(&mut file)?.map(|header| header_version(&header)),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => None,
+            Err(e) => return Err(e),
+        };
+        let table = match version {
+            Some(1) => upgrade_v1(path)?,
+            _ => Table::open(path)?,
+        };
+
+        Ok(MmapBackend {
+            path: path.to_path_buf(),
+            table: RwLock::new(table),
+        })
+    }
+
+    // Insert a key known to be absent, rebuilding the table first if it is too full
+    fn insert_missing(&self, table: &mut Table, key: &Key, value: &Value) -> io::Result<()> {
+        if (table.len + table.deleted + 1) * 100 > table.capacity * MAX_LOAD_PERCENT {
+            // Grow when mostly live, otherwise just clear out deleted slots
+            let capacity = if (table.len + 1) * 200 > table.capacity * MAX_LOAD_PERCENT {
+                table.capacity * 2
+            } else {
+                table.capacity
+            };
+            let rebuilt = rebuild(&self.path, capacity, table.entries().map(owned))?;
+            *table = rebuilt;
+        }
+
+        let index = table.find(key).expect_err("key was just looked up");
+        table.insert_new(index, key, value);
+        table.write_counts();
+        Ok(())
+    }
+
+    // update with the table already locked
+    fn update_locked(&self, table: &mut Table, key: &Key, apply: &mut dyn FnMut(Option<&Value>) -> Option<Value>) -> io::Result<bool> {
+        match table.find(key) {
+            Ok(index) => {
+                let Some(value) = apply(Some(&table.value_at(index))) else {
+                    return Ok(false);
+                };
+                table.slot_mut(index)[64..SLOT_SIZE].copy_from_slice(&value);
+            }
+            Err(_) => {
+                let Some(value) = apply(None) else {
+                    return Ok(false);
+                };
+                self.insert_missing(table, key, &value)?;
+            }
+        }
+        Ok(true)
+    }
+}
+
+// Write the given entries into a fresh table file that replaces the one at `path`
+fn rebuild<I>(path: &Path, capacity: usize, entries: I) -> io::Result<Table>
+where
+    I: Iterator<Item = (Key, Value)>,
+{
+    let mut tmp_name = path.file_name().map(|name| name.to_os_string()).unwrap_or_default();
+    tmp_name.push(".tmp");
+    let tmp_path = path.with_file_name(tmp_name);
+
+    let mut rebuilt = Table::create(&tmp_path, capacity)?;
+    for (key, value) in entries {
+        match rebuilt.find(&key) {
+            Ok(index) => rebuilt.slot_mut(index)[64..SLOT_SIZE].copy_from_slice(&value),
+            Err(index) => rebuilt.insert_new(index, &key, &value),
+        }
+    }
+    rebuilt.write_counts();
+    rebuilt.map.flush()?;
+
+    fs::rename(&tmp_path, path)?;
+    Ok(rebuilt)
+}
+
+// Table size that keeps `len` entries well under the load limit
+fn capacity_for(len: usize) -> usize {
+    (len * 200 / MAX_LOAD_PERCENT).max(INITIAL_CAPACITY)
+}
+
+impl StorageBackend for MmapBackend {
+    fn name(&self) -> &'static str {
+        "mmap"
+    }
+
+    fn get(&self, key: &Key) -> io::Result<Option<Value>> {
+        let table = self.table.read().unwrap();
+        Ok(table.find(key).ok().map(|index| table.value_at(index)))
+    }
+
+    fn put(&self, key: Key, value: Value) -> io::Result<Option<Value>> {
+        let mut table = self.table.write().unwrap();
+
+        if let Ok(index) = table.find(&key) {
+            let previous = table.value_at(index);
+            table.slot_mut(index)[64..SLOT_SIZE].copy_from_slice(&value);
+            return Ok(Some(previous));
+        }
+
+        self.insert_missing(&mut table, &key, &value)?;
+        Ok(None)
+    }
+
+    fn update(&self, key: &Key, apply: &mut dyn FnMut(Option<&Value>) -> Option<Value>) -> io::Result<bool> {
+        let mut table = self.table.write().unwrap();
+        self.update_locked(&mut table, key, apply)
+    }
+
+    fn update_many(&self, keys: &[Key], apply: &mut dyn FnMut(usize, Option<&Value>) -> Option<Value>) -> io::Result<usize> {
+        let mut table = self.table.write().unwrap();
+        let mut written = 0;
+        for (index, key) in keys.iter().enumerate() {
+            if self.update_locked(&mut table, key, &mut |current| apply(index, current))? {
+                written += 1;
+            }
+        }
+        Ok(written)
+    }
+
+    fn delete(&self, key: &Key) -> io::Result<Option<Value>> {
+        let mut table = self.table.write().unwrap();
+        let Ok(index) = table.find(key) else {
+            return Ok(None);
+        };
+
+        let previous = table.value_at(index);
+        table.slot_mut(index)[0] = DELETED;
+        table.len -= 1;
+        table.deleted += 1;
+        table.write_counts();
+        Ok(Some(previous))
+    }
+
+    fn delete_where(&self, matches: &mut dyn FnMut(&Key, &Value) -> bool) -> io::Result<usize> {
+        let mut table = self.table.write().unwrap();
+        let mut removed = 0;
+        for index in 0..table.capacity {
+            let slot = table.slot(index);
+            if slot[0] == USED && matches(slot[1..64].try_into().unwrap(), slot[64..SLOT_SIZE].try_into().unwrap()) {
+                table.slot_mut(index)[0] = DELETED;
+                removed += 1;
+            }
+        }
+        table.len -= removed;
+        table.deleted += removed;
+        table.write_counts();
+        Ok(removed)
+    }
+
+    fn len(&self) -> io::Result<usize> {
+        Ok(self.table.read().unwrap().len)
+    }
+
+    fn iter(&self, visit: &mut dyn FnMut(&Key, &Value) -> ControlFlow<()>) -> io::Result<()> {
+        let table = self.table.read().unwrap();
+        for (key, value) in table.entries() {
+            if visit(key.try_into().unwrap(), value.try_into().unwrap()).is_break() {
+                break;
+            }
+        }
+        Ok(())
+    }
+
+    fn snapshot(&self) -> io::Result<SnapshotView> {
+        let table = self.table.read().unwrap();
+        let mut view = SnapshotView::with_capacity(table.len);
+        for (key, value) in table.entries() {
+            view.insert(key.try_into().unwrap(), value.try_into().unwrap());
+        }
+        Ok(view)
+    }
+
+    fn replace(&self, entries: Vec<(Key, Value)>) -> io::Result<()> {
+        let mut table = self.table.write().unwrap();
+        *table = rebuild(&self.path, capacity_for(entries.len()), entries.into_iter())?;
+        Ok(())
+    }
+
+    fn is_persistent(&self) -> bool {
+        true
+    }
+
+    fn flush(&self) -> io::Result<()> {
+        self.table.read().unwrap().map.flush()
+    }
+}
+
+// The header of a storage file, None if it is too short or lacks the magic bytes
+fn read_header(file: &mut File) -> io::Result<Option<[u8; HEADER_SIZE]>> {
+    let mut header = [0u8; HEADER_SIZE];
+    match file.read_exact(&mut header) {
+        Ok(()) => Ok(Some(header).filter(|header| &header[0..4] == MAGIC)),
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+fn header_version(header: &[u8; HEADER_SIZE]) -> u32 {
+    u32::from_le_bytes(header[4..8].try_into().unwrap())
+}
+
+fn header_u64(header: &[u8; HEADER_SIZE], offset: usize) -> usize {
+    u64::from_le_bytes(header[offset..offset + 8].try_into().unwrap()) as usize
+}
+
+// Rewrite a version 1 file in the current format. The old file is mapped read-only and its slots are
+// streamed into the new table, so the upgrade does not need the whole file in memory; the entries
+// start at version 1.
+fn upgrade_v1(path: &Path) -> io::Result<Table> {
+    let mut file = File::open(path)?;
+    let header = read_header(&mut file)?.ok_or_else(|| invalid_data(format!("{} is not a mmap storage file", path.display())))?;
+    // Safety: the file is private to this process and not written while the upgrade runs
+    let old = unsafe { Mmap::map(&file)? };
+    let slots = old.len().saturating_sub(HEADER_SIZE) / V1_SLOT_SIZE;
+    let len = header_u64(&header, 16).min(slots);
+
+    let entries = old[HEADER_SIZE.min(old.len())..]
+        .chunks_exact(V1_SLOT_SIZE)
+        .filter(|slot| slot[0] == USED)
+        .map(|slot| (slot[1..64].try_into().unwrap(), stored(slot[64..V1_SLOT_SIZE].try_into().unwrap(), 1)));
+    rebuild(path, capacity_for(len), entries)
+}
+
+fn owned((key, value): (&[u8], &[u8])) -> (Key, Value) {
+    (key.try_into().unwrap(), value.try_into().unwrap())
+}
+
+// Stable across runs, unlike the std hasher, since slot positions are stored on disk
+fn fnv1a(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf2_9ce4_8422_2325, |hash, &byte| (hash ^ byte as u64).wrapping_mul(0x0100_0000_01b3))
+}
+
+fn invalid_data(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
@@ -0,0 +1,460 @@
+use std::{fmt, io, ops::ControlFlow, path::Path, str::FromStr, sync::Arc, time::{SystemTime, UNIX_EPOCH}};
+use crate::{config::Config, snapshot::SnapshotView};
+
+mod btree;
+mod memory;
+mod mmap;
+mod namespaces;
+mod tiered;
+
+pub use btree::BtreeBackend;
+pub use memory::MemoryBackend;
+pub use mmap::MmapBackend;
+pub use namespaces::Namespaces;
+pub use tiered::TieredBackend;
+
+// Cold tier files live apart from the files of a plain disk backend
+const COLD_TIER_DIR: &str = "cold";
+
+pub type Key = [u8; 63];
+// 56 value bytes, 6 byte timestamp and 2 byte expiry, exactly as sent in an insert frame
+pub type WireValue = [u8; 64];
+pub const VALUE_SIZE: usize = 72;
+// The wire value followed by the entry's version (u64, big endian)
+pub type Value = [u8; VALUE_SIZE];
+
+// The part of a stored value that clients see
+pub fn wire(value: &Value) -> &WireValue {
+    value[..64].try_into().unwrap()
+}
+
+pub fn version(value: &Value) -> u64 {
+    u64::from_be_bytes(value[64..].try_into().unwrap())
+}
+
+pub fn stored(wire: &WireValue, version: u64) -> Value {
+    let mut value = [0u8; VALUE_SIZE];
+    value[..64].copy_from_slice(wire);
+    value[64..].copy_from_slice(&version.to_be_bytes());
+    value
+}
+
+// Version for a write replacing `current`: one more than before. New keys start from the clock in
+// milliseconds, so a deleted and recreated key does not reuse the versions it had before, and the
+// version still fits the 48 bit field compare-and-swap frames carry it in.
+pub fn next_version(current: Option<&Value>) -> u64 {
+    match current {
+        Some(value) => version(value).wrapping_add(1).max(1),
+        None => SystemTime::now().duration_since(UNIX_EPOCH).map_or(1, |elapsed| elapsed.as_millis() as u64).max(1),
+    }
+}
+
+// Where the key space lives. Implementations do their own locking so the cache can share them with
+// background jobs.
+pub trait StorageBackend: Send + Sync {
+    fn name(&self) -> &'static str;
+
+    fn get(&self, key: &Key) -> io::Result<Option<Value>>;
+
+    // Insert or replace a value, returning the previous one
+    fn put(&self, key: Key, value: Value) -> io::Result<Option<Value>>;
+
+    // Read-modify-write a key atomically with respect to other writers. `apply` sees the current
+    // value and returns the value to store, or None to leave the key as it is. Returns whether it wrote.
+    fn update(&self, key: &Key, apply: &mut dyn FnMut(Option<&Value>) -> Option<Value>) -> io::Result<bool>;
+
+    // update for several keys in order as one atomic write; `apply` also gets the key's index.
+    // Returns how many keys were written.
+    fn update_many(&self, keys: &[Key], apply: &mut dyn FnMut(usize, Option<&Value>) -> Option<Value>) -> io::Result<usize>;
+
+    // Remove a key, returning its value if it existed
+    fn delete(&self, key: &Key) -> io::Result<Option<Value>>;
+
+    // Remove every entry `matches` accepts in one atomic write, returning how many were removed
+    fn delete_where(&self, matches: &mut dyn FnMut(&Key, &Value) -> bool) -> io::Result<usize>;
+
+    fn len(&self) -> io::Result<usize>;
+
+    fn is_empty(&self) -> io::Result<bool> {
+        Ok(self.len()? == 0)
+    }
+
+    // Visit every entry in no particular order until the visitor breaks
+    fn iter(&self, visit: &mut dyn FnMut(&Key, &Value) -> ControlFlow<()>) -> io::Result<()>;
+
+    // Point-in-time copy of the key space, written to snapshot files
+    fn snapshot(&self) -> io::Result<SnapshotView>;
+
+    // Replace the whole key space, used when loading a snapshot
+    fn replace(&self, entries: Vec<(Key, Value)>) -> io::Result<()>;
+
+    // Whether data survives a restart without a snapshot
+    fn is_persistent(&self) -> bool {
+        false
+    }
+
+    // Make every completed write durable
+    fn flush(&self) -> io::Result<()> {
+        Ok(())
+    }
+
+    // Per-tier counters, for backends that keep more than one tier
+    fn tier_stats(&self) -> Option<TierStats> {
+        None
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct TierStats {
+    pub hot_entries: usize,
+    pub cold_entries: usize,
+    pub hot_hits: u64,
+    pub cold_hits: u64,
+    pub misses: u64,
+    // Entries spilled from the hot tier to the cold tier
+    pub evictions: u64,
+}
+
+impl fmt::Display for TierStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "hot_entries={} cold_entries={} hot_hits={} cold_hits={} misses={} evictions={}",
+            self.hot_entries, self.cold_entries, self.hot_hits, self.cold_hits, self.misses, self.evictions
+        )
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum StorageKind {
+    #[default]
+    Memory,
+    Mmap,
+    Btree,
+    // Memory with overflow to a disk backend
+    Tiered,
+}
+
+impl FromStr for StorageKind {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "memory" => Ok(StorageKind::Memory),
+            "mmap" => Ok(StorageKind::Mmap),
+            "btree" | "redb" => Ok(StorageKind::Btree),
+            "tiered" => Ok(StorageKind::Tiered),
+            other => Err(format!("unknown storage backend '{}'", other)),
+        }
+    }
+}
+
+impl fmt::Display for StorageKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            StorageKind::Memory => write!(f, "memory"),
+            StorageKind::Mmap => write!(f, "mmap"),
+            StorageKind::Btree => write!(f, "btree"),
+            StorageKind::Tiered => write!(f, "tiered"),
+        }
+    }
+}
+
+// Open the configured backend, creating its files in the data directory if needed
+pub fn open(config: &Config) -> io::Result<Arc<dyn StorageBackend>> {
+    open_in(config, &config.data_dir)
+}
+
+// Open the configured backend with its files in `dir`
+fn open_in(config: &Config, dir: &Path) -> io::Result<Arc<dyn StorageBackend>> {
+    match config.storage {
+        StorageKind::Tiered => {
+            if !matches!(config.cold_storage, StorageKind::Mmap | StorageKind::Btree) {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("cold tier must be mmap or btree, not {}", config.cold_storage),
+                ));
+            }
+            let cold = open_backend(config.cold_storage, &dir.join(COLD_TIER_DIR))?;
+            Ok(Arc::new(TieredBackend::new(config.hot_entries, cold)?))
+        }
+        kind => open_backend(kind, dir),
+    }
+}
+
+fn open_backend(kind: StorageKind, dir: &Path) -> io::Result<Arc<dyn StorageBackend>> {
+    Ok(match kind {
+        StorageKind::Memory | StorageKind::Tiered => Arc::new(MemoryBackend::new()),
+        StorageKind::Mmap => {
+            std::fs::create_dir_all(dir)?;
+            Arc::new(MmapBackend::open(&dir.join(mmap::FILE_NAME))?)
+        }
+        StorageKind::Btree => {
+            std::fs::create_dir_all(dir)?;
+            Arc::new(BtreeBackend::open(&dir.join(btree::FILE_NAME))?)
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    fn key(name: &str) -> Key {
+        let mut key = [0u8; 63];
+        key[..name.len()].copy_from_slice(name.as_bytes());
+        key
+    }
+
+    fn value(byte: u8) -> Value {
+        [byte; VALUE_SIZE]
+    }
+
+    // Every backend must behave the same through the trait
+    fn check_backend(storage: &dyn StorageBackend) {
+        assert!(storage.is_empty().unwrap());
+        assert_eq!(storage.put(key("a"), value(1)).unwrap(), None);
+        assert_eq!(storage.put(key("a"), value(2)).unwrap(), Some(value(1)));
+        assert_eq!(storage.put(key("b"), value(3)).unwrap(), None);
+        assert_eq!(storage.get(&key("a")).unwrap(), Some(value(2)));
+        assert_eq!(storage.get(&key("missing")).unwrap(), None);
+        assert_eq!(storage.len().unwrap(), 2);
+
+        assert_eq!(storage.delete(&key("a")).unwrap(), Some(value(2)));
+        assert_eq!(storage.delete(&key("a")).unwrap(), None);
+        assert_eq!(storage.get(&key("a")).unwrap(), None);
+
+        // update sees the current value and may decline to write
+        assert!(storage.update(&key("a"), &mut |current| {
+            assert_eq!(current, None);
+            Some(value(4))
+        }).unwrap());
+        assert!(storage.update(&key("a"), &mut |current| current.map(|v| value(v[0] + 1))).unwrap());
+        assert!(!storage.update(&key("a"), &mut |_| None).unwrap());
+        assert_eq!(storage.delete(&key("a")).unwrap(), Some(value(5)));
+
+        // update_many applies in order, so a repeated key sees the earlier write
+        let written = storage.update_many(&[key("m1"), key("m2"), key("m1")], &mut |index, current| match index {
+            1 => None,
+            _ => Some(value(current.map_or(10, |v| v[0] + 1))),
+        }).unwrap();
+        assert_eq!(written, 2);
+        assert_eq!(storage.get(&key("m1")).unwrap(), Some(value(11)));
+        assert_eq!(storage.get(&key("m2")).unwrap(), None);
+        storage.delete(&key("m1")).unwrap();
+
+        // Enough keys to make the disk backends grow
+        for i in 0..3000u32 {
+            storage.put(key(&format!("key:{}", i)), value((i % 250) as u8)).unwrap();
+        }
+        for i in (0..3000u32).step_by(2) {
+            storage.delete(&key(&format!("key:{}", i))).unwrap();
+        }
+        assert_eq!(storage.len().unwrap(), 1501);
+        assert_eq!(storage.get(&key("key:2999")).unwrap(), Some(value((2999 % 250) as u8)));
+        assert_eq!(storage.get(&key("key:2998")).unwrap(), None);
+
+        let mut visited = 0;
+        storage.iter(&mut |_, _| {
+            visited += 1;
+            ControlFlow::Continue(())
+        }).unwrap();
+        assert_eq!(visited, 1501);
+
+        let snapshot = storage.snapshot().unwrap();
+        assert_eq!(snapshot.len(), 1501);
+
+        let removed = storage.delete_where(&mut |key, _| key.starts_with(b"key:29")).unwrap();
+        assert_eq!(removed, 56);
+        assert_eq!(storage.len().unwrap(), 1445);
+        assert_eq!(storage.get(&key("key:2999")).unwrap(), None);
+        assert_eq!(storage.get(&key("key:2899")).unwrap(), Some(value((2899 % 250) as u8)));
+        assert_eq!(snapshot[&key("b")], value(3));
+
+        storage.replace(vec![(key("x"), value(9))]).unwrap();
+        assert_eq!(storage.len().unwrap(), 1);
+        assert_eq!(storage.get(&key("x")).unwrap(), Some(value(9)));
+        assert_eq!(storage.get(&key("b")).unwrap(), None);
+        storage.flush().unwrap();
+    }
+
+    #[test]
+    fn test_memory_backend() {
+        check_backend(&MemoryBackend::new());
+    }
+
+    #[test]
+    fn test_mmap_backend() {
+        let dir = TempDir::new().unwrap();
+        check_backend(&*open_backend(StorageKind::Mmap, dir.path()).unwrap());
+    }
+
+    #[test]
+    fn test_btree_backend() {
+        let dir = TempDir::new().unwrap();
+        check_backend(&*open_backend(StorageKind::Btree, dir.path()).unwrap());
+    }
+
+    #[test]
+    fn test_tiered_backend() {
+        let dir = TempDir::new().unwrap();
+        let cold = open_backend(StorageKind::Mmap, dir.path()).unwrap();
+        check_backend(&TieredBackend::new(100, cold).unwrap());
+    }
+
+    #[test]
+    fn test_tiered_spills_and_promotes() {
+        let dir = TempDir::new().unwrap();
+        let config = Config {
+            data_dir: dir.path().to_path_buf(),
+            storage: StorageKind::Tiered,
+            hot_entries: 2,
+            ..Config::default()
+        };
+        let storage = open(&config).unwrap();
+
+        storage.put(key("a"), value(1)).unwrap();
+        storage.put(key("b"), value(2)).unwrap();
+        // "a" is touched, so "b" is the least recently used when "c" arrives
+        assert_eq!(storage.get(&key("a")).unwrap(), Some(value(1)));
+        storage.put(key("c"), value(3)).unwrap();
+
+        let stats = storage.tier_stats().unwrap();
+        assert_eq!((stats.hot_entries, stats.cold_entries, stats.evictions), (2, 1, 1));
+
+        // A cold hit moves "b" back and spills the next oldest
+        assert_eq!(storage.get(&key("b")).unwrap(), Some(value(2)));
+        assert_eq!(storage.get(&key("missing")).unwrap(), None);
+        let stats = storage.tier_stats().unwrap();
+        assert_eq!((stats.hot_hits, stats.cold_hits, stats.misses, stats.evictions), (1, 1, 1, 2));
+        assert_eq!(storage.len().unwrap(), 3);
+
+        // Overwriting a cold key reports its old value and leaves one copy
+        assert_eq!(storage.put(key("a"), value(9)).unwrap(), Some(value(1)));
+        assert_eq!(storage.len().unwrap(), 3);
+        assert_eq!(storage.snapshot().unwrap().len(), 3);
+    }
+
+    #[test]
+    fn test_tiered_rejects_memory_cold_tier() {
+        let dir = TempDir::new().unwrap();
+        let config = Config {
+            data_dir: dir.path().to_path_buf(),
+            storage: StorageKind::Tiered,
+            cold_storage: StorageKind::Memory,
+            ..Config::default()
+        };
+        assert!(open(&config).is_err());
+    }
+
+    #[test]
+    fn test_disk_backends_survive_reopen() {
+        for kind in [StorageKind::Mmap, StorageKind::Btree] {
+            let dir = TempDir::new().unwrap();
+            {
+                let storage = open_backend(kind, dir.path()).unwrap();
+                assert!(storage.is_persistent());
+                storage.put(key("kept"), value(7)).unwrap();
+                storage.put(key("dropped"), value(8)).unwrap();
+                storage.delete(&key("dropped")).unwrap();
+                storage.flush().unwrap();
+            }
+
+            let storage = open_backend(kind, dir.path()).unwrap();
+            assert_eq!(storage.get(&key("kept")).unwrap(), Some(value(7)), "{}", kind);
+            assert_eq!(storage.get(&key("dropped")).unwrap(), None);
+            assert_eq!(storage.len().unwrap(), 1);
+        }
+    }
+
+    #[test]
+    fn test_unversioned_files_are_upgraded() {
+        let dir = TempDir::new().unwrap();
+
+        // A version 1 mmap file with a single entry in slot 0
+        let mut file = vec![0u8; 128 + 4 * 128];
+        file[0..4].copy_from_slice(b"KVOM");
+        file[4..8].copy_from_slice(&1u32.to_le_bytes());
+        file[8..16].copy_from_slice(&4u64.to_le_bytes());
+        file[16..24].copy_from_slice(&1u64.to_le_bytes());
+        file[128] = 1;
+        file[129..192].copy_from_slice(&key("old"));
+        file[192..256].copy_from_slice(&[5; 64]);
+        std::fs::write(dir.path().join(mmap::FILE_NAME), file).unwrap();
+
+        let storage = open_backend(StorageKind::Mmap, dir.path()).unwrap();
+        assert_eq!(storage.get(&key("old")).unwrap(), Some(stored(&[5; 64], 1)));
+
+        // A redb file with the table from before versions
+        let path = dir.path().join(btree::FILE_NAME);
+        {
+            let db = redb::Database::create(&path).unwrap();
+            let txn = db.begin_write().unwrap();
+            {
+                let legacy: redb::TableDefinition<Key, WireValue> = redb::TableDefinition::new("entries");
+                let mut table = txn.open_table(legacy).unwrap();
+                table.insert(key("old"), [6; 64]).unwrap();
+            }
+            txn.commit().unwrap();
+        }
+
+        let storage = open_backend(StorageKind::Btree, dir.path()).unwrap();
+        assert_eq!(storage.get(&key("old")).unwrap(), Some(stored(&[6; 64], 1)));
+        assert_eq!(storage.len().unwrap(), 1);
+    }
+
+    #[test]
+    fn test_mmap_header_must_match_file() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join(mmap::FILE_NAME);
+
+        // Capacities whose table size overflows, or does not match the file, are rejected before mapping
+        for capacity in [u64::MAX / 2, 5] {
+            let mut file = vec![0u8; 128 + 4 * 136];
+            file[0..4].copy_from_slice(b"KVOM");
+            file[4..8].copy_from_slice(&2u32.to_le_bytes());
+            file[8..16].copy_from_slice(&capacity.to_le_bytes());
+            std::fs::write(&path, file).unwrap();
+
+            let error = mmap::MmapBackend::open(&path).err().unwrap();
+            assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+        }
+    }
+
+    #[test]
+    fn test_namespaces_reopen_from_disk() {
+        let dir = TempDir::new().unwrap();
+        let config = Config { data_dir: dir.path().to_path_buf(), storage: StorageKind::Mmap, namespaces: 4, ..Config::default() };
+        {
+            let namespaces = Namespaces::new(&config, open(&config).unwrap()).unwrap();
+            assert_eq!(namespaces.opened().len(), 1);
+            namespaces.get(2).unwrap().put(key("a"), value(2)).unwrap();
+            assert!(namespaces.get(4).is_err());
+            namespaces.get(2).unwrap().flush().unwrap();
+        }
+
+        let namespaces = Namespaces::new(&config, open(&config).unwrap()).unwrap();
+        let opened: Vec<usize> = namespaces.opened().iter().map(|(namespace, _)| *namespace).collect();
+        assert_eq!(opened, vec![0, 2]);
+        assert_eq!(namespaces.get(2).unwrap().get(&key("a")).unwrap(), Some(value(2)));
+        assert_eq!(namespaces.get(0).unwrap().get(&key("a")).unwrap(), None);
+    }
+
+    #[test]
+    fn test_versions() {
+        let created = next_version(None);
+        let value = stored(&[7; 64], created);
+        assert_eq!(wire(&value), &[7; 64]);
+        assert_eq!(version(&value), created);
+        assert_eq!(next_version(Some(&value)), created + 1);
+        assert!(next_version(None) >= created);
+    }
+
+    #[test]
+    fn test_parse_storage_kind() {
+        assert_eq!("MMAP".parse::<StorageKind>(), Ok(StorageKind::Mmap));
+        assert_eq!("redb".parse::<StorageKind>(), Ok(StorageKind::Btree));
+        assert!("rocksdb".parse::<StorageKind>().is_err());
+    }
+}
@@ -0,0 +1,68 @@
+use std::{io, path::PathBuf, sync::{Arc, RwLock}};
+use crate::config::Config;
+use super::{open_in, StorageBackend, StorageKind};
+
+pub const NAMESPACE_DIR: &str = "namespaces";
+
+// Numbered key spaces, each in its own backend so they can be counted and flushed on their own.
+// Namespace 0 is the storage the cache always had. The others keep disk files under
+// data/namespaces/<n> and are opened on first use, or at startup when those files already exist.
+pub struct Namespaces {
+    config: Config,
+    backends: RwLock<Vec<Option<Arc<dyn StorageBackend>>>>,
+}
+
+impl Namespaces {
+    pub fn new(config: &Config, default: Arc<dyn StorageBackend>) -> io::Result<Self> {
+        let mut backends = vec![None; config.namespaces.max(1)];
+        backends[0] = Some(default);
+
+        // Disk backends still hold the namespaces used before a restart
+        if matches!(config.storage, StorageKind::Mmap | StorageKind::Btree) {
+            for (namespace, backend) in backends.iter_mut().enumerate().skip(1) {
+                let dir = dir(config, namespace);
+                if dir.exists() {
+                    *backend = Some(open_in(config, &dir)?);
+                }
+            }
+        }
+
+        Ok(Namespaces { config: config.clone(), backends: RwLock::new(backends) })
+    }
+
+    // How many namespaces there are, opened or not
+    pub fn count(&self) -> usize {
+        self.backends.read().unwrap().len()
+    }
+
+    // The backend of a namespace, opening it on first use
+    pub fn get(&self, namespace: usize) -> io::Result<Arc<dyn StorageBackend>> {
+        if let Some(Some(backend)) = self.backends.read().unwrap().get(namespace) {
+            return Ok(Arc::clone(backend));
+        }
+
+        let mut backends = self.backends.write().unwrap();
+        let slot = backends.get_mut(namespace).ok_or_else(|| {
+            io::Error::new(io::ErrorKind::InvalidInput, format!("no namespace {}, there are {}", namespace, self.config.namespaces))
+        })?;
+        if slot.is_none() {
+            *slot = Some(open_in(&self.config, &dir(&self.config, namespace))?);
+        }
+        Ok(Arc::clone(slot.as_ref().unwrap()))
+    }
+
+    // Namespaces that have a backend, in order. Unopened ones are empty.
+    pub fn opened(&self) -> Vec<(usize, Arc<dyn StorageBackend>)> {
+        self.backends
+            .read()
+            .unwrap()
+            .iter()
+            .enumerate()
+            .filter_map(|(namespace, backend)| Some((namespace, Arc::clone(backend.as_ref()?))))
+            .collect()
+    }
+}
+
+fn dir(config: &Config, namespace: usize) -> PathBuf {
+    config.data_dir.join(NAMESPACE_DIR).join(namespace.to_string())
+}
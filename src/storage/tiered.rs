@@ -0,0 +1,322 @@
+use std::{collections::BTreeMap, io, ops::ControlFlow, sync::{atomic::{AtomicU64, Ordering}, Arc, Mutex}};
+use crate::snapshot::SnapshotView;
+use super::{Key, StorageBackend, TierStats, Value};
+
+// Least recently used entries in memory, bounded by entry count
+struct HotTier {
+    entries: hashbrown::HashMap<Key, (Value, u64)>,
+    // Access tick -> key, oldest first
+    recency: BTreeMap<u64, Key>,
+    tick: u64,
+}
+
+impl HotTier {
+    fn touch(&mut self, key: &Key) -> Option<Value> {
+        self.tick += 1;
+        let tick = self.tick;
+        let (value, last_used) = self.entries.get_mut(key)?;
+        self.recency.remove(last_used);
+        self.recency.insert(tick, *key);
+        *last_used = tick;
+        Some(*value)
+    }
+
+    fn insert(&mut self, key: Key, value: Value) -> Option<Value> {
+        self.tick += 1;
+        let previous = self.entries.insert(key, (value, self.tick));
+        if let Some((_, last_used)) = previous {
+            self.recency.remove(&last_used);
+        }
+        self.recency.insert(self.tick, key);
+        previous.map(|(value, _)| value)
+    }
+
+    fn remove(&mut self, key: &Key) -> Option<Value> {
+        let (value, last_used) = self.entries.remove(key)?;
+        self.recency.remove(&last_used);
+        Some(value)
+    }
+
+    fn oldest(&self) -> Option<(Key, Value)> {
+        let (_, key) = self.recency.first_key_value()?;
+        let (value, _) = self.entries.get(key)?;
+        Some((*key, *value))
+    }
+}
+
+// Hot tier in memory with overflow to a disk backend. A key lives in exactly one tier: inserts go to
+// the hot tier, the least recently used entries spill to the cold tier when it is full, and gets
+// promote cold entries back. The cold tier is scratch space; snapshots remain the durable copy.
+pub struct TieredBackend {
+    hot: Mutex<HotTier>,
+    cold: Arc<dyn StorageBackend>,
+    hot_capacity: usize,
+    hot_hits: AtomicU64,
+    cold_hits: AtomicU64,
+    misses: AtomicU64,
+    evictions: AtomicU64,
+}
+
+impl TieredBackend {
+    pub fn new(hot_capacity: usize, cold: Arc<dyn StorageBackend>) -> io::Result<Self> {
+        // Leftovers from a previous run are stale; the snapshot is loaded on top of an empty cache
+        cold.replace(Vec::new())?;
+
+        Ok(TieredBackend {
+            hot: Mutex::new(HotTier {
+                entries: hashbrown::HashMap::with_capacity(hot_capacity.min(10000)),
+                recency: BTreeMap::new(),
+                tick: 0,
+            }),
+            cold,
+            hot_capacity: hot_capacity.max(1),
+            hot_hits: AtomicU64::new(0),
+            cold_hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+            evictions: AtomicU64::new(0),
+        })
+    }
+
+    // Spill least recently used entries until the hot tier fits
+    fn evict(&self, hot: &mut HotTier) -> io::Result<()> {
+        while hot.entries.len() > self.hot_capacity {
+            let Some((key, value)) = hot.oldest() else {
+                break;
+            };
+            // Written to the cold tier before leaving the hot one, so a failed write loses nothing
+            self.cold.put(key, value)?;
+            hot.remove(&key);
+            self.evictions.fetch_add(1, Ordering::Relaxed);
+        }
+        Ok(())
+    }
+
+    // update with the hot tier already locked
+    fn update_locked(&self, hot: &mut HotTier, key: &Key, apply: &mut dyn FnMut(Option<&Value>) -> Option<Value>) -> io::Result<bool> {
+        // Updated keys are in use, so a cold entry is promoted either way
+        let current = match hot.touch(key) {
+            Some(value) => Some(value),
+            None => self.cold.delete(key)?.inspect(|value| {
+                hot.insert(*key, *value);
+            }),
+        };
+
+        let written = match apply(current.as_ref()) {
+            Some(value) => {
+                hot.insert(*key, value);
+                true
+            }
+            None => false,
+        };
+        self.evict(hot)?;
+        Ok(written)
+    }
+}
+
+impl StorageBackend for TieredBackend {
+    fn name(&self) -> &'static str {
+        "tiered"
+    }
+
+    fn get(&self, key: &Key) -> io::Result<Option<Value>> {
+        let mut hot = self.hot.lock().unwrap();
+        if let Some(value) = hot.touch(key) {
+            self.hot_hits.fetch_add(1, Ordering::Relaxed);
+            return Ok(Some(value));
+        }
+
+        let Some(value) = self.cold.delete(key)? else {
+            self.misses.fetch_add(1, Ordering::Relaxed);
+            return Ok(None);
+        };
+
+        self.cold_hits.fetch_add(1, Ordering::Relaxed);
+        hot.insert(*key, value);
+        self.evict(&mut hot)?;
+        Ok(Some(value))
+    }
+
+    fn put(&self, key: Key, value: Value) -> io::Result<Option<Value>> {
+        let mut hot = self.hot.lock().unwrap();
+        let previous = match hot.insert(key, value) {
+            Some(previous) => Some(previous),
+            None => self.cold.delete(&key)?,
+        };
+        self.evict(&mut hot)?;
+        Ok(previous)
+    }
+
+    fn update(&self, key: &Key, apply: &mut dyn FnMut(Option<&Value>) -> Option<Value>) -> io::Result<bool> {
+        let mut hot = self.hot.lock().unwrap();
+        self.update_locked(&mut hot, key, apply)
+    }
+
+    fn update_many(&self, keys: &[Key], apply: &mut dyn FnMut(usize, Option<&Value>) -> Option<Value>) -> io::Result<usize> {
+        let mut hot = self.hot.lock().unwrap();
+        let mut written = 0;
+        for (index, key) in keys.iter().enumerate() {
+            if self.update_locked(&mut hot, key, &mut |current| apply(index, current))? {
+                written += 1;
+            }
+        }
+        Ok(written)
+    }
+
+    fn delete(&self, key: &Key) -> io::Result<Option<Value>> {
+        let mut hot = self.hot.lock().unwrap();
+        match hot.remove(key) {
+            Some(previous) => Ok(Some(previous)),
+            None => self.cold.delete(key),
+        }
+    }
+
+    fn delete_where(&self, matches: &mut dyn FnMut(&Key, &Value) -> bool) -> io::Result<usize> {
+        let mut hot = self.hot.lock().unwrap();
+        let mut removed = 0;
+        let HotTier { entries, recency, .. } = &mut *hot;
+        entries.retain(|key, (value, last_used)| {
+            let matched = matches(key, value);
+            if matched {
+                recency.remove(last_used);
+                removed += 1;
+            }
+            !matched
+        });
+        Ok(removed + self.cold.delete_where(matches)?)
+    }
+
+    fn len(&self) -> io::Result<usize> {
+        let hot = self.hot.lock().unwrap();
+        Ok(hot.entries.len() + self.cold.len()?)
+    }
+
+    fn iter(&self, visit: &mut dyn FnMut(&Key, &Value) -> ControlFlow<()>) -> io::Result<()> {
+        let hot = self.hot.lock().unwrap();
+        for (key, (value, _)) in hot.entries.iter() {
+            if visit(key, value).is_break() {
+                return Ok(());
+            }
+        }
+        self.cold.iter(visit)
+    }
+
+    fn snapshot(&self) -> io::Result<SnapshotView> {
+        let hot = self.hot.lock().unwrap();
+        let mut view = self.cold.snapshot()?;
+        view.extend(hot.entries.iter().map(|(key, (value, _))| (*key, *value)));
+        Ok(view)
+    }
+
+    fn replace(&self, mut entries: Vec<(Key, Value)>) -> io::Result<()> {
+        let mut hot = self.hot.lock().unwrap();
+        let spill = entries.split_off(entries.len().min(self.hot_capacity));
+        self.cold.replace(spill)?;
+
+        hot.entries.clear();
+        hot.recency.clear();
+        for (key, value) in entries {
+            hot.insert(key, value);
+        }
+        Ok(())
+    }
+
+    fn flush(&self) -> io::Result<()> {
+        self.cold.flush()
+    }
+
+    fn tier_stats(&self) -> Option<TierStats> {
+        let hot_entries = self.hot.lock().unwrap().entries.len();
+        Some(TierStats {
+            hot_entries,
+            cold_entries: self.cold.len().unwrap_or(0),
+            hot_hits: self.hot_hits.load(Ordering::Relaxed),
+            cold_hits: self.cold_hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+            evictions: self.evictions.load(Ordering::Relaxed),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::AtomicBool;
+    use crate::storage::{MemoryBackend, VALUE_SIZE};
+
+    // A cold tier whose writes can be made to fail
+    struct FlakyCold {
+        inner: MemoryBackend,
+        failing: AtomicBool,
+    }
+
+    impl StorageBackend for FlakyCold {
+        fn name(&self) -> &'static str {
+            "flaky"
+        }
+
+        fn get(&self, key: &Key) -> io::Result<Option<Value>> {
+            self.inner.get(key)
+        }
+
+        fn put(&self, key: Key, value: Value) -> io::Result<Option<Value>> {
+            if self.failing.load(Ordering::SeqCst) {
+                return Err(io::Error::other("disk full"));
+            }
+            self.inner.put(key, value)
+        }
+
+        fn update(&self, key: &Key, apply: &mut dyn FnMut(Option<&Value>) -> Option<Value>) -> io::Result<bool> {
+            self.inner.update(key, apply)
+        }
+
+        fn update_many(&self, keys: &[Key], apply: &mut dyn FnMut(usize, Option<&Value>) -> Option<Value>) -> io::Result<usize> {
+            self.inner.update_many(keys, apply)
+        }
+
+        fn delete(&self, key: &Key) -> io::Result<Option<Value>> {
+            self.inner.delete(key)
+        }
+
+        fn delete_where(&self, matches: &mut dyn FnMut(&Key, &Value) -> bool) -> io::Result<usize> {
+            self.inner.delete_where(matches)
+        }
+
+        fn len(&self) -> io::Result<usize> {
+            self.inner.len()
+        }
+
+        fn iter(&self, visit: &mut dyn FnMut(&Key, &Value) -> ControlFlow<()>) -> io::Result<()> {
+            self.inner.iter(visit)
+        }
+
+        fn snapshot(&self) -> io::Result<SnapshotView> {
+            self.inner.snapshot()
+        }
+
+        fn replace(&self, entries: Vec<(Key, Value)>) -> io::Result<()> {
+            self.inner.replace(entries)
+        }
+    }
+
+    fn key(byte: u8) -> Key {
+        [byte; 63]
+    }
+
+    #[test]
+    fn test_failed_spill_keeps_the_entry() {
+        let cold = Arc::new(FlakyCold { inner: MemoryBackend::new(), failing: AtomicBool::new(true) });
+        let tiered = TieredBackend::new(1, cold.clone()).unwrap();
+        tiered.put(key(1), [1; VALUE_SIZE]).unwrap();
+        assert!(tiered.put(key(2), [2; VALUE_SIZE]).is_err());
+
+        // Both entries are still readable, the older one still in the hot tier
+        assert_eq!(tiered.get(&key(1)).unwrap(), Some([1; VALUE_SIZE]));
+        assert_eq!(tiered.get(&key(2)).unwrap(), Some([2; VALUE_SIZE]));
+        assert_eq!(tiered.tier_stats().unwrap().evictions, 0);
+
+        cold.failing.store(false, Ordering::SeqCst);
+        tiered.put(key(3), [3; VALUE_SIZE]).unwrap();
+        assert_eq!(tiered.len().unwrap(), 3);
+        assert_eq!(tiered.tier_stats().unwrap().hot_entries, 1);
+    }
+}
@@ -1,128 +1,253 @@
-use std::{io::{self, Read}, sync::{Arc, Mutex}, time::Duration};
-use chrono::Utc;
-use crate::{buffer::BufferAccess, Cache};
+use std::{io::{self, Read}, sync::{mpsc::{self, RecvTimeoutError}, Arc, Mutex}, thread::JoinHandle, time::{Duration, Instant}};
+use serde_json::json;
+use crate::{buffer::BufferAccess, logger::Logger, metrics, shutdown::{ShutdownSignal, SHUTDOWN_POLL_INTERVAL}, Cache, LogLevel};
 
 // Optimized input buffer size for better throughput
 const INPUT_BUFFER_SIZE: usize = 128 * 16; // 16 commands at once
-// How often to persist cache to disk (in seconds)
-const PERSISTENCE_INTERVAL_SECS: u64 = 60;
+// How often the save policies are checked (in seconds)
+const SAVE_POLICY_CHECK_SECS: u64 = 1;
+// Batches read from stdin but not yet processed
+const INPUT_QUEUE_SIZE: usize = 64;
 
 // run_tasks function, optimized for throughput and efficiency
 pub fn run_tasks(cache: &Arc<Mutex<Cache>>) -> Result<(), Box<dyn std::error::Error>> {
     // Log initial state
-    {
-        let mut cache_lock = cache.lock().unwrap();
-        let kv_size = cache_lock.vals.read().unwrap().len();
-        cache_lock.log_debug(format!("Starting cache service with {} entries", kv_size));
+    let (shutdown, metrics_addr, shutdown_on_eof, logger) = {
+        let cache_lock = cache.lock().unwrap();
+        let kv_size = cache_lock.key_count()?;
+        let backend = cache_lock.storage().name();
+        cache_lock.log_with(LogLevel::Info, "service", "starting cache service", &[("entries", json!(kv_size)), ("storage", json!(backend))]);
+        (cache_lock.shutdown_signal(), cache_lock.config().metrics_addr, cache_lock.config().shutdown_on_eof, cache_lock.logger().clone())
+    };
+
+    let mut background = Vec::new();
+
+    // Serve Prometheus metrics when configured; the cache keeps running if the address is unusable
+    if let Some(addr) = metrics_addr {
+        match std::net::TcpListener::bind(addr).and_then(|listener| metrics::serve(listener, Arc::clone(cache), shutdown.clone())) {
+            Ok(handle) => background.push(handle),
+            Err(e) => cache.lock().unwrap().log(LogLevel::Error, "metrics", format!("serving metrics on {} failed: {}", addr, e)),
+        }
     }
-    
-    // Create a background task for periodic persistence
+
+    // Create a background task that saves whenever a save policy is satisfied
     let persistence_cache = Arc::clone(cache);
-    std::thread::spawn(move || {
+    let persistence_shutdown = shutdown.clone();
+    background.push(std::thread::spawn(move || {
         loop {
-            // Sleep for the persistence interval
-            std::thread::sleep(Duration::from_secs(PERSISTENCE_INTERVAL_SECS));
-            
-            // Check if we should exit
-            {
-                let cache_lock = persistence_cache.lock().unwrap();
-                if cache_lock.should_exit.load(std::sync::atomic::Ordering::SeqCst) {
-                    break;
-                }
+            // Sleep until the next policy check, waking early on shutdown
+            if persistence_shutdown.sleep(Duration::from_secs(SAVE_POLICY_CHECK_SECS)) {
+                break;
             }
-            
-            // Persist cache to disk
+
+            // Persist cache to disk without holding the cache lock during serialization
             if let Ok(mut cache_lock) = persistence_cache.lock() {
-                let start_time = Utc::now();
-                cache_lock.log_debug("PERIODIC CACHE PERSISTENCE".to_string());
-                
-                if let Err(e) = cache_lock.clean_up() {
-                    eprintln!("Error during periodic persistence: {}", e);
+                if !cache_lock.save_due() {
+                    continue;
+                }
+
+                let changes = cache_lock.dirty();
+                cache_lock.log_with(LogLevel::Debug, "persistence", "save policy triggered", &[("changes", json!(changes))]);
+
+                if !cache_lock.bgsave() {
+                    cache_lock.log(LogLevel::Debug, "persistence", "background save already running, skipping");
                 }
-                
-                let end_time = Utc::now();
-                let duration = end_time - start_time;
-                cache_lock.log_debug(format!("Persistence completed in {} ms", duration.num_milliseconds()));
             }
         }
-    });
-    
+    }));
+
     // Create a background task for periodic cache invalidation
     let invalidation_cache = Arc::clone(cache);
-    std::thread::spawn(move || {
+    let invalidation_shutdown = shutdown.clone();
+    background.push(std::thread::spawn(move || {
+        let mut last_tier_stats = Vec::new();
         loop {
             // Run cache invalidation every 5 seconds
-            std::thread::sleep(Duration::from_secs(5));
-            
-            // Check if we should exit
-            {
-                let cache_lock = invalidation_cache.lock().unwrap();
-                if cache_lock.should_exit.load(std::sync::atomic::Ordering::SeqCst) {
-                    break;
-                }
+            if invalidation_shutdown.sleep(Duration::from_secs(5)) {
+                break;
             }
-            
+
             // Invalidate expired cache entries
             if let Ok(mut cache_lock) = invalidation_cache.lock() {
                 let _ = cache_lock.invalidate_cache();
+
+                // Tiered storage counters, logged when they change
+                let tier_stats = cache_lock.tier_stats();
+                if tier_stats != last_tier_stats {
+                    for (namespace, stats) in &tier_stats {
+                        cache_lock.log_with(LogLevel::Debug, "storage", format!("tier stats {}", stats), &[("namespace", json!(namespace))]);
+                    }
+                    last_tier_stats = tier_stats;
+                }
             }
         }
-    });
-    
+    }));
+
+    // Stdin is read on its own thread so the main loop can notice shutdown while no input arrives
+    let input = spawn_input_reader(shutdown.clone(), logger);
+
     // Main processing loop - optimized for throughput
-    let stdin = io::stdin();
-    let mut handle = stdin.lock();
-    
-    // Pre-allocate buffer for batch processing
-    let mut buffer = vec![0u8; INPUT_BUFFER_SIZE];
-    let mut command_buffers = Vec::with_capacity(16);
-    
     loop {
-        // Check if we should exit
-        {
-            let cache_lock = cache.lock().unwrap();
-            if cache_lock.should_exit.load(std::sync::atomic::Ordering::SeqCst) {
+        if shutdown.is_requested() {
+            break;
+        }
+
+        match input.recv_timeout(SHUTDOWN_POLL_INTERVAL) {
+            Ok(command_buffers) => process_commands(cache, &command_buffers),
+            Err(RecvTimeoutError::Timeout) => continue,
+            Err(RecvTimeoutError::Disconnected) if shutdown_on_eof => {
+                // Stdin closed, nobody is left to send commands
+                shutdown.request();
                 break;
             }
+            // Stdin closed; keep serving background jobs until a signal asks to stop
+            Err(RecvTimeoutError::Disconnected) => std::thread::sleep(SHUTDOWN_POLL_INTERVAL),
         }
-        
+    }
+
+    // Finish commands that were already read before shutdown was requested
+    while let Ok(command_buffers) = input.try_recv() {
+        process_commands(cache, &command_buffers);
+    }
+
+    shutdown_gracefully(cache, &shutdown, background);
+
+    Ok(())
+}
+
+fn spawn_input_reader(shutdown: ShutdownSignal, logger: Logger) -> mpsc::Receiver<Vec<[u8; 128]>> {
+    let (sender, receiver) = mpsc::sync_channel(INPUT_QUEUE_SIZE);
+    std::thread::spawn(move || read_input(io::stdin().lock(), &sender, &shutdown, &logger));
+    receiver
+}
+
+// Send every whole frame read from `reader` until EOF, an error or shutdown
+fn read_input(mut reader: impl Read, sender: &mpsc::SyncSender<Vec<[u8; 128]>>, shutdown: &ShutdownSignal, logger: &Logger) {
+    // Pre-allocate buffer for batch processing
+    let mut buffer = vec![0u8; INPUT_BUFFER_SIZE];
+    // Bytes of a frame split across reads, kept at the start of the buffer
+    let mut partial = 0;
+
+    loop {
         // Read a batch of commands
-        match handle.read(&mut buffer) {
+        match reader.read(&mut buffer[partial..]) {
             Ok(bytes_read) if bytes_read > 0 => {
-                // Process in chunks of 128 bytes (command size)
-                command_buffers.clear();
-                
-                for chunk in buffer[..bytes_read].chunks_exact(128) {
-                    let mut cmd_buffer = [0u8; 128];
-                    cmd_buffer.copy_from_slice(chunk);
-                    command_buffers.push(cmd_buffer);
+                // Stop accepting input once shutdown has started
+                if shutdown.is_requested() {
+                    break;
                 }
-                
-                if !command_buffers.is_empty() {
-                    // Process commands in batch when possible
-                    if command_buffers.len() > 1 {
-                        if let Ok(mut cache_lock) = cache.lock() {
-                            let _ = cache_lock.handle_batch(&command_buffers);
-                        }
-                    } else {
-                        // Process single command
-                        if let Ok(mut cache_lock) = cache.lock() {
-                            let _ = cache_lock.handle_in(command_buffers[0]);
-                        }
-                    }
+
+                // Process in chunks of 128 bytes (command size)
+                let filled = partial + bytes_read;
+                let command_buffers: Vec<[u8; 128]> = buffer[..filled]
+                    .chunks_exact(128)
+                    .map(|chunk| {
+                        let mut cmd_buffer = [0u8; 128];
+                        cmd_buffer.copy_from_slice(chunk);
+                        cmd_buffer
+                    })
+                    .collect();
+
+                // Keep the rest for the next read so frames stay aligned
+                partial = filled % 128;
+                buffer.copy_within(filled - partial..filled, 0);
+
+                if !command_buffers.is_empty() && sender.send(command_buffers).is_err() {
+                    break;
                 }
             },
-            Ok(_) => {
-                // Zero bytes read, pause briefly to avoid CPU spinning
-                std::thread::sleep(Duration::from_millis(10));
-            },
+            Ok(_) => break, // EOF
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
             Err(e) => {
-                eprintln!("Error reading from stdin: {}", e);
+                logger.log(LogLevel::Error, "input", format!("reading from stdin failed: {}", e));
                 break;
             }
         }
     }
-    
-    Ok(())
 }
 
+fn process_commands(cache: &Arc<Mutex<Cache>>, command_buffers: &[[u8; 128]]) {
+    let waiting = Instant::now();
+    if let Ok(mut cache_lock) = cache.lock() {
+        // Time spent behind background jobs holding the lock shows up in the slow log
+        cache_lock.begin_batch(command_buffers.len(), waiting.elapsed());
+
+        // Process commands in batch when possible
+        if command_buffers.len() > 1 {
+            let _ = cache_lock.handle_batch(command_buffers);
+        } else {
+            let _ = cache_lock.handle_in(command_buffers[0]);
+        }
+    }
+}
+
+// Flush responses, write a final snapshot and stop background threads within the shutdown deadline
+fn shutdown_gracefully(cache: &Arc<Mutex<Cache>>, shutdown: &ShutdownSignal, background: Vec<JoinHandle<()>>) {
+    shutdown.arm_watchdog();
+    let deadline = Instant::now() + shutdown.timeout();
+
+    if let Ok(mut cache_lock) = cache.lock() {
+        let _ = cache_lock.responses().flush();
+        cache_lock.log(LogLevel::Info, "shutdown", "handling shutdown");
+
+        // Let background invalidation and save jobs finish before the final snapshot
+        cache_lock.thread_pool.join();
+
+        for (namespace, stats) in cache_lock.tier_stats() {
+            cache_lock.log_with(LogLevel::Debug, "storage", format!("tier stats {}", stats), &[("namespace", json!(namespace))]);
+        }
+
+        if let Err(e) = cache_lock.final_save() {
+            cache_lock.log(LogLevel::Error, "shutdown", format!("final save failed: {}", e));
+        }
+    }
+
+    for handle in background {
+        while !handle.is_finished() && Instant::now() < deadline {
+            std::thread::sleep(Duration::from_millis(5));
+        }
+        if handle.is_finished() {
+            let _ = handle.join();
+        }
+    }
+
+    // Flush even if a command panicked while holding the lock, so the last lines are not lost
+    let cache_lock = cache.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+    cache_lock.log(LogLevel::Info, "shutdown", "exit");
+    if let Err(e) = cache_lock.flush_log() {
+        eprintln!("Error flushing log: {}", e);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Hands out its data a few bytes at a time, like a pipe that splits frames
+    struct Trickle {
+        data: Vec<u8>,
+        sizes: std::iter::Cycle<std::vec::IntoIter<usize>>,
+    }
+
+    impl Read for Trickle {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let size = self.sizes.next().unwrap().min(buf.len()).min(self.data.len());
+            buf[..size].copy_from_slice(&self.data[..size]);
+            self.data.drain(..size);
+            Ok(size)
+        }
+    }
+
+    #[test]
+    fn test_frames_split_across_reads() {
+        let frames: Vec<[u8; 128]> = (0..5u8).map(|i| [i + 1; 128]).collect();
+        let reader = Trickle { data: frames.concat(), sizes: vec![100, 200, 7, 333].into_iter().cycle() };
+        let dir = tempfile::TempDir::new().unwrap();
+        let logger = Logger::new(dir.path().join("log.log").to_str().unwrap(), LogLevel::Info, Default::default());
+        let (sender, receiver) = mpsc::sync_channel(INPUT_QUEUE_SIZE);
+
+        read_input(reader, &sender, &ShutdownSignal::new(Duration::from_secs(1), None), &logger);
+        drop(sender);
+        assert_eq!(receiver.iter().flatten().collect::<Vec<_>>(), frames);
+    }
+}
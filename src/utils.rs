@@ -3,7 +3,7 @@ use std::{borrow::Cow, fs, io::{self, BufWriter, Write}, path::Path};
 
 use chrono::{DateTime, TimeDelta, Utc};
 
-use crate::CacheEntry;
+use crate::{storage::{self, Value}, CacheEntry};
 
 // Utility function to extract timestamp and expiration from cache value
 pub fn parse_cache_metadata(value: &[u8; 64]) -> (DateTime<Utc>, Option<DateTime<Utc>>) {
@@ -19,7 +19,7 @@ pub fn parse_cache_metadata(value: &[u8; 64]) -> (DateTime<Utc>, Option<DateTime
     
     // Create DateTime objects
     let created_at = DateTime::<Utc>::from_timestamp(timestamp, 0)
-        .unwrap_or_else(|| Utc::now());
+        .unwrap_or_else(Utc::now);
     
     let expires_at = if expiry_seconds > 0 {
         Some(created_at + TimeDelta::try_seconds(expiry_seconds as i64).unwrap_or_default())
@@ -30,17 +30,18 @@ pub fn parse_cache_metadata(value: &[u8; 64]) -> (DateTime<Utc>, Option<DateTime
     (created_at, expires_at)
 }
 
-// Utility function to create a cache entry from raw data
-pub fn create_cache_entry(value: &[u8; 64]) -> CacheEntry {
+// Utility function to create a cache entry from a stored value
+pub fn create_cache_entry(value: &Value) -> CacheEntry {
     let mut value_only = [0u8; 56];
     value_only.copy_from_slice(&value[0..56]);
     
-    let (created_at, expires_at) = parse_cache_metadata(value);
+    let (created_at, expires_at) = parse_cache_metadata(storage::wire(value));
     
     CacheEntry {
         value: value_only,
         created_at,
         expires_at,
+        version: storage::version(value),
     }
 }
 
@@ -143,13 +144,14 @@ mod tests {
         value[62..64].copy_from_slice(&expiry_bytes);
         
         // Create cache entry
-        let entry = create_cache_entry(&value);
+        let entry = create_cache_entry(&storage::stored(&value, 7));
         
         // Check value portion
         assert_eq!(&entry.value[0..5], b"hello");
         
         // Check metadata
         assert!(entry.expires_at.is_some());
+        assert_eq!(entry.version, 7);
     }
     
     #[test]